@@ -1,10 +1,12 @@
 use clap::{crate_authors, App, Arg};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path;
 
 use super::linear;
+use super::scene;
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -12,6 +14,134 @@ pub struct OutputConfig {
     pub image_width: u32,
     pub image_height: u32,
     pub image_file: String,
+
+    #[serde(default)]
+    pub image_format: Option<ImageFormat>,
+
+    // Only applies to 8 bit formats, floating point images are saved untouched
+    #[serde(default)]
+    pub transform: OutputTransformConfig,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ImageFormat {
+    Png,
+    Exr,
+    Hdr,
+    Pfm,
+}
+
+fn default_hable_white() -> f64 {
+    11.2
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ToneMapConfig {
+    #[default]
+    Clamp,
+    Reinhard {
+        // Radiance mapped to white, unbounded when left out
+        #[serde(default)]
+        white: Option<f64>,
+    },
+    Aces,
+    Hable {
+        #[serde(default = "default_hable_white")]
+        white: f64,
+    },
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferFunction {
+    #[default]
+    Linear,
+    Srgb,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OutputTransformConfig {
+    // Exposure adjustment in stops
+    pub exposure: f64,
+    pub tone_mapping: ToneMapConfig,
+    pub transfer_function: TransferFunction,
+    pub dither: bool,
+}
+
+impl OutputConfig {
+    // Replaces a frame number placeholder in the image file, such as %04d for
+    // a number padded with zeros to four digits. The last placeholder is used,
+    // leaving any other percent signs, such as in a directory name, as they are.
+    // None if there isn't one.
+    pub fn frame_file(&self, frame: u32) -> Option<String> {
+        self.image_file.rmatch_indices('%').find_map(|(start, _)| {
+            let rest = &self.image_file[start + 1..];
+            let end = rest.find(|c: char| !c.is_ascii_digit())?;
+            if !rest[end..].starts_with('d') {
+                return None;
+            }
+
+            let width = match &rest[..end] {
+                "" => 0,
+                width => width.parse::<usize>().ok()?,
+            };
+
+            Some(format!(
+                "{}{:0width$}{}",
+                &self.image_file[..start],
+                frame,
+                &rest[end + 1..],
+                width = width
+            ))
+        })
+    }
+
+    // An explicit image format wins, otherwise it is picked from the file extension
+    pub fn format(&self) -> ImageFormat {
+        if let Some(format) = self.image_format {
+            return format;
+        }
+
+        let extension = path::Path::new(&self.image_file)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        match extension.as_deref() {
+            Some("exr") => ImageFormat::Exr,
+            Some("hdr") => ImageFormat::Hdr,
+            Some("pfm") => ImageFormat::Pfm,
+            _ => ImageFormat::Png,
+        }
+    }
+}
+
+fn default_frame_rate() -> f64 {
+    24.0
+}
+
+// Renders an image for each frame of a range, with frame n starting at time
+// n / frameRate and the shutter timed from there
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnimationConfig {
+    #[serde(default)]
+    pub first_frame: u32,
+    pub last_frame: u32,
+
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: f64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Projection {
+    #[default]
+    Perspective,
+    Orthographic,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -25,7 +155,497 @@ pub struct CameraConfig {
     #[serde(default)]
     pub roll: f64,
 
+    // Only used by the perspective projection
+    #[serde(default)]
     pub focal_length: f64,
+
+    #[serde(default)]
+    pub projection: Projection,
+
+    // Thin lens depth of field for the perspective projection, which is
+    // otherwise a pinhole with everything in focus
+    #[serde(default)]
+    pub aperture: Option<ApertureConfig>,
+
+    // Times between which rays are cast, blurring anything that moves
+    #[serde(default)]
+    pub shutter: ShutterConfig,
+
+    // Moves the camera over time
+    #[serde(default)]
+    pub keyframes: Vec<CameraKeyframe>,
+
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    CatmullRom,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShutterConfig {
+    pub open: f64,
+    pub close: f64,
+}
+
+// Placement of the camera at one time. Values left out are the camera's own.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraKeyframe {
+    pub time: f64,
+
+    #[serde(default)]
+    pub position: Option<linear::Vector>,
+
+    #[serde(default)]
+    pub target: Option<linear::Vector>,
+
+    #[serde(default)]
+    pub roll: Option<f64>,
+
+    #[serde(default)]
+    pub focal_length: Option<f64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ApertureShape {
+    #[default]
+    Disk,
+    // Rotation in degrees of the polygon's corners
+    Polygon {
+        blades: u32,
+        #[serde(default)]
+        rotation: f64,
+    },
+}
+
+// The aperture's size is given by either an f-stop, dividing the focal length
+// into the aperture's diameter, or a radius
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApertureConfig {
+    #[serde(default)]
+    pub f_stop: Option<f64>,
+
+    #[serde(default)]
+    pub radius: Option<f64>,
+
+    // Autofocuses on the camera's target when left out
+    #[serde(default)]
+    pub focus_distance: Option<f64>,
+
+    #[serde(default)]
+    pub shape: ApertureShape,
+}
+
+impl ApertureConfig {
+    // Checks that the aperture has a size and a shape it can be sampled over
+    fn validate(&self) -> Result<(), io::Error> {
+        match (self.f_stop, self.radius) {
+            (Some(_), Some(_)) | (None, None) => {
+                return Err(invalid("Aperture requires either an f-stop or a radius"));
+            }
+            (Some(f_stop), None) if f_stop <= 0.0 => {
+                return Err(invalid("Aperture f-stop must be positive"));
+            }
+            (None, Some(radius)) if radius < 0.0 => {
+                return Err(invalid("Aperture radius can't be negative"));
+            }
+            _ => (),
+        }
+
+        match self.shape {
+            ApertureShape::Polygon { blades, .. } if blades < 3 => {
+                Err(invalid("Polygonal apertures require at least 3 blades"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_box_radius() -> f64 {
+    0.5
+}
+
+fn default_tent_radius() -> f64 {
+    1.0
+}
+
+fn default_gaussian_radius() -> f64 {
+    1.5
+}
+
+fn default_gaussian_alpha() -> f64 {
+    2.0
+}
+
+fn default_mitchell_radius() -> f64 {
+    2.0
+}
+
+fn default_mitchell_parameter() -> f64 {
+    1.0 / 3.0
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FilterConfig {
+    Box {
+        #[serde(default = "default_box_radius")]
+        radius: f64,
+    },
+    Tent {
+        #[serde(default = "default_tent_radius")]
+        radius: f64,
+    },
+    Gaussian {
+        #[serde(default = "default_gaussian_radius")]
+        radius: f64,
+        #[serde(default = "default_gaussian_alpha")]
+        alpha: f64,
+    },
+    Mitchell {
+        #[serde(default = "default_mitchell_radius")]
+        radius: f64,
+        #[serde(default = "default_mitchell_parameter")]
+        b: f64,
+        #[serde(default = "default_mitchell_parameter")]
+        c: f64,
+    },
+}
+
+impl FilterConfig {
+    // Filters only weigh samples within a positive radius, and a Gaussian which
+    // doesn't fall off gives every sample a weight of zero
+    fn validate(&self) -> Result<(), io::Error> {
+        let (radius, alpha) = match *self {
+            FilterConfig::Box { radius } => (radius, None),
+            FilterConfig::Tent { radius } => (radius, None),
+            FilterConfig::Gaussian { radius, alpha } => (radius, Some(alpha)),
+            FilterConfig::Mitchell { radius, .. } => (radius, None),
+        };
+
+        if radius <= 0.0 {
+            return Err(invalid("Filter radius must be positive"));
+        }
+
+        match alpha {
+            Some(alpha) if alpha <= 0.0 => Err(invalid("Gaussian filter alpha must be positive")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for FilterConfig {
+    fn default() -> FilterConfig {
+        FilterConfig::Box {
+            radius: default_box_radius(),
+        }
+    }
+}
+
+fn default_roulette_depth() -> u32 {
+    3
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum IntegratorConfig {
+    #[default]
+    Whitted,
+    PathTracer {
+        #[serde(default = "default_roulette_depth")]
+        roulette_depth: u32,
+    },
+}
+
+fn default_samples_per_pixel() -> u32 {
+    1
+}
+
+fn default_mesh_light_samples() -> u32 {
+    16
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplingConfig {
+    #[serde(default = "default_samples_per_pixel")]
+    pub samples_per_pixel: u32,
+
+    #[serde(default)]
+    pub filter: FilterConfig,
+
+    // Shadow rays cast towards emissive geometry from each shaded point
+    #[serde(default = "default_mesh_light_samples")]
+    pub mesh_light_samples: u32,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> SamplingConfig {
+        SamplingConfig {
+            samples_per_pixel: default_samples_per_pixel(),
+            filter: FilterConfig::default(),
+            mesh_light_samples: default_mesh_light_samples(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MaterialConfig {
+    pub specular: scene::lighting::Color,
+    pub diffuse: scene::lighting::Color,
+    pub ambient: scene::lighting::Color,
+    pub emission: scene::lighting::Color,
+    pub shininess: f64,
+    pub reflectance: f64,
+    pub transparency: f64,
+    pub index_of_refraction: f64,
+    pub culling: Option<scene::primitive::Culling>,
+}
+
+impl Default for MaterialConfig {
+    fn default() -> MaterialConfig {
+        MaterialConfig {
+            specular: scene::lighting::Color::new(0.5, 0.5, 0.5),
+            diffuse: scene::lighting::Color::new(0.8, 0.8, 0.8),
+            ambient: scene::lighting::Color::new(1.0, 1.0, 1.0),
+            emission: scene::lighting::Color::black(),
+            shininess: 225.0,
+            reflectance: 0.1,
+            transparency: 0.0,
+            index_of_refraction: 1.45,
+            culling: None,
+        }
+    }
+}
+
+impl MaterialConfig {
+    pub fn to_material(&self) -> Box<dyn scene::material::Material> {
+        Box::new(scene::material::PhongMaterial::new(
+            self.specular,
+            self.diffuse,
+            self.ambient,
+            self.emission,
+            self.shininess,
+            self.reflectance,
+            self.transparency,
+            self.index_of_refraction,
+            scene::lighting::TextureMaps::default(),
+            self.culling
+                .unwrap_or_else(|| scene::primitive::Culling::default_for(self.transparency)),
+        ))
+    }
+}
+
+// Replaces values from a model's own material definitions. Only the values
+// given are replaced.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MaterialOverride {
+    pub reflectance: Option<f64>,
+    pub transparency: Option<f64>,
+    pub index_of_refraction: Option<f64>,
+    pub culling: Option<scene::primitive::Culling>,
+}
+
+impl MaterialOverride {
+    // Combines two sets of overrides, preferring values from this one
+    pub fn or(&self, other: &MaterialOverride) -> MaterialOverride {
+        MaterialOverride {
+            reflectance: self.reflectance.or(other.reflectance),
+            transparency: self.transparency.or(other.transparency),
+            index_of_refraction: self.index_of_refraction.or(other.index_of_refraction),
+            culling: self.culling.or(other.culling),
+        }
+    }
+
+    pub fn apply(&self, material: &mut MaterialConfig) {
+        material.reflectance = self.reflectance.unwrap_or(material.reflectance);
+        material.transparency = self.transparency.unwrap_or(material.transparency);
+        material.index_of_refraction = self
+            .index_of_refraction
+            .unwrap_or(material.index_of_refraction);
+        material.culling = self.culling.or(material.culling);
+    }
+}
+
+fn zero_vector() -> linear::Vector {
+    linear::Vector::new(0.0, 0.0, 0.0)
+}
+
+fn unit_scale() -> linear::Vector {
+    linear::Vector::new(1.0, 1.0, 1.0)
+}
+
+// Places a model in the scene. It is scaled along each axis, then rotated
+// about the x, y and z axes in turn (in degrees), then translated.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransformConfig {
+    #[serde(default = "zero_vector")]
+    pub translate: linear::Vector,
+
+    #[serde(default = "zero_vector")]
+    pub rotate: linear::Vector,
+
+    #[serde(default = "unit_scale")]
+    pub scale: linear::Vector,
+}
+
+impl TransformConfig {
+    pub fn decomposition(&self) -> linear::Decomposition {
+        let axes = [
+            linear::Vector::new(1.0, 0.0, 0.0),
+            linear::Vector::new(0.0, 1.0, 0.0),
+            linear::Vector::new(0.0, 0.0, 1.0),
+        ];
+
+        let rotation = axes
+            .iter()
+            .enumerate()
+            .fold(linear::Quaternion::identity(), |rotation, (index, axis)| {
+                linear::Quaternion::from_axis_angle(self.rotate.component(index), axis)
+                    .multiply(&rotation)
+            });
+
+        linear::Decomposition {
+            scale: self.scale,
+            rotation,
+            translate: self.translate,
+        }
+    }
+
+    // None if the scale flattens the model
+    pub fn to_transform(&self) -> Option<linear::Transform> {
+        linear::Transform::new(self.decomposition().matrix())
+    }
+}
+
+// Placement of a moving model or shape at one time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TransformKeyframe {
+    pub time: f64,
+
+    #[serde(flatten)]
+    pub transform: TransformConfig,
+}
+
+// None if the model is ever flattened
+pub fn to_animated_transform(
+    keyframes: &[TransformKeyframe],
+) -> Option<linear::AnimatedTransform> {
+    linear::AnimatedTransform::new(
+        keyframes
+            .iter()
+            .map(|keyframe| (keyframe.time, keyframe.transform.decomposition()))
+            .collect(),
+    )
+}
+
+impl Default for TransformConfig {
+    fn default() -> TransformConfig {
+        TransformConfig {
+            translate: zero_vector(),
+            rotate: zero_vector(),
+            scale: unit_scale(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelConfig {
+    pub file: String,
+
+    #[serde(default)]
+    pub transform: TransformConfig,
+
+    // Overrides for this model's materials, by name, on top of the material file
+    #[serde(default)]
+    pub materials: HashMap<String, MaterialOverride>,
+
+    // Replaces the culling of every material of the model
+    #[serde(default)]
+    pub culling: Option<scene::primitive::Culling>,
+
+    // Moves the model over time, replacing its transform
+    #[serde(default)]
+    pub keyframes: Vec<TransformKeyframe>,
+}
+
+// Analytic shapes, given in their own coordinates before being placed by a
+// transform
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PrimitiveShape {
+    // Centered on the origin
+    Sphere { radius: f64 },
+    // The y = 0 plane, facing up
+    Plane,
+    // Between two corners, aligned with the axes
+    Box {
+        min: linear::Vector,
+        max: linear::Vector,
+    },
+    // Open tube around the y axis, from y = 0 up to the height
+    Cylinder { radius: f64, height: f64 },
+    // Open cone around the y axis, with its base at y = 0 and its tip at the height
+    Cone { radius: f64, height: f64 },
+    // In the y = 0 plane around the origin, facing up
+    Disk { radius: f64 },
+}
+
+impl PrimitiveShape {
+    pub fn to_primitive(&self, material_id: usize) -> Box<dyn scene::primitive::Primitive> {
+        match *self {
+            PrimitiveShape::Sphere { radius } => {
+                Box::new(scene::primitive::Sphere::new(radius, material_id))
+            }
+            PrimitiveShape::Plane => Box::new(scene::primitive::Plane::new(material_id)),
+            PrimitiveShape::Box { min, max } => {
+                Box::new(scene::primitive::AxisAlignedBox::new(min, max, material_id))
+            }
+            PrimitiveShape::Cylinder { radius, height } => {
+                Box::new(scene::primitive::Cylinder::new(radius, height, material_id))
+            }
+            PrimitiveShape::Cone { radius, height } => {
+                Box::new(scene::primitive::Cone::new(radius, height, material_id))
+            }
+            PrimitiveShape::Disk { radius } => {
+                Box::new(scene::primitive::Disk::new(radius, material_id))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrimitiveConfig {
+    pub shape: PrimitiveShape,
+
+    #[serde(default)]
+    pub transform: TransformConfig,
+
+    // Falls back to the default material
+    #[serde(default)]
+    pub material: Option<MaterialConfig>,
+
+    // Replaces the culling of the material
+    #[serde(default)]
+    pub culling: Option<scene::primitive::Culling>,
+
+    // Moves the shape over time, replacing its transform
+    #[serde(default)]
+    pub keyframes: Vec<TransformKeyframe>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -34,15 +654,91 @@ pub struct Config {
     pub camera: CameraConfig,
     pub output: OutputConfig,
 
+    // Renders a numbered sequence of images rather than a single one
+    #[serde(default)]
+    pub animation: Option<AnimationConfig>,
+
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+
+    #[serde(default)]
+    pub integrator: IntegratorConfig,
+
+    // Reflection depth for the Whitted integrator, or bounce limit for path tracing
     pub maximum_reflections: u32,
 
-    pub model_file: String,
     pub lighting_file: String,
+
+    // A single model file, or a scene file listing any number of models and
+    // primitives. Both may be given, in which case the model file is loaded first.
+    #[serde(default)]
+    pub model_file: Option<String>,
+
+    #[serde(default)]
+    pub scene_file: Option<String>,
+
+    // Material overrides for every model, keyed by material name
+    #[serde(default)]
+    pub material_file: Option<String>,
+
+    // Used for models whose format carries no material data, such as STL
+    #[serde(default)]
+    pub default_material: MaterialConfig,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Rejects settings which can't be rendered, before any work is done
+fn validate(config: &Config) -> Result<(), io::Error> {
+    if let Some(aperture) = &config.camera.aperture {
+        aperture.validate()?;
+    }
+
+    // Tone mapping divides by the white point
+    let white = match config.output.transform.tone_mapping {
+        ToneMapConfig::Reinhard { white } => white,
+        ToneMapConfig::Hable { white } => Some(white),
+        _ => None,
+    };
+    if white.is_some_and(|white| white <= 0.0) {
+        return Err(invalid("Tone mapping white point must be positive"));
+    }
+
+    if config.sampling.samples_per_pixel == 0 {
+        return Err(invalid("Sampling needs at least one sample per pixel"));
+    }
+    config.sampling.filter.validate()?;
+
+    let shutter = &config.camera.shutter;
+    if shutter.close < shutter.open {
+        return Err(invalid("Shutter must close after it opens"));
+    }
+
+    if let Some(animation) = &config.animation {
+        if animation.frame_rate <= 0.0 {
+            return Err(invalid("Animation frame rate must be positive"));
+        }
+
+        if animation.last_frame < animation.first_frame {
+            return Err(invalid("Animation must end on or after its first frame"));
+        }
+
+        if config.output.frame_file(animation.first_frame).is_none() {
+            return Err(invalid(
+                "Animation image file must contain a frame number such as %04d",
+            ));
+        }
+    }
+
+    Ok(())
 }
 
 fn parse_config_file(config_file: &path::Path) -> Result<Config, io::Error> {
     let f = fs::File::open(config_file)?;
     let config: Config = serde_json::from_reader(f)?;
+    validate(&config)?;
 
     Ok(config)
 }
@@ -92,3 +788,121 @@ pub fn configure() -> Result<Config, io::Error> {
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Config with the given camera and extra settings
+    fn config(camera: &str, extra: &str) -> Config {
+        serde_json::from_str(&format!(
+            r#"{{"camera": {{"viewWidth": 1, "position": {{"x": 0, "y": 0, "z": 1}},
+                    "target": {{"x": 0, "y": 0, "z": 0}}, "focalLength": 1 {}}},
+                "output": {{"imageWidth": 4, "imageHeight": 4, "imageFile": "out.png"}},
+                "maximumReflections": 0, "lightingFile": "lights.json" {}}}"#,
+            camera, extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn aperture_validation() {
+        let aperture = |json: &str| serde_json::from_str::<ApertureConfig>(json).unwrap();
+
+        let f_stop = aperture(r#"{"fStop": 2}"#);
+        assert!(f_stop.validate().is_ok());
+
+        let radius = aperture(r#"{"radius": 0.5, "shape": {"type": "polygon", "blades": 3}}"#);
+        assert!(radius.validate().is_ok());
+
+        for json in &[
+            r#"{"fStop": 0}"#,
+            r#"{"radius": -0.5}"#,
+            r#"{"fStop": 2, "radius": 0.5}"#,
+            r#"{"focusDistance": 2}"#,
+            r#"{"fStop": 2, "shape": {"type": "polygon", "blades": 2}}"#,
+        ] {
+            assert!(aperture(json).validate().is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn shutter_validation() {
+        assert!(validate(&config("", "")).is_ok());
+        assert!(validate(&config(r#", "shutter": {"open": 1, "close": 1.5}"#, "")).is_ok());
+        assert!(validate(&config(r#", "shutter": {"open": 1, "close": 0.5}"#, "")).is_err());
+    }
+
+    #[test]
+    fn sampling_validation() {
+        let sampling = |json: &str| validate(&config("", &format!(r#", "sampling": {}"#, json)));
+
+        assert!(sampling(r#"{"samplesPerPixel": 4}"#).is_ok());
+        assert!(sampling(r#"{"filter": {"type": "gaussian", "radius": 2, "alpha": 1}}"#).is_ok());
+
+        for json in &[
+            r#"{"samplesPerPixel": 0}"#,
+            r#"{"filter": {"type": "box", "radius": 0}}"#,
+            r#"{"filter": {"type": "tent", "radius": -1}}"#,
+            r#"{"filter": {"type": "mitchell", "radius": 0}}"#,
+            r#"{"filter": {"type": "gaussian", "alpha": 0}}"#,
+        ] {
+            assert!(sampling(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn white_point_validation() {
+        let tone_mapping = |json: &str| {
+            let mut config = config("", "");
+            config.output.transform.tone_mapping = serde_json::from_str(json).unwrap();
+            validate(&config)
+        };
+
+        assert!(tone_mapping(r#"{"type": "reinhard"}"#).is_ok());
+        assert!(tone_mapping(r#"{"type": "reinhard", "white": 4}"#).is_ok());
+        assert!(tone_mapping(r#"{"type": "hable"}"#).is_ok());
+        assert!(tone_mapping(r#"{"type": "reinhard", "white": 0}"#).is_err());
+        assert!(tone_mapping(r#"{"type": "hable", "white": -1}"#).is_err());
+    }
+
+    #[test]
+    fn frame_files() {
+        let output = |image_file: &str| OutputConfig {
+            image_width: 4,
+            image_height: 4,
+            image_file: image_file.to_string(),
+            image_format: None,
+            transform: OutputTransformConfig::default(),
+        };
+
+        assert_eq!(Some("frame_7.png".to_string()), output("frame_%d.png").frame_file(7));
+        assert_eq!(Some("frame_0007.png".to_string()), output("frame_%04d.png").frame_file(7));
+        assert_eq!(Some("12345.exr".to_string()), output("%02d.exr").frame_file(12345));
+        assert_eq!(None, output("frame.png").frame_file(7));
+        assert_eq!(None, output("frame_%.png").frame_file(7));
+        assert_eq!(None, output("frame_%4.png").frame_file(7));
+        assert_eq!(
+            Some("renders/100%/frame_0012.png".to_string()),
+            output("renders/100%/frame_%04d.png").frame_file(12)
+        );
+        assert_eq!(
+            Some("renders/%d/frame_12.png".to_string()),
+            output("renders/%d/frame_%d.png").frame_file(12)
+        );
+    }
+
+    #[test]
+    fn animation_validation() {
+        let animated = |image_file: &str, animation: &str| {
+            let mut config = config("", &format!(r#", "animation": {}"#, animation));
+            config.output.image_file = image_file.to_string();
+            validate(&config)
+        };
+
+        assert!(animated("frame_%03d.png", r#"{"firstFrame": 2, "lastFrame": 2}"#).is_ok());
+        assert!(animated("frame.png", r#"{"lastFrame": 2}"#).is_err());
+        assert!(animated("frame_%d.png", r#"{"firstFrame": 3, "lastFrame": 2}"#).is_err());
+        assert!(animated("frame_%d.png", r#"{"lastFrame": 2, "frameRate": 0}"#).is_err());
+    }
+}
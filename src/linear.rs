@@ -1,4 +1,3 @@
-use approx;
 use serde::{Deserialize, Serialize};
 use std::f64::consts;
 
@@ -26,6 +25,23 @@ impl Vector {
         )
     }
 
+    pub fn component(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            2 => self.z,
+            _ => panic!("Vector axis must be 0, 1, or 2"),
+        }
+    }
+
+    pub fn min(&self, other: &Vector) -> Vector {
+        Vector::new(self.x.min(other.x), self.y.min(other.y), self.z.min(other.z))
+    }
+
+    pub fn max(&self, other: &Vector) -> Vector {
+        Vector::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
+    }
+
     pub fn magnitude(&self) -> f64 {
         (self.dot(self)).sqrt()
     }
@@ -73,9 +89,9 @@ impl Vector {
         ];
 
         Vector::new(
-            matrix[3 * 0 + 0] * self.x + matrix[3 * 0 + 1] * self.y + matrix[3 * 0 + 2] * self.z,
-            matrix[3 * 1 + 0] * self.x + matrix[3 * 1 + 1] * self.y + matrix[3 * 1 + 2] * self.z,
-            matrix[3 * 2 + 0] * self.x + matrix[3 * 2 + 1] * self.y + matrix[3 * 2 + 2] * self.z,
+            matrix[0] * self.x + matrix[1] * self.y + matrix[2] * self.z,
+            matrix[3] * self.x + matrix[4] * self.y + matrix[5] * self.z,
+            matrix[6] * self.x + matrix[7] * self.y + matrix[8] * self.z,
         )
     }
 
@@ -85,6 +101,38 @@ impl Vector {
         delta.subtract(self)
     }
 
+    // Refracts a unit direction through a surface with the given unit normal
+    // (facing against the direction), where eta is the ratio of refractive
+    // indices n1 / n2. Returns None on total internal reflection.
+    pub fn refract(&self, normal: &Vector, eta: f64) -> Option<Vector> {
+        let cos_incident = -self.dot(normal);
+        let sin2_transmitted = eta * eta * (1.0 - cos_incident * cos_incident);
+        if sin2_transmitted > 1.0 {
+            return None;
+        }
+
+        let cos_transmitted = (1.0 - sin2_transmitted).sqrt();
+
+        Some(
+            self.scale(eta)
+                .add(&normal.scale(eta * cos_incident - cos_transmitted)),
+        )
+    }
+
+    // Two unit vectors which, with this unit vector, form an orthonormal basis
+    pub fn orthonormal_basis(&self) -> (Vector, Vector) {
+        let helper = if self.x.abs() > 0.9 {
+            Vector::new(0.0, 1.0, 0.0)
+        } else {
+            Vector::new(1.0, 0.0, 0.0)
+        };
+
+        let tangent = helper.cross(self).normalize();
+        let bitangent = self.cross(&tangent);
+
+        (tangent, bitangent)
+    }
+
     pub fn equals(&self, other: &Vector) -> bool {
         approx::abs_diff_eq!(self.x, other.x)
             && approx::abs_diff_eq!(self.y, other.y)
@@ -95,6 +143,390 @@ impl Vector {
 pub struct Ray {
     pub position: Vector,
     pub direction: Vector,
+
+    // When the ray was cast, for scenes which move while the shutter is open
+    pub time: f64,
+}
+
+// Affine transform in homogeneous coordinates, stored in row major order
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix4([[f64; 4]; 4]);
+
+impl Matrix4 {
+    pub fn new(rows: [[f64; 4]; 4]) -> Matrix4 {
+        Matrix4(rows)
+    }
+
+    pub fn identity() -> Matrix4 {
+        Matrix4::scaling(&Vector::new(1.0, 1.0, 1.0))
+    }
+
+    pub fn translation(offset: &Vector) -> Matrix4 {
+        Matrix4([
+            [1.0, 0.0, 0.0, offset.x],
+            [0.0, 1.0, 0.0, offset.y],
+            [0.0, 0.0, 1.0, offset.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn scaling(scale: &Vector) -> Matrix4 {
+        Matrix4([
+            [scale.x, 0.0, 0.0, 0.0],
+            [0.0, scale.y, 0.0, 0.0],
+            [0.0, 0.0, scale.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    // Applies other first, then this transform
+    pub fn multiply(&self, other: &Matrix4) -> Matrix4 {
+        let mut product = [[0.0; 4]; 4];
+        for (row, product_row) in product.iter_mut().enumerate() {
+            for (column, value) in product_row.iter_mut().enumerate() {
+                *value = (0..4)
+                    .map(|index| self.0[row][index] * other.0[index][column])
+                    .sum();
+            }
+        }
+
+        Matrix4(product)
+    }
+
+    // Gauss-Jordan elimination with partial pivoting, or None if the matrix is
+    // singular
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut matrix = self.0;
+        let mut inverse = Matrix4::identity().0;
+
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&a, &b| matrix[a][column].abs().total_cmp(&matrix[b][column].abs()))
+                .unwrap();
+            if matrix[pivot][column].abs() < 1e-12 {
+                return None;
+            }
+
+            matrix.swap(column, pivot);
+            inverse.swap(column, pivot);
+
+            let scale = 1.0 / matrix[column][column];
+            for index in 0..4 {
+                matrix[column][index] *= scale;
+                inverse[column][index] *= scale;
+            }
+
+            for row in 0..4 {
+                let factor = matrix[row][column];
+                if row == column || factor == 0.0 {
+                    continue;
+                }
+
+                for index in 0..4 {
+                    matrix[row][index] -= factor * matrix[column][index];
+                    inverse[row][index] -= factor * inverse[column][index];
+                }
+            }
+        }
+
+        Some(Matrix4(inverse))
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Matrix4::identity()
+    }
+
+    // Only the linear part contributes, the bottom row being 0 0 0 1. Negative
+    // when the transform mirrors.
+    pub fn determinant(&self) -> f64 {
+        let m = &self.0;
+
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    pub fn transform_point(&self, point: &Vector) -> Vector {
+        let m = &self.0;
+
+        Vector::new(
+            m[0][0] * point.x + m[0][1] * point.y + m[0][2] * point.z + m[0][3],
+            m[1][0] * point.x + m[1][1] * point.y + m[1][2] * point.z + m[1][3],
+            m[2][0] * point.x + m[2][1] * point.y + m[2][2] * point.z + m[2][3],
+        )
+    }
+
+    // Directions and offsets are unaffected by translation
+    pub fn transform_vector(&self, vector: &Vector) -> Vector {
+        let m = &self.0;
+
+        Vector::new(
+            m[0][0] * vector.x + m[0][1] * vector.y + m[0][2] * vector.z,
+            m[1][0] * vector.x + m[1][1] * vector.y + m[1][2] * vector.z,
+            m[2][0] * vector.x + m[2][1] * vector.y + m[2][2] * vector.z,
+        )
+    }
+}
+
+// Affine transform together with its inverse, so points can be moved both ways
+// and normals transformed without inverting the matrix again
+#[derive(Copy, Clone, Debug)]
+pub struct Transform {
+    matrix: Matrix4,
+    inverse: Matrix4,
+}
+
+impl Transform {
+    // None if the matrix is singular, such as when it scales an axis to zero
+    pub fn new(matrix: Matrix4) -> Option<Transform> {
+        let inverse = matrix.inverse()?;
+
+        Some(Transform { matrix, inverse })
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.matrix.is_identity()
+    }
+
+    pub fn determinant(&self) -> f64 {
+        self.matrix.determinant()
+    }
+
+    pub fn point(&self, point: &Vector) -> Vector {
+        self.matrix.transform_point(point)
+    }
+
+    pub fn vector(&self, vector: &Vector) -> Vector {
+        self.matrix.transform_vector(vector)
+    }
+
+    // Normals are transformed by the inverse transpose, which keeps them
+    // perpendicular to the surface under non-uniform scaling. The result is
+    // left unnormalized.
+    pub fn normal(&self, normal: &Vector) -> Vector {
+        let m = &self.inverse.0;
+
+        Vector::new(
+            m[0][0] * normal.x + m[1][0] * normal.y + m[2][0] * normal.z,
+            m[0][1] * normal.x + m[1][1] * normal.y + m[2][1] * normal.z,
+            m[0][2] * normal.x + m[1][2] * normal.y + m[2][2] * normal.z,
+        )
+    }
+
+    // The direction is not normalized, so distances along the ray are the same
+    // before and after
+    pub fn ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            position: self.point(&ray.position),
+            direction: self.vector(&ray.direction),
+            time: ray.time,
+        }
+    }
+}
+
+// Unit quaternion representing a rotation, w + xi + yj + zk
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quaternion {
+    w: f64,
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+impl Quaternion {
+    pub fn new(w: f64, x: f64, y: f64, z: f64) -> Quaternion {
+        Quaternion { w, x, y, z }
+    }
+
+    pub fn identity() -> Quaternion {
+        Quaternion::new(1.0, 0.0, 0.0, 0.0)
+    }
+
+    // Rotation about an axis through the origin, matching Vector::rotate
+    pub fn from_axis_angle(degrees: f64, axis: &Vector) -> Quaternion {
+        let axis = axis.normalize();
+        let half_angle = 0.5 * degrees * consts::PI / 180.0;
+        let sin = half_angle.sin();
+
+        Quaternion::new(half_angle.cos(), axis.x * sin, axis.y * sin, axis.z * sin)
+    }
+
+    // Hamilton product, rotating by other first, then by this quaternion
+    pub fn multiply(&self, other: &Quaternion) -> Quaternion {
+        Quaternion::new(
+            self.w * other.w - self.x * other.x - self.y * other.y - self.z * other.z,
+            self.w * other.x + self.x * other.w + self.y * other.z - self.z * other.y,
+            self.w * other.y - self.x * other.z + self.y * other.w + self.z * other.x,
+            self.w * other.z + self.x * other.y - self.y * other.x + self.z * other.w,
+        )
+    }
+
+    // The opposite rotation
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub fn dot(&self, other: &Quaternion) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    // Spherical linear interpolation, turning at a constant rate the shorter
+    // way round
+    pub fn slerp(&self, other: &Quaternion, t: f64) -> Quaternion {
+        let (other, cos) = match self.dot(other) {
+            cos if cos < 0.0 => (
+                Quaternion::new(-other.w, -other.x, -other.y, -other.z),
+                -cos,
+            ),
+            cos => (*other, cos),
+        };
+
+        // Nearly equal rotations are blended linearly rather than dividing by
+        // the sine of a tiny angle
+        let (a, b) = match cos > 0.9995 {
+            true => (1.0 - t, t),
+            false => {
+                let angle = cos.acos();
+                let sin = angle.sin();
+
+                (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+            }
+        };
+
+        let blend = Quaternion::new(
+            a * self.w + b * other.w,
+            a * self.x + b * other.x,
+            a * self.y + b * other.y,
+            a * self.z + b * other.z,
+        );
+        let length = blend.dot(&blend).sqrt();
+
+        Quaternion::new(
+            blend.w / length,
+            blend.x / length,
+            blend.y / length,
+            blend.z / length,
+        )
+    }
+
+    pub fn rotate(&self, vector: &Vector) -> Vector {
+        let axis = Vector::new(self.x, self.y, self.z);
+        let t = axis.cross(vector).scale(2.0);
+
+        vector.add(&t.scale(self.w)).add(&axis.cross(&t))
+    }
+
+    pub fn matrix(&self) -> Matrix4 {
+        let x = self.rotate(&Vector::new(1.0, 0.0, 0.0));
+        let y = self.rotate(&Vector::new(0.0, 1.0, 0.0));
+        let z = self.rotate(&Vector::new(0.0, 0.0, 1.0));
+
+        Matrix4([
+            [x.x, y.x, z.x, 0.0],
+            [x.y, y.y, z.y, 0.0],
+            [x.z, y.z, z.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+}
+
+// Transform made of a scale, then a rotation, then a translation, which unlike
+// a matrix can be interpolated
+#[derive(Copy, Clone, Debug)]
+pub struct Decomposition {
+    pub scale: Vector,
+    pub rotation: Quaternion,
+    pub translate: Vector,
+}
+
+impl Decomposition {
+    pub fn matrix(&self) -> Matrix4 {
+        Matrix4::translation(&self.translate)
+            .multiply(&self.rotation.matrix())
+            .multiply(&Matrix4::scaling(&self.scale))
+    }
+
+    pub fn interpolate(&self, other: &Decomposition, t: f64) -> Decomposition {
+        let lerp = |a: &Vector, b: &Vector| a.scale(1.0 - t).add(&b.scale(t));
+
+        Decomposition {
+            scale: lerp(&self.scale, &other.scale),
+            rotation: self.rotation.slerp(&other.rotation, t),
+            translate: lerp(&self.translate, &other.translate),
+        }
+    }
+
+    // Each part is undone in reverse order, without inverting the matrix. The
+    // scale must not be zero along any axis.
+    fn transform(&self) -> Transform {
+        let inverse_scale = Vector::new(1.0 / self.scale.x, 1.0 / self.scale.y, 1.0 / self.scale.z);
+        let inverse = Matrix4::scaling(&inverse_scale)
+            .multiply(&self.rotation.conjugate().matrix())
+            .multiply(&Matrix4::translation(&self.translate.negative()));
+
+        Transform {
+            matrix: self.matrix(),
+            inverse,
+        }
+    }
+}
+
+// Transform changing over time, interpolated between keyframes and held before
+// the first and after the last
+#[derive(Clone, Debug)]
+pub struct AnimatedTransform {
+    keyframes: Vec<(f64, Decomposition)>,
+}
+
+impl AnimatedTransform {
+    // None without keyframes, or if any axis is ever scaled to zero, including
+    // by a scale changing sign between keyframes
+    pub fn new(mut keyframes: Vec<(f64, Decomposition)>) -> Option<AnimatedTransform> {
+        keyframes.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        let axes = |scale: &Vector| [scale.x, scale.y, scale.z];
+        let flattened = keyframes
+            .iter()
+            .any(|(_, keyframe)| axes(&keyframe.scale).contains(&0.0));
+        let flipped = keyframes.windows(2).any(|pair| {
+            let (a, b) = (axes(&pair[0].1.scale), axes(&pair[1].1.scale));
+            (0..3).any(|axis| a[axis] * b[axis] < 0.0)
+        });
+
+        if keyframes.is_empty() || flattened || flipped {
+            return None;
+        }
+
+        Some(AnimatedTransform { keyframes })
+    }
+
+    pub fn keyframes(&self) -> &[(f64, Decomposition)] {
+        &self.keyframes
+    }
+
+    pub fn at(&self, time: f64) -> Transform {
+        let keyframes = &self.keyframes;
+
+        match keyframes.iter().position(|&(keyframe_time, _)| keyframe_time > time) {
+            Some(0) => keyframes[0].1.transform(),
+            None => keyframes[keyframes.len() - 1].1.transform(),
+            Some(index) => {
+                let (start_time, start) = keyframes[index - 1];
+                let (end_time, end) = keyframes[index];
+
+                start
+                    .interpolate(&end, (time - start_time) / (end_time - start_time))
+                    .transform()
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -167,7 +599,7 @@ mod tests {
             y: 5.0,
             z: -3.0,
         };
-        approx::assert_ulps_eq!((70.0 as f64).sqrt(), v.magnitude());
+        approx::assert_ulps_eq!(70.0_f64.sqrt(), v.magnitude());
     }
 
     #[test]
@@ -256,9 +688,9 @@ mod tests {
         };
 
         let v2 = v1.normalize();
-        approx::assert_ulps_eq!(1.0 / (3.0 as f64).sqrt(), v2.x);
-        approx::assert_ulps_eq!(-1.0 / (3.0 as f64).sqrt(), v2.y);
-        approx::assert_ulps_eq!(1.0 / (3.0 as f64).sqrt(), v2.z);
+        approx::assert_ulps_eq!(1.0 / 3.0_f64.sqrt(), v2.x);
+        approx::assert_ulps_eq!(-1.0 / 3.0_f64.sqrt(), v2.y);
+        approx::assert_ulps_eq!(1.0 / 3.0_f64.sqrt(), v2.z);
     }
 
     #[test]
@@ -293,9 +725,9 @@ mod tests {
         };
 
         let v2 = v1.rotate(120.0, &axis);
-        approx::assert_ulps_eq!(0.0, v2.x);
-        approx::assert_ulps_eq!(1.0, v2.y);
-        approx::assert_ulps_eq!(0.0, v2.z);
+        approx::assert_abs_diff_eq!(0.0, v2.x, epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(1.0, v2.y, epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(0.0, v2.z, epsilon = 1e-12);
 
         let v1 = Vector {
             x: -34.0,
@@ -335,4 +767,250 @@ mod tests {
         approx::assert_ulps_eq!(3.0, r.y);
         approx::assert_ulps_eq!(5.0, r.z);
     }
+
+    #[test]
+    fn refract() {
+        let normal = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+
+        let v = Vector {
+            x: 0.0,
+            y: -1.0,
+            z: 0.0,
+        };
+
+        let r = v.refract(&normal, 1.0 / 1.5).unwrap();
+        approx::assert_ulps_eq!(0.0, r.x);
+        approx::assert_ulps_eq!(-1.0, r.y);
+        approx::assert_ulps_eq!(0.0, r.z);
+
+        let v = Vector {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        }
+        .normalize();
+
+        // Snell's law: sin(45) / 1.5 = sin(theta)
+        let r = v.refract(&normal, 1.0 / 1.5).unwrap();
+        let sin_theta = (0.5_f64).sqrt() / 1.5;
+        approx::assert_abs_diff_eq!(sin_theta, r.x, epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(-(1.0 - sin_theta * sin_theta).sqrt(), r.y, epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(1.0, r.magnitude(), epsilon = 1e-12);
+
+        // Past the critical angle going from glass into air
+        assert!(v.refract(&normal, 1.5).is_none());
+    }
+
+    #[test]
+    fn orthonormal_basis() {
+        let v = Vector {
+            x: 1.0,
+            y: 2.0,
+            z: -3.0,
+        }
+        .normalize();
+
+        let (tangent, bitangent) = v.orthonormal_basis();
+        approx::assert_abs_diff_eq!(0.0, v.dot(&tangent), epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(0.0, v.dot(&bitangent), epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(0.0, tangent.dot(&bitangent), epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(1.0, tangent.magnitude(), epsilon = 1e-12);
+        approx::assert_abs_diff_eq!(1.0, bitangent.magnitude(), epsilon = 1e-12);
+        assert!(tangent.cross(&bitangent).equals(&v));
+    }
+
+    // Round trips through several transforms pick up more rounding error than
+    // Vector::equals allows
+    fn assert_close(expected: &Vector, actual: &Vector) {
+        approx::assert_abs_diff_eq!(expected.x, actual.x, epsilon = 1e-9);
+        approx::assert_abs_diff_eq!(expected.y, actual.y, epsilon = 1e-9);
+        approx::assert_abs_diff_eq!(expected.z, actual.z, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn matrix_multiply() {
+        let scale = Matrix4::scaling(&Vector::new(2.0, 3.0, 4.0));
+        let translation = Matrix4::translation(&Vector::new(1.0, -1.0, 5.0));
+
+        // Scaled first, then translated
+        let matrix = translation.multiply(&scale);
+        let p = matrix.transform_point(&Vector::new(1.0, 1.0, 1.0));
+        assert!(p.equals(&Vector::new(3.0, 2.0, 9.0)));
+
+        // Translation doesn't move vectors
+        let v = matrix.transform_vector(&Vector::new(1.0, 1.0, 1.0));
+        assert!(v.equals(&Vector::new(2.0, 3.0, 4.0)));
+
+        let matrix = scale.multiply(&translation);
+        let p = matrix.transform_point(&Vector::new(1.0, 1.0, 1.0));
+        assert!(p.equals(&Vector::new(4.0, 0.0, 24.0)));
+
+        assert_eq!(scale, scale.multiply(&Matrix4::identity()));
+        assert_eq!(scale, Matrix4::identity().multiply(&scale));
+    }
+
+    #[test]
+    fn matrix_inverse() {
+        let rotation = Quaternion::from_axis_angle(60.0, &Vector::new(0.0, 1.0, 1.0));
+        let matrix = Matrix4::translation(&Vector::new(3.0, -2.0, 7.0))
+            .multiply(&rotation.matrix())
+            .multiply(&Matrix4::scaling(&Vector::new(2.0, -0.5, 3.0)));
+
+        let inverse = matrix.inverse().unwrap();
+        let product = matrix.multiply(&inverse);
+        for row in 0..4 {
+            for column in 0..4 {
+                let expected = if row == column { 1.0 } else { 0.0 };
+                approx::assert_abs_diff_eq!(expected, product.0[row][column], epsilon = 1e-12);
+            }
+        }
+
+        let p = Vector::new(1.0, 2.0, 3.0);
+        assert_close(&p, &inverse.transform_point(&matrix.transform_point(&p)));
+
+        assert!(Matrix4::scaling(&Vector::new(1.0, 0.0, 1.0))
+            .inverse()
+            .is_none());
+        assert!(Matrix4::identity().inverse().unwrap().is_identity());
+    }
+
+    #[test]
+    fn matrix_determinant() {
+        let matrix = Matrix4::translation(&Vector::new(3.0, -2.0, 7.0))
+            .multiply(&Matrix4::scaling(&Vector::new(2.0, -0.5, 3.0)));
+        approx::assert_ulps_eq!(-3.0, matrix.determinant());
+
+        let rotation = Quaternion::from_axis_angle(60.0, &Vector::new(0.0, 1.0, 1.0));
+        approx::assert_abs_diff_eq!(1.0, rotation.matrix().determinant(), epsilon = 1e-12);
+
+        let matrix = Matrix4::new([
+            [0.0, 1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        approx::assert_ulps_eq!(-1.0, matrix.determinant());
+    }
+
+    #[test]
+    fn transform() {
+        let matrix = Matrix4::translation(&Vector::new(0.0, 5.0, 0.0))
+            .multiply(&Matrix4::scaling(&Vector::new(4.0, 1.0, 1.0)));
+        let transform = Transform::new(matrix).unwrap();
+
+        let p = Vector::new(1.0, 1.0, 1.0);
+        assert!(transform.point(&p).equals(&Vector::new(4.0, 6.0, 1.0)));
+        assert_close(&p, &transform.inverse().point(&transform.point(&p)));
+        assert!(transform
+            .inverse()
+            .vector(&Vector::new(4.0, 0.0, 0.0))
+            .equals(&Vector::new(1.0, 0.0, 0.0)));
+
+        // A normal to the plane x + y = 0 stays perpendicular to it once the
+        // plane is stretched along x
+        let normal = Vector::new(1.0, 1.0, 0.0);
+        let tangent = Vector::new(1.0, -1.0, 0.0);
+        let moved = transform.normal(&normal);
+        approx::assert_abs_diff_eq!(0.0, moved.dot(&transform.vector(&tangent)), epsilon = 1e-12);
+        assert!(moved
+            .normalize()
+            .equals(&Vector::new(1.0, 4.0, 0.0).normalize()));
+
+        // Rays keep their parameterization
+        let ray = transform.ray(&Ray {
+            position: Vector::new(0.0, 0.0, 0.0),
+            direction: Vector::new(1.0, 0.0, 0.0),
+            time: 0.0,
+        });
+        assert!(ray.position.equals(&Vector::new(0.0, 5.0, 0.0)));
+        assert!(ray.direction.equals(&Vector::new(4.0, 0.0, 0.0)));
+
+        assert!(!transform.is_identity());
+        assert!(Transform::new(Matrix4::identity()).unwrap().is_identity());
+        assert!(Transform::new(Matrix4::scaling(&Vector::new(0.0, 1.0, 1.0))).is_none());
+    }
+
+    #[test]
+    fn quaternion_rotate() {
+        let axis = Vector::new(1.0, 1.0, 1.0);
+        let q = Quaternion::from_axis_angle(120.0, &axis);
+
+        let v = q.rotate(&Vector::new(1.0, 0.0, 0.0));
+        assert!(v.equals(&Vector::new(0.0, 1.0, 0.0)));
+
+        let v = Vector::new(-34.0, 13.0, 124.0);
+        let axis = Vector::new(-23.0, 345.0, 24.0);
+        let q = Quaternion::from_axis_angle(-75.0, &axis);
+        assert_close(&v.rotate(-75.0, &axis), &q.rotate(&v));
+
+        let v = Vector::new(0.3, -0.2, 0.9);
+        assert!(q.matrix().transform_vector(&v).equals(&q.rotate(&v)));
+        assert!(Quaternion::identity().rotate(&v).equals(&v));
+    }
+
+    #[test]
+    fn quaternion_multiply() {
+        let x = Quaternion::from_axis_angle(90.0, &Vector::new(1.0, 0.0, 0.0));
+        let y = Quaternion::from_axis_angle(90.0, &Vector::new(0.0, 1.0, 0.0));
+
+        // Rotated about x first, then y
+        let q = y.multiply(&x);
+        let v = Vector::new(0.0, 1.0, 0.0);
+        assert!(q.rotate(&v).equals(&y.rotate(&x.rotate(&v))));
+        assert!(q.rotate(&v).equals(&Vector::new(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn quaternion_slerp() {
+        let axis = Vector::new(0.0, 0.0, 1.0);
+        let start = Quaternion::from_axis_angle(10.0, &axis);
+        let end = Quaternion::from_axis_angle(130.0, &axis);
+        let v = Vector::new(1.0, 0.0, 0.0);
+
+        // Turns at a constant rate
+        let q = start.slerp(&end, 0.25);
+        assert_close(&v.rotate(40.0, &axis), &q.rotate(&v));
+        assert_close(&end.rotate(&v), &start.slerp(&end, 1.0).rotate(&v));
+
+        // The same rotation written negated still takes the shorter path
+        let negated = Quaternion::new(-end.w, -end.x, -end.y, -end.z);
+        assert_close(&q.rotate(&v), &start.slerp(&negated, 0.25).rotate(&v));
+
+        // Nearly equal rotations stay normalized
+        let nearby = Quaternion::from_axis_angle(10.001, &axis);
+        approx::assert_ulps_eq!(1.0, start.slerp(&nearby, 0.5).dot(&start.slerp(&nearby, 0.5)));
+    }
+
+    #[test]
+    fn animated_transform() {
+        let keyframe = |x: f64, degrees: f64, scale: f64| Decomposition {
+            scale: Vector::new(scale, scale, scale),
+            rotation: Quaternion::from_axis_angle(degrees, &Vector::new(0.0, 0.0, 1.0)),
+            translate: Vector::new(x, 0.0, 0.0),
+        };
+        let start = keyframe(0.0, 0.0, 1.0);
+        let motion = AnimatedTransform::new(vec![(2.0, keyframe(4.0, 90.0, 3.0)), (0.0, start)])
+            .unwrap();
+        assert_eq!(0.0, motion.keyframes()[0].0);
+
+        // Held outside the keyframes
+        let p = Vector::new(1.0, 0.0, 0.0);
+        assert_close(&p, &motion.at(-1.0).point(&p));
+        assert_close(&Vector::new(4.0, 3.0, 0.0), &motion.at(5.0).point(&p));
+
+        // Halfway turned, scaled and moved, with a matching inverse
+        let transform = motion.at(1.0);
+        let half = 0.5f64.sqrt();
+        assert_close(&Vector::new(2.0 + 2.0 * half, 2.0 * half, 0.0), &transform.point(&p));
+        assert_close(&p, &transform.inverse().point(&transform.point(&p)));
+
+        assert!(AnimatedTransform::new(Vec::new()).is_none());
+        assert!(AnimatedTransform::new(vec![(0.0, keyframe(0.0, 0.0, 0.0))]).is_none());
+        let flipped = keyframe(0.0, 0.0, -1.0);
+        assert!(AnimatedTransform::new(vec![(0.0, start), (1.0, flipped)]).is_none());
+    }
 }
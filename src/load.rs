@@ -1,13 +1,40 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path;
 
+use super::config;
 use super::linear;
 use super::scene;
 
+mod stl;
+mod threemf;
 mod wavefront;
 
+// Materials and the triangles referring to them by index
+type ModelData = (
+    Vec<Box<dyn scene::material::Material>>,
+    Vec<scene::primitive::Triangle>,
+);
+
+// Triangles with no area, which some exporters write and some transforms leave,
+// have no normal and can't be hit, so they are left out
+fn triangle(
+    a: linear::Vector,
+    b: linear::Vector,
+    c: linear::Vector,
+    material_id: usize,
+) -> Option<scene::primitive::Triangle> {
+    let normal = b.subtract(&a).cross(&c.subtract(&a));
+
+    if normal.magnitude() > 0.0 {
+        Some(scene::primitive::Triangle::new(a, b, c, material_id, None, None))
+    } else {
+        None
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct LightingData {
     lights: Vec<scene::lighting::LightSource>,
@@ -17,14 +44,425 @@ fn parse_lights(lighting_file: &str) -> Result<Vec<scene::lighting::LightSource>
     let f = fs::File::open(path::Path::new(lighting_file))?;
     let data: LightingData = serde_json::from_reader(f)?;
 
+    if !data.lights.iter().all(|light| light.is_valid()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Light directions and disk normals can't be zero",
+        ));
+    }
+
     Ok(data.lights)
 }
 
-pub fn scene(lighting_file: &str, model_file: &str) -> Result<scene::Scene, io::Error> {
-    let lights = parse_lights(&lighting_file).expect("Lighting config should load correctly");
+#[derive(Serialize, Deserialize)]
+struct MaterialData {
+    materials: HashMap<String, config::MaterialOverride>,
+}
+
+fn parse_material_overrides(
+    material_file: Option<&str>,
+) -> Result<HashMap<String, config::MaterialOverride>, io::Error> {
+    let material_file = match material_file {
+        Some(material_file) => material_file,
+        None => return Ok(HashMap::new()),
+    };
+
+    let f = fs::File::open(path::Path::new(material_file))?;
+    let data: MaterialData = serde_json::from_reader(f)?;
+
+    Ok(data.materials)
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SceneData {
+    #[serde(default)]
+    models: Vec<config::ModelConfig>,
+
+    #[serde(default)]
+    primitives: Vec<config::PrimitiveConfig>,
+}
+
+fn parse_scene(scene_file: &str) -> Result<SceneData, io::Error> {
+    let f = fs::File::open(path::Path::new(scene_file))?;
+    let data: SceneData = serde_json::from_reader(f)?;
+
+    Ok(data)
+}
+
+fn invalid_transform(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Transform of {} must not scale to zero", name),
+    )
+}
+
+// Whether a shape is scaled evenly along every axis wherever it is placed,
+// ignoring mirroring, which is all the area of analytic shapes allows for
+fn scales_evenly(
+    transform: &config::TransformConfig,
+    keyframes: &[config::TransformKeyframe],
+) -> bool {
+    let even = |transform: &config::TransformConfig| {
+        let scale = |axis: usize| transform.scale.component(axis).abs();
+        scale(0) == scale(1) && scale(1) == scale(2)
+    };
+
+    match keyframes.is_empty() {
+        true => even(transform),
+        false => keyframes.iter().all(|keyframe| even(&keyframe.transform)),
+    }
+}
+
+// Keyframes replace the transform. None if either flattens the model.
+fn place(
+    transform: &config::TransformConfig,
+    keyframes: &[config::TransformKeyframe],
+    mesh: usize,
+    material_offset: usize,
+    culling: Option<scene::primitive::Culling>,
+) -> Option<scene::instance::Instance> {
+    match keyframes.is_empty() {
+        true => {
+            let transform = transform.to_transform()?;
+            Some(scene::instance::Instance::new(mesh, material_offset, culling, transform))
+        }
+        false => {
+            let motion = config::to_animated_transform(keyframes)?;
+            Some(scene::instance::Instance::animated(mesh, material_offset, culling, motion))
+        }
+    }
+}
+
+fn load_model(
+    model_file: &str,
+    overrides: &HashMap<String, config::MaterialOverride>,
+    default_material: &config::MaterialConfig,
+) -> Result<ModelData, io::Error> {
+    let model_path = path::Path::new(model_file);
+    let extension = model_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    let model = match extension.as_deref() {
+        Some("obj") => wavefront::load_obj(model_path, overrides)
+            .expect("OBJ/MTL model files should load correctly"),
+        Some("stl") => stl::load_stl(model_path, default_material.to_material())?,
+        Some("3mf") => threemf::load_3mf(model_path, overrides, default_material)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported model file format: {}", model_file),
+            ))
+        }
+    };
+
+    Ok(model)
+}
+
+pub fn scene(render_config: &config::Config) -> Result<scene::Scene, io::Error> {
+    let lights = parse_lights(&render_config.lighting_file)
+        .expect("Lighting config should load correctly");
+    let overrides = parse_material_overrides(render_config.material_file.as_deref())
+        .expect("Material overrides should load correctly");
+
+    let scene_data = match &render_config.scene_file {
+        Some(scene_file) => parse_scene(scene_file).expect("Scene file should load correctly"),
+        None => SceneData::default(),
+    };
+
+    let mut models = Vec::new();
+    if let Some(model_file) = &render_config.model_file {
+        models.push(config::ModelConfig {
+            file: model_file.clone(),
+            transform: config::TransformConfig::default(),
+            materials: HashMap::new(),
+            culling: None,
+            keyframes: Vec::new(),
+        });
+    }
+    models.extend(scene_data.models);
+
+    if models.is_empty() && scene_data.primitives.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Config must give a model file or a scene file",
+        ));
+    }
+
+    let mut materials = Vec::new();
+    let mut meshes = Vec::new();
+    let mut instances = Vec::new();
+
+    // Instances of the same file share its geometry, and also its materials
+    // when they override them in the same way
+    let mut mesh_indices: HashMap<String, usize> = HashMap::new();
+    let mut material_offsets = Vec::new();
+
+    for model in &models {
+        let mut model_overrides = overrides.clone();
+        for (name, model_override) in &model.materials {
+            let merged = match overrides.get(name) {
+                Some(file_override) => model_override.or(file_override),
+                None => model_override.clone(),
+            };
+            model_overrides.insert(name.clone(), merged);
+        }
+
+        let loaded_materials = material_offsets
+            .iter()
+            .find(|(file, overrides, _)| *file == model.file && *overrides == model_overrides)
+            .map(|(_, _, offset)| *offset);
+
+        let (mesh, material_offset) = match (mesh_indices.get(&model.file), loaded_materials) {
+            (Some(&mesh), Some(material_offset)) => (mesh, material_offset),
+            (mesh, _) => {
+                let (model_materials, objects) =
+                    load_model(&model.file, &model_overrides, &render_config.default_material)?;
+
+                let material_offset = materials.len();
+                materials.extend(model_materials);
+                material_offsets.push((model.file.clone(), model_overrides, material_offset));
+
+                let mesh = match mesh {
+                    Some(&mesh) => mesh,
+                    None => {
+                        meshes.push(scene::instance::Mesh::new(
+                            objects
+                                .into_iter()
+                                .map(|object| Box::new(object) as Box<dyn scene::primitive::Primitive>)
+                                .collect(),
+                        ));
+                        mesh_indices.insert(model.file.clone(), meshes.len() - 1);
+                        meshes.len() - 1
+                    }
+                };
+
+                (mesh, material_offset)
+            }
+        };
+
+        let instance = place(
+            &model.transform,
+            &model.keyframes,
+            mesh,
+            material_offset,
+            model.culling,
+        );
+        instances.push(instance.ok_or_else(|| invalid_transform(&model.file))?);
+    }
+
+    // Each primitive is a mesh of its own, placed by its instance
+    for primitive in &scene_data.primitives {
+        let material = primitive
+            .material
+            .as_ref()
+            .unwrap_or(&render_config.default_material);
+        // Glowing shapes are sampled by their area
+        let bounded = !matches!(primitive.shape, config::PrimitiveShape::Plane);
+        if bounded
+            && material.emission.max_component() > 0.0
+            && !scales_evenly(&primitive.transform, &primitive.keyframes)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Glowing primitives must be scaled evenly along every axis",
+            ));
+        }
+
+        materials.push(material.to_material());
+
+        meshes.push(scene::instance::Mesh::new(vec![primitive.shape.to_primitive(0)]));
+        let instance = place(
+            &primitive.transform,
+            &primitive.keyframes,
+            meshes.len() - 1,
+            materials.len() - 1,
+            primitive.culling,
+        );
+        instances.push(instance.ok_or_else(|| invalid_transform("primitive"))?);
+    }
+
+    Ok(scene::Scene::new(
+        materials,
+        meshes,
+        instances,
+        lights,
+        render_config.sampling.mesh_light_samples,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn place_models() {
+        let triangle = scene::primitive::Triangle::new(
+            linear::Vector::new(0.0, 0.0, 0.0),
+            linear::Vector::new(1.0, 0.0, 0.0),
+            linear::Vector::new(0.0, 1.0, 0.0),
+            0,
+            None,
+            Some((
+                linear::Vector::new(0.0, 0.0, 1.0),
+                linear::Vector::new(0.0, 0.0, 1.0),
+                linear::Vector::new(0.0, 0.0, 1.0),
+            )),
+        );
+        let mesh = scene::instance::Mesh::new(vec![Box::new(triangle)]);
+        let object = mesh.objects()[0].as_ref();
+
+        // Quarter turn about x, then moved up
+        let transform = config::TransformConfig {
+            translate: linear::Vector::new(0.0, 5.0, 0.0),
+            rotate: linear::Vector::new(90.0, 0.0, 0.0),
+            scale: linear::Vector::new(2.0, 2.0, 2.0),
+        };
+        let instance =
+            scene::instance::Instance::new(0, 3, None, transform.to_transform().unwrap());
+
+        let bounds = instance.bounds(&mesh);
+        assert!(bounds.min.equals(&linear::Vector::new(0.0, 5.0, 0.0)));
+        assert!(bounds.max.equals(&linear::Vector::new(2.0, 5.0, 2.0)));
+        assert!(instance
+            .normal(&object.surface_normal(0.2, 0.2), 0.0)
+            .equals(&linear::Vector::new(0.0, -1.0, 0.0)));
+        approx::assert_ulps_eq!(2.0, instance.area(object, 0.0));
+        assert_eq!(3, instance.material_id(object));
+
+        // Rays are intersected in the mesh's coordinates
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.5, 0.0, 0.5),
+            direction: linear::Vector::new(0.0, 1.0, 0.0),
+            time: 0.0,
+        };
+        let (intersected, t, _, _) = object.intersect(
+            &instance.object_ray(&ray),
+            f64::MAX,
+            scene::primitive::Culling::Back,
+        );
+        assert!(intersected);
+        approx::assert_ulps_eq!(5.0, t);
+
+        // Mirrored instances keep normals facing out of the surface
+        let transform = config::TransformConfig {
+            scale: linear::Vector::new(1.0, 1.0, -1.0),
+            ..config::TransformConfig::default()
+        };
+        let instance =
+            scene::instance::Instance::new(0, 0, None, transform.to_transform().unwrap());
+        assert!(instance
+            .normal(&object.geometric_normal(0.2, 0.2), 0.0)
+            .equals(&linear::Vector::new(0.0, 0.0, -1.0)));
+    }
+
+    #[test]
+    fn move_models() {
+        let triangle = scene::primitive::Triangle::new(
+            linear::Vector::new(0.0, 0.0, 0.0),
+            linear::Vector::new(1.0, 0.0, 0.0),
+            linear::Vector::new(0.0, 1.0, 0.0),
+            0,
+            None,
+            None,
+        );
+        let mesh = scene::instance::Mesh::new(vec![Box::new(triangle)]);
+        let object = mesh.objects()[0].as_ref();
+
+        // Quarter turn about z while rising
+        let keyframes = vec![
+            config::TransformKeyframe {
+                time: 1.0,
+                transform: config::TransformConfig {
+                    translate: linear::Vector::new(0.0, 0.0, 4.0),
+                    rotate: linear::Vector::new(0.0, 0.0, 90.0),
+                    ..config::TransformConfig::default()
+                },
+            },
+            config::TransformKeyframe {
+                time: 0.0,
+                transform: config::TransformConfig::default(),
+            },
+        ];
+        let instance = place(&config::TransformConfig::default(), &keyframes, 0, 0, None).unwrap();
+
+        // Hits move with the instance, and it is held outside its keyframes
+        for &(time, distance) in &[(-1.0, 10.0), (0.0, 10.0), (0.5, 8.0), (2.0, 6.0)] {
+            let ray = linear::Ray {
+                position: linear::Vector::new(-0.1, 0.3, 10.0),
+                direction: linear::Vector::new(0.0, 0.0, -1.0),
+                time,
+            };
+            let (intersected, t, _, _) = object.intersect(
+                &instance.object_ray(&ray),
+                f64::MAX,
+                scene::primitive::Culling::Back,
+            );
+            assert_eq!(time > 0.0, intersected);
+            if intersected {
+                approx::assert_relative_eq!(distance, t, epsilon = 1e-9);
+            }
+        }
+
+        // Bounds cover the whole motion, even where it turns out of the plane
+        // of both keyframes
+        let mut flipped = keyframes.clone();
+        flipped[0].transform.rotate = linear::Vector::new(180.0, 0.0, 0.0);
+        flipped[0].transform.translate = linear::Vector::new(0.0, 0.0, 0.0);
+        let flipped = place(&config::TransformConfig::default(), &flipped, 0, 0, None).unwrap();
+
+        for instance in &[instance, flipped] {
+            let bounds = instance.bounds(&mesh);
+            for step in 0..=20 {
+                let time = step as f64 / 20.0;
+                for corner in &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)] {
+                    let corner = linear::Vector::new(corner.0, corner.1, 0.0);
+                    let point = instance.point(&corner, time);
+                    assert!(point.max(&bounds.min).equals(&point));
+                    assert!(point.min(&bounds.max).equals(&point));
+                }
+            }
+        }
+
+        // Scales can't pass through zero between keyframes
+        let mut mirrored = keyframes.clone();
+        mirrored[0].transform.scale = linear::Vector::new(-1.0, 1.0, 1.0);
+        assert!(place(&config::TransformConfig::default(), &mirrored, 0, 0, None).is_none());
+
+        // Mirroring keeps areas in proportion, unlike stretching one axis
+        assert!(scales_evenly(&config::TransformConfig::default(), &mirrored));
+        let mut stretched = keyframes.clone();
+        stretched[1].transform.scale = linear::Vector::new(1.0, 2.0, 1.0);
+        assert!(!scales_evenly(&config::TransformConfig::default(), &stretched));
+        assert!(!scales_evenly(&stretched[1].transform, &[]));
+    }
+
+    #[test]
+    fn scaled_plane_texture() {
+        let mesh = scene::instance::Mesh::new(vec![Box::new(scene::primitive::Plane::new(0))]);
+        let object = mesh.objects()[0].as_ref();
 
-    let (materials, objects) = wavefront::load_obj(path::Path::new(&model_file))
-        .expect("OBJ/MTL model files should load correctly");
+        // Texture coordinates are spread over the instance's scale along the
+        // plane, whatever its height
+        let scales = [(1.0, 1.0, 1.0, 1.0), (4.0, 1.0, 4.0, 0.25), (2.0, 5.0, 8.0, 0.25)];
+        for &(x, y, z, density) in &scales {
+            let transform = config::TransformConfig {
+                scale: linear::Vector::new(x, y, z),
+                ..config::TransformConfig::default()
+            };
+            let instance =
+                scene::instance::Instance::new(0, 0, None, transform.to_transform().unwrap());
+            let hit = scene::instance::Hit {
+                object,
+                instance: &instance,
+                t: 1.0,
+                b: 0.5,
+                c: 0.5,
+                time: 0.0,
+            };
 
-    Ok(scene::Scene::new(materials, objects, lights))
+            approx::assert_ulps_eq!(density, hit.uv_density());
+        }
+    }
 }
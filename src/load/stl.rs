@@ -1,44 +1,230 @@
-use crate::linear_algebra as la;
-use crate::primitive;
 use std::fs;
 use std::io;
-use std::io::Read;
-use std::io::Seek;
 use std::path;
+use std::str;
 
-fn read_vector(f: &mut fs::File) -> Result<la::Vector, io::Error> {
+use super::linear;
+use super::scene;
+
+const HEADER_SIZE: usize = 80;
+const FACET_SIZE: usize = 50;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
     let mut buffer = [0; 4];
+    buffer.copy_from_slice(&data[offset..offset + 4]);
+
+    u32::from_le_bytes(buffer)
+}
 
-    let _ = f.read(&mut buffer[..])?;
+fn read_vector(data: &[u8], offset: usize) -> linear::Vector {
+    let mut buffer = [0; 4];
+
+    buffer.copy_from_slice(&data[offset..offset + 4]);
     let x = f32::from_le_bytes(buffer) as f64;
-    let _ = f.read(&mut buffer[..])?;
+    buffer.copy_from_slice(&data[offset + 4..offset + 8]);
     let y = f32::from_le_bytes(buffer) as f64;
-    let _ = f.read(&mut buffer[..])?;
+    buffer.copy_from_slice(&data[offset + 8..offset + 12]);
     let z = f32::from_le_bytes(buffer) as f64;
 
-    Ok(la::Vector::new(x, y, z))
+    linear::Vector::new(x, y, z)
 }
 
-pub fn load_stl(file: &path::Path) -> Result<Vec<primitive::Triangle>, io::Error> {
-    let mut f = fs::File::open(file)?;
-    let mut buffer = [0; 4];
+// Binary STL files are an 80 byte header, a facet count, and then 50 bytes per
+// facet. ASCII files also start with "solid", and some binary exporters write
+// that into the header too, so the size is checked before trusting either.
+fn is_binary(data: &[u8]) -> bool {
+    if data.len() < HEADER_SIZE + 4 {
+        return false;
+    }
+
+    let facets = read_u32(data, HEADER_SIZE) as usize;
 
-    let _ = f.read(&mut buffer[..])?;
-    let facets = u32::from_le_bytes(buffer);
+    data.len() == HEADER_SIZE + 4 + facets * FACET_SIZE
+}
+
+fn parse_binary(data: &[u8], material_id: usize) -> Vec<scene::primitive::Triangle> {
+    let facets = read_u32(data, HEADER_SIZE) as usize;
+
+    let mut triangles = Vec::with_capacity(facets);
 
+    for facet in 0..facets {
+        // Skip the facet normal, it is recomputed from the vertices
+        let offset = HEADER_SIZE + 4 + facet * FACET_SIZE + 12;
+
+        let a = read_vector(data, offset);
+        let b = read_vector(data, offset + 12);
+        let c = read_vector(data, offset + 24);
+
+        triangles.extend(super::triangle(a, b, c, material_id));
+    }
+
+    triangles
+}
+
+fn parse_ascii(
+    data: &[u8],
+    material_id: usize,
+) -> Result<Vec<scene::primitive::Triangle>, io::Error> {
+    let text = str::from_utf8(data).map_err(|_| invalid_data("ASCII STL must be valid UTF-8"))?;
+
+    let mut tokens = text.split_whitespace();
+    let mut vertices = Vec::new();
     let mut triangles = Vec::new();
 
-    for _ in 0..facets {
-        let _normal = read_vector(&mut f)?;
-        let v1 = read_vector(&mut f)?;
-        let v2 = read_vector(&mut f)?;
-        let v3 = read_vector(&mut f)?;
+    while let Some(token) = tokens.next() {
+        match token {
+            "vertex" => {
+                let mut coordinates = [0.0; 3];
+                for coordinate in coordinates.iter_mut() {
+                    *coordinate = tokens
+                        .next()
+                        .and_then(|value| value.parse::<f64>().ok())
+                        .ok_or_else(|| invalid_data("STL vertex must have three coordinates"))?;
+                }
 
-        f.seek(io::SeekFrom::Current(2))?;
+                vertices.push(linear::Vector::new(
+                    coordinates[0],
+                    coordinates[1],
+                    coordinates[2],
+                ));
+            }
+            "endfacet" => {
+                if vertices.len() != 3 {
+                    return Err(invalid_data("STL facet must have exactly three vertices"));
+                }
 
-        let triangle = primitive::Triangle::new(v1, v2, v3, 0, None, None);
-        triangles.push(triangle);
+                triangles.extend(super::triangle(vertices[0], vertices[1], vertices[2], material_id));
+                vertices.clear();
+            }
+            _ => {}
+        }
     }
 
     Ok(triangles)
 }
+
+fn parse(data: &[u8], material_id: usize) -> Result<Vec<scene::primitive::Triangle>, io::Error> {
+    if is_binary(data) {
+        Ok(parse_binary(data, material_id))
+    } else if data.starts_with(b"solid") {
+        parse_ascii(data, material_id)
+    } else {
+        Err(invalid_data("File is neither binary nor ASCII STL"))
+    }
+}
+
+pub fn load_stl(
+    file: &path::Path,
+    material: Box<dyn scene::material::Material>,
+) -> Result<super::ModelData, io::Error> {
+    let data = fs::read(file)?;
+    let triangles = parse(&data, 0)?;
+
+    Ok((vec![material], triangles))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene::primitive::Primitive;
+
+    const ASCII: &str = "solid test
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 0 1 0
+    endloop
+  endfacet
+  facet normal 0 0 -1
+    outer loop
+      vertex 0 0 0
+      vertex 0 1 0
+      vertex 1 0 0
+    endloop
+  endfacet
+endsolid test
+";
+
+    fn push_vector(data: &mut Vec<u8>, x: f32, y: f32, z: f32) {
+        data.extend_from_slice(&x.to_le_bytes());
+        data.extend_from_slice(&y.to_le_bytes());
+        data.extend_from_slice(&z.to_le_bytes());
+    }
+
+    #[test]
+    fn ascii() {
+        let triangles = parse(ASCII.as_bytes(), 0).unwrap();
+        assert_eq!(2, triangles.len());
+
+        let normal = triangles[0].surface_normal(0.0, 0.0);
+        assert!(normal.equals(&linear::Vector::new(0.0, 0.0, 1.0)));
+
+        assert!(parse(b"solid broken\nfacet outer loop vertex 0 0 0 endloop endfacet", 0).is_err());
+    }
+
+    #[test]
+    fn binary() {
+        // Header deliberately starts with "solid" like some exporters write
+        let mut data = b"solid binary".to_vec();
+        data.resize(HEADER_SIZE, 0);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        push_vector(&mut data, 0.0, 0.0, 1.0);
+        push_vector(&mut data, 0.0, 0.0, 0.0);
+        push_vector(&mut data, 1.0, 0.0, 0.0);
+        push_vector(&mut data, 0.0, 1.0, 0.0);
+        data.extend_from_slice(&[0, 0]);
+
+        let triangles = parse(&data, 3).unwrap();
+        assert_eq!(1, triangles.len());
+        assert_eq!(3, triangles[0].material_id());
+
+        let normal = triangles[0].surface_normal(0.0, 0.0);
+        assert!(normal.equals(&linear::Vector::new(0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn degenerate_facets() {
+        let ascii = "solid test
+  facet normal 0 0 0
+    outer loop
+      vertex 0 0 0
+      vertex 1 1 1
+      vertex 2 2 2
+    endloop
+  endfacet
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid test
+";
+        let triangles = parse(ascii.as_bytes(), 0).unwrap();
+        assert_eq!(1, triangles.len());
+
+        let mut data = vec![0; HEADER_SIZE];
+        data.extend_from_slice(&2u32.to_le_bytes());
+        push_vector(&mut data, 0.0, 0.0, 0.0);
+        push_vector(&mut data, 1.0, 0.0, 0.0);
+        push_vector(&mut data, 1.0, 0.0, 0.0);
+        push_vector(&mut data, 0.0, 1.0, 0.0);
+        data.extend_from_slice(&[0, 0]);
+        push_vector(&mut data, 0.0, 0.0, 1.0);
+        push_vector(&mut data, 0.0, 0.0, 0.0);
+        push_vector(&mut data, 1.0, 0.0, 0.0);
+        push_vector(&mut data, 0.0, 1.0, 0.0);
+        data.extend_from_slice(&[0, 0]);
+
+        let triangles = parse(&data, 0).unwrap();
+        assert_eq!(1, triangles.len());
+        let normal = triangles[0].surface_normal(0.0, 0.0);
+        assert!(normal.equals(&linear::Vector::new(0.0, 0.0, 1.0)));
+    }
+}
@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+
+use super::config;
+use super::linear;
+use super::scene;
+
+const CORE_NAMESPACE: &str = "http://schemas.microsoft.com/3dmanufacturing/core/2015/02";
+const RELATIONSHIP_NAMESPACE: &str = "http://schemas.openxmlformats.org/package/2006/relationships";
+const MODEL_RELATIONSHIP: &str = "http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel";
+const DEFAULT_MODEL_PATH: &str = "3D/3dmodel.model";
+
+// Components may reference other objects, this guards against reference cycles
+const MAX_COMPONENT_DEPTH: usize = 32;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// 3MF transforms are 4x3 affine matrices applied to row vectors, stored row by
+// row as "m00 m01 m02 m10 m11 m12 m20 m21 m22 m30 m31 m32".
+fn parse_transform(text: Option<&str>) -> Result<linear::Matrix4, io::Error> {
+    let text = match text {
+        None => return Ok(linear::Matrix4::identity()),
+        Some(text) => text,
+    };
+
+    let m = text
+        .split_whitespace()
+        .map(|value| value.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid_data(format!("Invalid 3MF transform: {}", text)))?;
+
+    if m.len() != 12 {
+        return Err(invalid_data(format!("Invalid 3MF transform: {}", text)));
+    }
+
+    Ok(linear::Matrix4::new([
+        [m[0], m[3], m[6], m[9]],
+        [m[1], m[4], m[7], m[10]],
+        [m[2], m[5], m[8], m[11]],
+        [0.0, 0.0, 0.0, 1.0],
+    ]))
+}
+
+// Reference to a base material, as a (basematerials id, index) pair
+type Property = (String, usize);
+
+struct MeshTriangle {
+    vertices: (usize, usize, usize),
+    property: Option<Property>,
+}
+
+enum ObjectData {
+    Mesh {
+        vertices: Vec<linear::Vector>,
+        triangles: Vec<MeshTriangle>,
+    },
+    Components(Vec<(String, linear::Matrix4)>),
+}
+
+struct Object {
+    data: ObjectData,
+    property: Option<Property>,
+}
+
+struct Model {
+    materials: Vec<Box<dyn scene::material::Material>>,
+    material_index: HashMap<Property, usize>,
+    objects: HashMap<String, Object>,
+    triangles: Vec<scene::primitive::Triangle>,
+}
+
+fn attribute<'a>(node: &roxmltree::Node<'a, '_>, name: &str) -> Result<&'a str, io::Error> {
+    node.attribute(name).ok_or_else(|| {
+        invalid_data(format!(
+            "3MF <{}> is missing the \"{}\" attribute",
+            node.tag_name().name(),
+            name
+        ))
+    })
+}
+
+fn parse_attribute<T: std::str::FromStr>(
+    node: &roxmltree::Node,
+    name: &str,
+) -> Result<T, io::Error> {
+    let value = attribute(node, name)?;
+
+    value.parse::<T>().map_err(|_| {
+        invalid_data(format!(
+            "3MF <{}> has an invalid \"{}\" attribute: {}",
+            node.tag_name().name(),
+            name,
+            value
+        ))
+    })
+}
+
+fn children<'a, 'input: 'a>(
+    node: &roxmltree::Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.has_tag_name((CORE_NAMESPACE, name)))
+}
+
+fn child<'a, 'input: 'a>(
+    node: &roxmltree::Node<'a, 'input>,
+    name: &'a str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+fn parse_property(
+    node: &roxmltree::Node,
+    pid: &str,
+    index: &str,
+) -> Result<Option<Property>, io::Error> {
+    match node.attribute(pid) {
+        None => Ok(None),
+        Some(id) => Ok(Some((id.to_string(), parse_attribute(node, index)?))),
+    }
+}
+
+// Parses "#RRGGBB" or "#RRGGBBAA" into a color and an alpha
+fn parse_color(text: &str) -> Result<(scene::lighting::Color, f64), io::Error> {
+    let error = || invalid_data(format!("Invalid 3MF color: {}", text));
+
+    let digits = text.strip_prefix('#').ok_or_else(error)?;
+    if (digits.len() != 6 && digits.len() != 8) || !digits.is_ascii() {
+        return Err(error());
+    }
+
+    let channel = |index: usize| -> Result<f64, io::Error> {
+        let value =
+            u8::from_str_radix(&digits[2 * index..2 * index + 2], 16).map_err(|_| error())?;
+        Ok(value as f64 / 255.0)
+    };
+
+    let alpha = match digits.len() {
+        8 => channel(3)?,
+        _ => 1.0,
+    };
+
+    Ok((
+        scene::lighting::Color::new(channel(0)?, channel(1)?, channel(2)?),
+        alpha,
+    ))
+}
+
+fn parse_base_materials(
+    node: &roxmltree::Node,
+    overrides: &HashMap<String, config::MaterialOverride>,
+    default_material: &config::MaterialConfig,
+    model: &mut Model,
+) -> Result<(), io::Error> {
+    let id = attribute(node, "id")?;
+
+    for (index, base) in children(node, "base").enumerate() {
+        let (diffuse, alpha) = parse_color(attribute(&base, "displaycolor")?)?;
+
+        let mut material = config::MaterialConfig {
+            diffuse,
+            transparency: 1.0 - alpha,
+            ..default_material.clone()
+        };
+
+        if let Some(material_override) = base.attribute("name").and_then(|name| overrides.get(name))
+        {
+            material_override.apply(&mut material);
+        }
+
+        model
+            .material_index
+            .insert((id.to_string(), index), model.materials.len());
+        model.materials.push(material.to_material());
+    }
+
+    Ok(())
+}
+
+fn parse_mesh(node: &roxmltree::Node, object_pid: Option<&str>) -> Result<ObjectData, io::Error> {
+    let mut vertices = Vec::new();
+    if let Some(list) = child(node, "vertices") {
+        for vertex in children(&list, "vertex") {
+            vertices.push(linear::Vector::new(
+                parse_attribute(&vertex, "x")?,
+                parse_attribute(&vertex, "y")?,
+                parse_attribute(&vertex, "z")?,
+            ));
+        }
+    }
+
+    let mut triangles = Vec::new();
+    if let Some(list) = child(node, "triangles") {
+        for triangle in children(&list, "triangle") {
+            let indices = (
+                parse_attribute(&triangle, "v1")?,
+                parse_attribute(&triangle, "v2")?,
+                parse_attribute(&triangle, "v3")?,
+            );
+
+            if indices.0 >= vertices.len()
+                || indices.1 >= vertices.len()
+                || indices.2 >= vertices.len()
+            {
+                return Err(invalid_data(
+                    "3MF triangle references a missing vertex".to_string(),
+                ));
+            }
+
+            if indices.0 == indices.1 || indices.1 == indices.2 || indices.2 == indices.0 {
+                return Err(invalid_data("3MF triangle repeats a vertex".to_string()));
+            }
+
+            // Triangles without a pid index into their object's property group
+            let property = match triangle.attribute("pid").or(object_pid) {
+                Some(id) if triangle.has_attribute("p1") => {
+                    Some((id.to_string(), parse_attribute(&triangle, "p1")?))
+                }
+                _ => parse_property(&triangle, "pid", "p1")?,
+            };
+
+            triangles.push(MeshTriangle {
+                vertices: indices,
+                property,
+            });
+        }
+    }
+
+    Ok(ObjectData::Mesh {
+        vertices,
+        triangles,
+    })
+}
+
+fn parse_components(node: &roxmltree::Node) -> Result<ObjectData, io::Error> {
+    let mut components = Vec::new();
+    for component in children(node, "component") {
+        components.push((
+            attribute(&component, "objectid")?.to_string(),
+            parse_transform(component.attribute("transform"))?,
+        ));
+    }
+
+    Ok(ObjectData::Components(components))
+}
+
+fn instantiate(
+    model: &mut Model,
+    id: &str,
+    transform: &linear::Matrix4,
+    property: Option<&Property>,
+    depth: usize,
+) -> Result<(), io::Error> {
+    if depth > MAX_COMPONENT_DEPTH {
+        return Err(invalid_data(
+            "3MF components are nested too deeply".to_string(),
+        ));
+    }
+
+    let object = model
+        .objects
+        .get(id)
+        .ok_or_else(|| invalid_data(format!("3MF build references missing object {}", id)))?;
+
+    let property = object.property.as_ref().or(property);
+
+    match &object.data {
+        ObjectData::Mesh {
+            vertices,
+            triangles,
+        } => {
+            let vertices = vertices
+                .iter()
+                .map(|vertex| transform.transform_point(vertex))
+                .collect::<Vec<_>>();
+
+            // Mirroring transforms flip the winding, and so the facing, of triangles
+            let mirrored = transform.determinant() < 0.0;
+
+            let mut mesh = Vec::with_capacity(triangles.len());
+            for triangle in triangles {
+                let material_id = triangle
+                    .property
+                    .as_ref()
+                    .or(property)
+                    .and_then(|property| model.material_index.get(property))
+                    .copied()
+                    .unwrap_or(0);
+
+                let a = vertices[triangle.vertices.0];
+                let (b, c) = match mirrored {
+                    false => (vertices[triangle.vertices.1], vertices[triangle.vertices.2]),
+                    true => (vertices[triangle.vertices.2], vertices[triangle.vertices.1]),
+                };
+
+                mesh.extend(super::triangle(a, b, c, material_id));
+            }
+
+            model.triangles.extend(mesh);
+        }
+        ObjectData::Components(components) => {
+            let property = property.cloned();
+            for (component_id, component_transform) in components.clone() {
+                instantiate(
+                    model,
+                    &component_id,
+                    &transform.multiply(&component_transform),
+                    property.as_ref(),
+                    depth + 1,
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_model(
+    text: &str,
+    overrides: &HashMap<String, config::MaterialOverride>,
+    default_material: &config::MaterialConfig,
+) -> Result<super::ModelData, io::Error> {
+    let document = roxmltree::Document::parse(text)
+        .map_err(|error| invalid_data(format!("Invalid 3MF model XML: {}", error)))?;
+
+    let root = document.root_element();
+    if !root.has_tag_name((CORE_NAMESPACE, "model")) {
+        return Err(invalid_data(
+            "3MF model part must contain a <model>".to_string(),
+        ));
+    }
+
+    let mut model = Model {
+        materials: vec![default_material.to_material()],
+        material_index: HashMap::new(),
+        objects: HashMap::new(),
+        triangles: Vec::new(),
+    };
+
+    if let Some(resources) = child(&root, "resources") {
+        for resource in resources.children() {
+            if resource.has_tag_name((CORE_NAMESPACE, "basematerials")) {
+                parse_base_materials(&resource, overrides, default_material, &mut model)?;
+            } else if resource.has_tag_name((CORE_NAMESPACE, "object")) {
+                let data = if let Some(mesh) = child(&resource, "mesh") {
+                    parse_mesh(&mesh, resource.attribute("pid"))?
+                } else if let Some(components) = child(&resource, "components") {
+                    parse_components(&components)?
+                } else {
+                    continue;
+                };
+
+                let object = Object {
+                    data,
+                    property: parse_property(&resource, "pid", "pindex")?,
+                };
+
+                model
+                    .objects
+                    .insert(attribute(&resource, "id")?.to_string(), object);
+            }
+        }
+    }
+
+    if let Some(build) = child(&root, "build") {
+        for item in children(&build, "item") {
+            let transform = parse_transform(item.attribute("transform"))?;
+            instantiate(
+                &mut model,
+                attribute(&item, "objectid")?,
+                &transform,
+                None,
+                0,
+            )?;
+        }
+    }
+
+    Ok((model.materials, model.triangles))
+}
+
+fn read_entry<R: io::Read + io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    name: &str,
+) -> Result<String, io::Error> {
+    let mut entry = archive
+        .by_name(name)
+        .map_err(|error| invalid_data(format!("Couldn't read {} from 3MF: {}", name, error)))?;
+
+    let mut text = String::new();
+    entry.read_to_string(&mut text)?;
+
+    Ok(text)
+}
+
+// Finds the root model part through the package relationships
+fn model_path<R: io::Read + io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+) -> Result<String, io::Error> {
+    let relationships = match read_entry(archive, "_rels/.rels") {
+        Ok(relationships) => relationships,
+        Err(_) => return Ok(DEFAULT_MODEL_PATH.to_string()),
+    };
+
+    let document = roxmltree::Document::parse(&relationships)
+        .map_err(|error| invalid_data(format!("Invalid 3MF relationships: {}", error)))?;
+
+    let target = document
+        .root_element()
+        .children()
+        .filter(|node| node.has_tag_name((RELATIONSHIP_NAMESPACE, "Relationship")))
+        .find(|node| node.attribute("Type") == Some(MODEL_RELATIONSHIP))
+        .and_then(|node| node.attribute("Target"));
+
+    match target {
+        Some(target) => Ok(target.trim_start_matches('/').to_string()),
+        None => Ok(DEFAULT_MODEL_PATH.to_string()),
+    }
+}
+
+pub fn load_3mf(
+    file: &path::Path,
+    overrides: &HashMap<String, config::MaterialOverride>,
+    default_material: &config::MaterialConfig,
+) -> Result<super::ModelData, io::Error> {
+    let f = fs::File::open(file)?;
+    let mut archive = zip::ZipArchive::new(f)
+        .map_err(|error| invalid_data(format!("Couldn't open 3MF archive: {}", error)))?;
+
+    let model_path = model_path(&mut archive)?;
+    let text = read_entry(&mut archive, &model_path)?;
+
+    parse_model(&text, overrides, default_material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene::primitive::Primitive;
+
+    const MODEL: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<model xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02" unit="millimeter">
+    <resources>
+        <basematerials id="1">
+            <base name="Red" displaycolor="#FF0000" />
+            <base name="Blue" displaycolor="#0000FF80" />
+        </basematerials>
+        <object id="2" type="model" pid="1" pindex="0">
+            <mesh>
+                <vertices>
+                    <vertex x="0" y="0" z="0" />
+                    <vertex x="1" y="0" z="0" />
+                    <vertex x="0" y="1" z="0" />
+                </vertices>
+                <triangles>
+                    <triangle v1="0" v2="1" v3="2" />
+                    <triangle v1="0" v2="2" v3="1" pid="1" p1="1" />
+                </triangles>
+            </mesh>
+        </object>
+        <object id="3" type="model">
+            <components>
+                <component objectid="2" transform="1 0 0 0 1 0 0 0 1 0 0 5" />
+            </components>
+        </object>
+    </resources>
+    <build>
+        <item objectid="2" />
+        <item objectid="3" transform="-1 0 0 0 1 0 0 0 1 10 0 0" />
+    </build>
+</model>
+"##;
+
+    #[test]
+    fn parse() {
+        let (materials, triangles) =
+            parse_model(MODEL, &HashMap::new(), &config::MaterialConfig::default()).unwrap();
+
+        assert_eq!(3, materials.len());
+        assert_eq!(4, triangles.len());
+
+        assert_eq!(1, triangles[0].material_id());
+        assert_eq!(2, triangles[1].material_id());
+
+        let normal = triangles[0].surface_normal(0.0, 0.0);
+        assert!(normal.equals(&linear::Vector::new(0.0, 0.0, 1.0)));
+
+        // The mirrored instance keeps its original facing
+        let normal = triangles[2].surface_normal(0.0, 0.0);
+        assert!(normal.equals(&linear::Vector::new(0.0, 0.0, 1.0)));
+
+        let bounds = triangles[2].bounds();
+        assert!(bounds.min.equals(&linear::Vector::new(9.0, 0.0, 5.0)));
+        assert!(bounds.max.equals(&linear::Vector::new(10.0, 1.0, 5.0)));
+    }
+
+    // Only the object names the property group, which its triangles index into
+    const OBJECT_PROPERTIES: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<model xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02" unit="millimeter">
+    <resources>
+        <basematerials id="1">
+            <base name="Red" displaycolor="#FF0000" />
+            <base name="Green" displaycolor="#00FF00" />
+            <base name="Blue" displaycolor="#0000FF" />
+        </basematerials>
+        <object id="2" type="model" pid="1" pindex="0">
+            <mesh>
+                <vertices>
+                    <vertex x="0" y="0" z="0" />
+                    <vertex x="1" y="0" z="0" />
+                    <vertex x="0" y="1" z="0" />
+                </vertices>
+                <triangles>
+                    <triangle v1="0" v2="1" v3="2" p1="2" />
+                    <triangle v1="0" v2="2" v3="1" />
+                    <triangle v1="1" v2="0" v3="2" p1="1" />
+                </triangles>
+            </mesh>
+        </object>
+    </resources>
+    <build>
+        <item objectid="2" />
+    </build>
+</model>
+"##;
+
+    #[test]
+    fn object_properties() {
+        let (materials, triangles) = parse_model(
+            OBJECT_PROPERTIES,
+            &HashMap::new(),
+            &config::MaterialConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(4, materials.len());
+        assert_eq!(3, triangles[0].material_id());
+        assert_eq!(1, triangles[1].material_id());
+        assert_eq!(2, triangles[2].material_id());
+    }
+
+    // A flat triangle, and a build item squashing the object flat
+    const DEGENERATE: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<model xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02" unit="millimeter">
+    <resources>
+        <object id="1" type="model">
+            <mesh>
+                <vertices>
+                    <vertex x="0" y="0" z="0" />
+                    <vertex x="1" y="0" z="0" />
+                    <vertex x="0" y="1" z="0" />
+                    <vertex x="2" y="0" z="0" />
+                </vertices>
+                <triangles>
+                    <triangle v1="0" v2="1" v3="2" />
+                    <triangle v1="0" v2="1" v3="3" />
+                </triangles>
+            </mesh>
+        </object>
+    </resources>
+    <build>
+        <item objectid="1" />
+        <item objectid="1" transform="1 0 0 0 0 0 0 0 1 0 0 0" />
+    </build>
+</model>
+"##;
+
+    #[test]
+    fn degenerate_triangles() {
+        let (_, triangles) = parse_model(
+            DEGENERATE,
+            &HashMap::new(),
+            &config::MaterialConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(1, triangles.len());
+
+        // Triangles must have three different corners
+        let repeated = DEGENERATE.replace(r#"v1="0" v2="1" v3="3""#, r#"v1="0" v2="0" v3="1""#);
+        let error = parse_model(
+            &repeated,
+            &HashMap::new(),
+            &config::MaterialConfig::default(),
+        );
+        assert_eq!(io::ErrorKind::InvalidData, error.err().unwrap().kind());
+    }
+
+    #[test]
+    fn load_package() {
+        let (materials, triangles) = load_3mf(
+            path::Path::new("3mf/model.3mf"),
+            &HashMap::new(),
+            &config::MaterialConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(1, materials.len());
+        assert_eq!(1882, triangles.len());
+    }
+}
@@ -1,8 +1,10 @@
-use obj;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::path;
 
+use super::config;
 use super::scene;
 use super::linear;
 
@@ -28,55 +30,308 @@ fn get_dir(file: &path::Path) -> path::PathBuf {
     path
 }
 
-fn convert_material(material: &obj::Material, base_path: &path::PathBuf) ->scene:: lighting::Material {
+// MTL statements for each material that the obj crate doesn't handle, keyed by
+// lowercase keyword. It only keeps the first word of texture maps, which drops
+// any options given before the file name, and doesn't know the PBR extensions.
+type MaterialStatements = HashMap<String, HashMap<String, String>>;
+
+fn is_extra_statement(keyword: &str) -> bool {
+    keyword.starts_with("map_") || ["bump", "norm", "pr", "pm"].contains(&keyword)
+}
+
+fn parse_material_statements(mtl: &str, statements: &mut MaterialStatements) {
+    let mut material = None;
+
+    for line in mtl.lines() {
+        let line = line.trim();
+        let (keyword, rest) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            material = Some(rest.to_string());
+        } else if is_extra_statement(&keyword.to_lowercase()) {
+            if let Some(material) = &material {
+                statements
+                    .entry(material.clone())
+                    .or_default()
+                    .insert(keyword.to_lowercase(), rest.to_string());
+            }
+        }
+    }
+}
+
+// Keywords understood by the obj crate's MTL parser, which panics on anything
+// else. Other statements are only read by parse_material_statements.
+const OBJ_MTL_KEYWORDS: [&str; 20] = [
+    "newmtl", "Ka", "Kd", "Ks", "Ke", "Ns", "Ni", "Km", "d", "Tr", "Tf", "illum", "map_Ka",
+    "map_Kd", "map_Ks", "map_d", "map_refl", "map_bump", "map_Bump", "bump",
+];
+
+fn strip_unknown_statements(mtl: &str) -> String {
+    mtl.lines()
+        .filter(|line| match line.split_whitespace().next() {
+            Some(keyword) => keyword.starts_with('#') || OBJ_MTL_KEYWORDS.contains(&keyword),
+            None => true,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Loads the object's material libraries, replacing Obj::load_mtls, and returns
+// the statements the obj crate doesn't handle
+fn load_mtls(object: &mut obj::Obj<obj::SimplePolygon>) -> Result<MaterialStatements, io::Error> {
+    let mut statements = HashMap::new();
+    let mut materials = HashMap::new();
+
+    for library in &object.material_libs {
+        let mtl = fs::read_to_string(object.path.join(library))
+            .map_err(|_| io::Error::other(format!("Couldn't load mtl {}", library)))?;
+
+        parse_material_statements(&mtl, &mut statements);
+
+        let mtl = obj::Mtl::load(&mut strip_unknown_statements(&mtl).as_bytes());
+        for material in mtl.materials {
+            materials.insert(material.name.clone(), material);
+        }
+    }
+
+    for group in object.objects.iter_mut().flat_map(|o| o.groups.iter_mut()) {
+        if let Some(material) = &mut group.material {
+            if let Some(loaded) = materials.get(&material.name) {
+                *material = Cow::Owned(loaded.clone());
+            }
+        }
+    }
+
+    Ok(statements)
+}
+
+fn is_option_argument(token: &str) -> bool {
+    token.parse::<f64>().is_ok() || token == "on" || token == "off"
+}
+
+struct MapOptions {
+    file: String,
+    wrap: scene::lighting::WrapMode,
+    bump_multiplier: f64,
+}
+
+// Splits a map statement into its file name and options. Besides the standard
+// "-clamp on|off" and "-bm", a "-wrap repeat|clamp|mirror" option is accepted,
+// and other options are skipped.
+fn parse_map(statement: &str) -> MapOptions {
+    let mut wrap = scene::lighting::WrapMode::Repeat;
+    let mut bump_multiplier = 1.0;
+    let mut tokens = statement.split_whitespace().peekable();
+
+    while let Some(token) = tokens.peek() {
+        if !token.starts_with('-') || is_option_argument(token) {
+            break;
+        }
+
+        let option = tokens.next().unwrap();
+        match (option, tokens.peek().copied()) {
+            ("-clamp", Some("on")) => wrap = scene::lighting::WrapMode::Clamp,
+            ("-clamp", Some("off")) => wrap = scene::lighting::WrapMode::Repeat,
+            ("-wrap", Some("repeat")) => wrap = scene::lighting::WrapMode::Repeat,
+            ("-wrap", Some("clamp")) => wrap = scene::lighting::WrapMode::Clamp,
+            ("-wrap", Some("mirror")) => wrap = scene::lighting::WrapMode::Mirror,
+            ("-bm", Some(value)) => bump_multiplier = value.parse().unwrap_or(1.0),
+            ("-imfchan", _) | ("-type", _) => {
+                tokens.next();
+            }
+            _ => {}
+        }
+
+        if option == "-clamp" || option == "-wrap" {
+            tokens.next();
+        }
+
+        while tokens.peek().is_some_and(|token| is_option_argument(token)) {
+            tokens.next();
+        }
+    }
+
+    // Exporters on Windows write backslash separators, sometimes escaped
+    let file = tokens
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("\\\\", "/")
+        .replace('\\', "/");
+
+    MapOptions {
+        file,
+        wrap,
+        bump_multiplier,
+    }
+}
+
+fn load_texture(options: &MapOptions, base_path: &path::Path) -> scene::lighting::Texture {
+    let full_path = base_path.join(&options.file);
+    let image = image::open(full_path)
+        .expect("Image texture must load correctly")
+        .to_rgba();
+
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+
+    scene::lighting::Texture::new(image.into_raw(), width, height, options.wrap)
+}
+
+fn load_texture_maps(
+    statements: Option<&HashMap<String, String>>,
+    base_path: &path::Path,
+) -> scene::lighting::TextureMaps {
+    let statements = match statements {
+        Some(statements) => statements,
+        None => return scene::lighting::TextureMaps::default(),
+    };
+
+    let load = |keyword: &str| {
+        statements
+            .get(keyword)
+            .map(|statement| load_texture(&parse_map(statement), base_path))
+    };
+
+    let bump = statements
+        .get("map_bump")
+        .or_else(|| statements.get("bump"))
+        .map(|statement| {
+            let options = parse_map(statement);
+
+            scene::lighting::BumpMap {
+                texture: load_texture(&options, base_path),
+                multiplier: options.bump_multiplier,
+            }
+        });
+
+    scene::lighting::TextureMaps {
+        diffuse: load("map_kd"),
+        specular: load("map_ks"),
+        ambient: load("map_ka"),
+        shininess: load("map_ns"),
+        opacity: load("map_d"),
+        bump,
+        normal: load("norm").or_else(|| load("map_norm")),
+        metallic: load("map_pm"),
+        roughness: load("map_pr"),
+    }
+}
+
+// Materials using any of the PBR extensions are shaded as metallic-roughness
+// materials, with the base color taken from Kd
+fn is_pbr(statements: &HashMap<String, String>) -> bool {
+    ["pr", "pm", "map_pr", "map_pm"]
+        .iter()
+        .any(|keyword| statements.contains_key(*keyword))
+}
+
+// Illumination models 3 to 7 turn on ray traced reflection, with Ks as the
+// reflection color
+fn reflectance(material: &obj::Material) -> f64 {
+    match material.illum {
+        Some(3..=7) => convert_color(material.ks).average(),
+        _ => 0.0,
+    }
+}
+
+fn convert_material(
+    material: &obj::Material,
+    statements: Option<&HashMap<String, String>>,
+    overrides: Option<&config::MaterialOverride>,
+    base_path: &path::Path,
+) -> Result<Box<dyn scene::material::Material>, io::Error> {
+    let overrides = overrides.cloned().unwrap_or_default();
+
     let specular = convert_color(material.ks);
     let diffuse = convert_color(material.kd);
     let ambient = convert_color(material.ka);
+    let emission = convert_color(material.ke);
 
     let alpha = match material.ns {
         None => 0.0,
         Some(alpha) => alpha as f64,
     };
 
-    let texture = match &material.map_kd {
-        None => scene::lighting::Texture::new(Vec::new(), 0, 0),
-        Some(path) => {
-            let mut full_path = path::PathBuf::from(base_path);
-            full_path.push(path);
-            let file = image::open(full_path).expect("Image texture must load correctly");
-            let image = file.to_rgb();
-            let data = image.to_vec();
-            let width = image.width() as usize;
-            let height = image.height() as usize;
-
-            scene::lighting::Texture::new(data, width, height)
-        }
-    };
-
+    // MTL stores opacity ("dissolve") rather than transparency
     let transparency = match material.d {
-        None => 1.0,
-        Some(d) => d as f64,
+        None => 0.0,
+        Some(d) => 1.0 - d as f64,
     };
+    let transparency = overrides.transparency.unwrap_or(transparency);
 
     let index_of_refraction = match material.ni {
         None => 1.45,
         Some(ni) => ni as f64,
     };
+    let index_of_refraction = overrides.index_of_refraction.unwrap_or(index_of_refraction);
 
-    scene::lighting::Material::new(
+    let reflectance = overrides.reflectance.unwrap_or_else(|| reflectance(material));
+    let culling = overrides
+        .culling
+        .unwrap_or_else(|| scene::primitive::Culling::default_for(transparency));
+
+    let textures = load_texture_maps(statements, base_path);
+
+    if let Some(statements) = statements.filter(|statements| is_pbr(statements)) {
+        // PBR materials reflect according to their metallic and roughness, and
+        // are never transparent
+        if overrides.reflectance.is_some() || overrides.transparency.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Reflectance and transparency of PBR material {} can't be overridden",
+                    material.name
+                ),
+            ));
+        }
+
+        let value = |keyword: &str, default: f64| {
+            statements
+                .get(keyword)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        // Texture maps multiply the scalar value, so a map on its own uses it as is
+        let roughness = match statements.contains_key("map_pr") {
+            true => value("pr", 1.0),
+            false => value("pr", 0.5),
+        };
+        let metallic = match statements.contains_key("map_pm") {
+            true => value("pm", 1.0),
+            false => value("pm", 0.0),
+        };
+
+        return Ok(Box::new(scene::material::PbrMaterial::new(
+            diffuse,
+            metallic,
+            roughness,
+            index_of_refraction,
+            emission,
+            textures,
+            culling,
+        )));
+    }
+
+    Ok(Box::new(scene::material::PhongMaterial::new(
         specular,
         diffuse,
         ambient,
+        emission,
         alpha,
-        0.1,
+        reflectance,
         transparency,
         index_of_refraction,
-        texture,
-    )
+        textures,
+        culling,
+    )))
 }
 
 fn tessellate(
-    polygon: &Vec<obj::IndexTuple>,
+    polygon: &[obj::IndexTuple],
     object: &obj::Obj<Vec<obj::IndexTuple>>,
     material_index: usize,
     mesh: &mut Vec<scene::primitive::Triangle>,
@@ -84,15 +339,8 @@ fn tessellate(
     let anchor = polygon[0];
     let a = to_vector(&object.position[anchor.0]);
 
-    let has_texture = match anchor.1 {
-        None => false,
-        Some(_) => true,
-    };
-
-    let has_normals = match anchor.2 {
-        None => false,
-        Some(_) => true,
-    };
+    let has_texture = anchor.1.is_some();
+    let has_normals = anchor.2.is_some();
 
     for others in polygon[1..].windows(2) {
         let b = to_vector(&object.position[others[0].0]);
@@ -125,38 +373,33 @@ fn tessellate(
 
 pub fn load_obj(
     file: &path::Path,
-) -> Result<(Vec<scene::lighting::Material>, Vec<scene::primitive::Triangle>), io::Error> {
+    overrides: &HashMap<String, config::MaterialOverride>,
+) -> Result<super::ModelData, io::Error> {
     let mut object: obj::Obj<obj::SimplePolygon> = obj::Obj::load(file)?;
-    let mtls = object.load_mtls();
-
-    if let Err(errors) = mtls {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Couldn't load mtl {}", errors[0].0),
-        ));
-    }
+    let material_statements = load_mtls(&mut object)?;
 
     let mut mesh = Vec::new();
 
     let mut materials_index = HashMap::new();
-    let mut materials = Vec::new();
+    let mut materials: Vec<Box<dyn scene::material::Material>> = Vec::new();
     let mut current_material = 1;
 
-    materials.push(scene::lighting::Material::new(
+    materials.push(Box::new(scene::material::PhongMaterial::new(
         scene::lighting::Color::black(),
         scene::lighting::Color::black(),
         scene::lighting::Color::black(),
+        scene::lighting::Color::black(),
+        0.0,
         0.0,
         0.0,
-        1.0,
         1.45,
-        scene::lighting::Texture::new(Vec::new(), 0, 0),
-    ));
+        scene::lighting::TextureMaps::default(),
+        scene::primitive::Culling::Back,
+    )));
 
     materials_index.insert("none", 0);
 
     let resource_dir = get_dir(file);
-
     for o in &object.objects {
         for g in &o.groups {
             for polygon in &g.polys {
@@ -164,19 +407,114 @@ pub fn load_obj(
                 if let Some(material_ref) = &g.material {
                     material_name = &material_ref.name;
                     if !(materials_index.contains_key(material_name)) {
-                        materials.push(convert_material(material_ref, &resource_dir));
+                        materials.push(convert_material(
+                            material_ref,
+                            material_statements.get(material_name),
+                            overrides.get(material_name),
+                            &resource_dir,
+                        )?);
                         materials_index.insert(material_name, current_material);
-                        current_material = current_material + 1;
+                        current_material += 1;
                     }
                 }
 
                 let index = materials_index.get(material_name).unwrap_or(&0);
                 let index = (*index) as usize;
 
-                tessellate(&polygon, &object, index, &mut mesh);
+                tessellate(polygon, &object, index, &mut mesh);
             }
         }
     }
 
     Ok((materials, mesh))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_options() {
+        let options = parse_map("textures\\\\plane.png");
+        assert_eq!("textures/plane.png", options.file);
+        assert_eq!(scene::lighting::WrapMode::Repeat, options.wrap);
+
+        let options = parse_map("-s 2 2 1 -clamp on -imfchan l my texture.png");
+        assert_eq!("my texture.png", options.file);
+        assert_eq!(scene::lighting::WrapMode::Clamp, options.wrap);
+
+        let options = parse_map("-wrap mirror -bm 0.5 tile.png");
+        assert_eq!(scene::lighting::WrapMode::Mirror, options.wrap);
+        assert_eq!(0.5, options.bump_multiplier);
+    }
+
+    #[test]
+    fn reflectance_from_illum() {
+        let mut material = obj::Material::new("metal".to_string());
+        material.ks = Some([0.2, 0.4, 0.6]);
+
+        material.illum = Some(2);
+        assert_eq!(0.0, reflectance(&material));
+
+        material.illum = Some(3);
+        assert!((reflectance(&material) - 0.4).abs() < 1e-6);
+
+        material.illum = None;
+        assert_eq!(0.0, reflectance(&material));
+    }
+
+    #[test]
+    fn material_statements() {
+        let mut statements = HashMap::new();
+        parse_material_statements(
+            "newmtl first\nmap_Kd -clamp on a.png\nnewmtl second\nKd 1 1 1\nbump b.png\nPr 0.3\nPm 1\n",
+            &mut statements,
+        );
+
+        assert_eq!("-clamp on a.png", statements["first"]["map_kd"]);
+        assert_eq!("b.png", statements["second"]["bump"]);
+        assert_eq!("0.3", statements["second"]["pr"]);
+        assert!(!statements["second"].contains_key("kd"));
+
+        assert!(!is_pbr(&statements["first"]));
+        assert!(is_pbr(&statements["second"]));
+
+        let stripped = strip_unknown_statements("newmtl a\n# note\nPr 0.3\nnorm n.png\nKd 1 1 1");
+        assert_eq!("newmtl a\n# note\nKd 1 1 1", stripped);
+    }
+
+    #[test]
+    fn pbr_overrides() {
+        let material = obj::Material::new("metal".to_string());
+        let mut statements = HashMap::new();
+        parse_material_statements("newmtl metal\nPm 1\n", &mut statements);
+        let base_path = path::Path::new(".");
+
+        let culling = config::MaterialOverride {
+            culling: Some(scene::primitive::Culling::TwoSided),
+            ..config::MaterialOverride::default()
+        };
+        let converted = convert_material(
+            &material,
+            statements.get("metal"),
+            Some(&culling),
+            base_path,
+        );
+        assert!(converted.is_ok());
+
+        // Phong materials take the overrides PBR materials can't
+        let transparent = config::MaterialOverride {
+            transparency: Some(0.5),
+            ..config::MaterialOverride::default()
+        };
+        let converted = convert_material(
+            &material,
+            statements.get("metal"),
+            Some(&transparent),
+            base_path,
+        );
+        assert!(converted.is_err());
+        let converted = convert_material(&material, None, Some(&transparent), base_path);
+        assert!(converted.is_ok());
+    }
+}
@@ -2,35 +2,176 @@ mod linear;
 mod config;
 mod load;
 mod render;
+mod save;
 mod scene;
 
-use std::path;
+fn create_aperture(camera: &config::CameraConfig) -> Option<render::lens::Aperture> {
+    let aperture = camera.aperture.as_ref()?;
+
+    let shape = match aperture.shape {
+        config::ApertureShape::Disk => render::lens::ApertureShape::Disk,
+        config::ApertureShape::Polygon { blades, rotation } => {
+            render::lens::ApertureShape::Polygon { blades, rotation }
+        }
+    };
+
+    // An f-stop is kept as it is, so the opening follows the focal length as
+    // the camera zooms
+    let size = match aperture.f_stop {
+        Some(f_stop) => render::lens::ApertureSize::FStop(f_stop),
+        None => render::lens::ApertureSize::Radius(aperture.radius.unwrap_or(0.0)),
+    };
+
+    Some(render::lens::Aperture::new(
+        size,
+        aperture.focus_distance,
+        shape,
+    ))
+}
+
+fn create_camera_keyframes(camera: &config::CameraConfig) -> Vec<render::lens::CameraKeyframe> {
+    if camera.keyframes.is_empty() {
+        return vec![render::lens::CameraKeyframe {
+            time: 0.0,
+            position: camera.position,
+            target: camera.target,
+            roll: camera.roll,
+            focal_length: camera.focal_length,
+        }];
+    }
+
+    camera
+        .keyframes
+        .iter()
+        .map(|keyframe| render::lens::CameraKeyframe {
+            time: keyframe.time,
+            position: keyframe.position.unwrap_or(camera.position),
+            target: keyframe.target.unwrap_or(camera.target),
+            roll: keyframe.roll.unwrap_or(camera.roll),
+            focal_length: keyframe.focal_length.unwrap_or(camera.focal_length),
+        })
+        .collect()
+}
+
+fn create_lens(camera: &config::CameraConfig, output: &config::OutputConfig) -> Box<dyn render::lens::Lens> {
+    let keyframes = create_camera_keyframes(camera);
+    let interpolation = match camera.interpolation {
+        config::Interpolation::Linear => render::lens::Interpolation::Linear,
+        config::Interpolation::CatmullRom => render::lens::Interpolation::CatmullRom,
+    };
+
+    match camera.projection {
+        config::Projection::Perspective => {
+            assert!(
+                keyframes.iter().all(|keyframe| keyframe.focal_length > 0.0),
+                "Perspective projection requires a positive focal length"
+            );
+
+            Box::new(render::lens::PerspectiveLens::new(
+                camera.view_width,
+                output.image_width,
+                output.image_height,
+                render::lens::CameraPath::new(keyframes, interpolation),
+                create_aperture(camera),
+            ))
+        }
+        config::Projection::Orthographic => Box::new(render::lens::OrthographicLens::new(
+            camera.view_width,
+            output.image_width as f64,
+            output.image_height as f64,
+            render::lens::CameraPath::new(keyframes, interpolation),
+        )),
+    }
+}
+
+fn create_filter(filter: &config::FilterConfig) -> Box<dyn render::filter::Filter> {
+    match *filter {
+        config::FilterConfig::Box { radius } => Box::new(render::filter::BoxFilter::new(radius)),
+        config::FilterConfig::Tent { radius } => Box::new(render::filter::TentFilter::new(radius)),
+        config::FilterConfig::Gaussian { radius, alpha } => {
+            Box::new(render::filter::GaussianFilter::new(radius, alpha))
+        }
+        config::FilterConfig::Mitchell { radius, b, c } => {
+            Box::new(render::filter::MitchellFilter::new(radius, b, c))
+        }
+    }
+}
+
+fn create_integrator(
+    integrator: &config::IntegratorConfig,
+    maximum_reflections: u32,
+) -> Box<dyn render::integrator::Integrator> {
+    match *integrator {
+        config::IntegratorConfig::Whitted => {
+            Box::new(render::integrator::WhittedIntegrator::new(maximum_reflections))
+        }
+        config::IntegratorConfig::PathTracer { roulette_depth } => Box::new(
+            render::integrator::PathTracer::new(maximum_reflections, roulette_depth),
+        ),
+    }
+}
+
+fn create_tone_map(tone_map: &config::ToneMapConfig) -> Box<dyn render::tonemap::ToneMapOperator> {
+    match *tone_map {
+        config::ToneMapConfig::Clamp => Box::new(render::tonemap::ClampOperator),
+        config::ToneMapConfig::Reinhard { white } => Box::new(render::tonemap::ReinhardOperator::new(white)),
+        config::ToneMapConfig::Aces => Box::new(render::tonemap::AcesOperator),
+        config::ToneMapConfig::Hable { white } => Box::new(render::tonemap::HableOperator::new(white)),
+    }
+}
 
 fn main() {
     let render_config = config::configure().expect("Configuration must succeed");
 
-    let scene_data = load::scene(&render_config.lighting_file, &render_config.model_file).expect("Scene data must load");
+    let scene_data = load::scene(&render_config).expect("Scene data must load");
 
-    let camera_scope = render::lens::Scope::new(render_config.camera.target, render_config.camera.position, render_config.camera.roll);
+    let shutter = &render_config.camera.shutter;
+    let lens = create_lens(&render_config.camera, &render_config.output);
+    let filter = create_filter(&render_config.sampling.filter);
+    let integrator = create_integrator(&render_config.integrator, render_config.maximum_reflections);
 
-    let lens = render::lens::PerspectiveLens::new(
-        render_config.camera.view_width,
-        render_config.output.image_width,
-        render_config.output.image_height,
-        camera_scope,
-        render_config.camera.focal_length,
+    let transform_config = &render_config.output.transform;
+    let tone_map = create_tone_map(&transform_config.tone_mapping);
+    let transform = render::tonemap::OutputTransform::new(
+        transform_config.exposure,
+        tone_map.as_ref(),
+        transform_config.transfer_function == config::TransferFunction::Srgb,
+        transform_config.dither,
     );
 
-    let task = render::RenderTask {
-        scene: &scene_data,
-        lens:  &lens,
-        image_width: render_config.output.image_width,
-        image_height: render_config.output.image_height,
-        max_reflections: render_config.maximum_reflections,
+    // A still image is a single frame starting at time zero
+    let frames = match &render_config.animation {
+        None => vec![(0.0, render_config.output.image_file.clone())],
+        // The config was checked to have a frame number placeholder
+        Some(animation) => (animation.first_frame..=animation.last_frame)
+            .filter_map(|frame| {
+                let image_file = render_config.output.frame_file(frame)?;
+
+                Some((frame as f64 / animation.frame_rate, image_file))
+            })
+            .collect(),
     };
 
-    let image = task.execute();
-    image
-        .save_with_format(path::Path::new(&render_config.output.image_file), image::ImageFormat::Png)
+    // The scene is loaded once and shared by every frame
+    for (start, image_file) in frames {
+        let task = render::RenderTask {
+            scene: &scene_data,
+            lens: lens.as_ref(),
+            filter: filter.as_ref(),
+            integrator: integrator.as_ref(),
+            image_width: render_config.output.image_width,
+            image_height: render_config.output.image_height,
+            samples_per_pixel: render_config.sampling.samples_per_pixel,
+            shutter: (start + shutter.open, start + shutter.close),
+        };
+
+        let framebuffer = task.execute();
+        save::image(
+            &framebuffer,
+            &image_file,
+            render_config.output.format(),
+            &transform,
+        )
         .expect("Saving output file should succeed!");
+    }
 }
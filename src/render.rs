@@ -1,23 +1,38 @@
-use image;
-
 use super::linear;
 use super::scene;
 
 pub mod lens;
 pub mod camera;
+pub mod filter;
+pub mod integrator;
+pub mod tonemap;
+
+// Linear radiance for every pixel, before any conversion for display
+pub type Framebuffer = image::ImageBuffer<image::Rgb<f32>, Vec<f32>>;
 
 pub struct RenderTask<'a> {
     pub scene: &'a scene::Scene,
     pub lens: &'a dyn lens::Lens,
+    pub filter: &'a dyn filter::Filter,
+    pub integrator: &'a dyn integrator::Integrator,
     pub image_width: u32,
     pub image_height: u32,
-    pub max_reflections: u32,
+    pub samples_per_pixel: u32,
+    pub shutter: (f64, f64),
 }
 
 impl RenderTask<'_> {
-    pub fn execute(&self) -> image::RgbImage {
-        let camera = camera::Camera::new(self.image_width, self.image_height, self.lens);
+    pub fn execute(&self) -> Framebuffer {
+        let camera = camera::Camera::new(
+            self.image_width,
+            self.image_height,
+            self.lens,
+            self.samples_per_pixel,
+            self.filter,
+            self.integrator,
+            self.shutter,
+        );
 
-        camera.render(&self.scene, self.max_reflections)
+        camera.render(self.scene)
     }
 }
@@ -1,7 +1,11 @@
-use super::linear;
 use super::scene;
 use std::sync::{Arc, Mutex};
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
+use super::filter;
+use super::integrator;
 use super::lens;
 
 const DEPTH: usize = 3;
@@ -10,6 +14,12 @@ pub struct Camera<'a> {
     image_width: u32,
     image_height: u32,
     lens: &'a dyn lens::Lens,
+    samples_per_pixel: u32,
+    filter: &'a dyn filter::Filter,
+    integrator: &'a dyn integrator::Integrator,
+
+    // Times the shutter opens and closes
+    shutter: (f64, f64),
 }
 
 impl<'a> Camera<'a> {
@@ -17,19 +27,109 @@ impl<'a> Camera<'a> {
         image_width: u32,
         image_height: u32,
         lens: &'a dyn lens::Lens,
-    ) -> Camera {
+        samples_per_pixel: u32,
+        filter: &'a dyn filter::Filter,
+        integrator: &'a dyn integrator::Integrator,
+        shutter: (f64, f64),
+    ) -> Camera<'a> {
         Camera {
             image_width,
             image_height,
             lens,
+            samples_per_pixel,
+            filter,
+            integrator,
+            shutter,
+        }
+    }
+
+    // Jittered sub-pixel offsets covering the filter's support. A square number of
+    // samples is stratified on a grid, otherwise each axis is stratified separately
+    // and the strata are paired at random.
+    fn sample_offsets(&self, rng: &mut SmallRng) -> Vec<(f64, f64)> {
+        let count = self.samples_per_pixel;
+        if count == 1 {
+            return vec![(0.0, 0.0)];
+        }
+
+        let radius = self.filter.radius();
+        let to_offset = |stratum: f64, strata: f64| (2.0 * stratum / strata - 1.0) * radius;
+
+        let strata = (count as f64).sqrt() as u32;
+        if strata * strata == count {
+            let mut offsets = Vec::with_capacity(count as usize);
+            for y in 0..strata {
+                for x in 0..strata {
+                    offsets.push((
+                        to_offset(x as f64 + rng.gen::<f64>(), strata as f64),
+                        to_offset(y as f64 + rng.gen::<f64>(), strata as f64),
+                    ));
+                }
+            }
+
+            return offsets;
+        }
+
+        let mut rows = (0..count).collect::<Vec<_>>();
+        rows.shuffle(rng);
+
+        (0..count)
+            .zip(rows)
+            .map(|(x, y)| {
+                (
+                    to_offset(x as f64 + rng.gen::<f64>(), count as f64),
+                    to_offset(y as f64 + rng.gen::<f64>(), count as f64),
+                )
+            })
+            .collect()
+    }
+
+    fn render_pixel(
+        &self,
+        scene: &scene::Scene,
+        pixel_x: u32,
+        pixel_y: u32,
+    ) -> scene::lighting::Color {
+        let seed = (pixel_y as u64) * (self.image_width as u64) + (pixel_x as u64);
+        let mut rng = SmallRng::seed_from_u64(seed);
+
+        let mut color = scene::lighting::Color::black();
+        let mut total_weight = 0.0;
+
+        for (offset_x, offset_y) in self.sample_offsets(&mut rng) {
+            let weight = self.filter.evaluate(offset_x, offset_y);
+            if weight == 0.0 {
+                continue;
+            }
+
+            let sample_x = (pixel_x as f64) + 0.5 + offset_x;
+            let sample_y = (pixel_y as f64) + 0.5 + offset_y;
+
+            let screen_x = 2.0 * sample_x / (self.image_width as f64) - 1.0;
+            let screen_y = -2.0 * sample_y / (self.image_height as f64) + 1.0;
+
+            // Each ray is cast at a random moment while the shutter is open
+            let time = match self.shutter {
+                (open, close) if close > open => open + (close - open) * rng.gen::<f64>(),
+                (open, _) => open,
+            };
+
+            let (ray, cone) = self.lens.generate_light_ray(screen_x, screen_y, time, &mut rng);
+
+            color.add(self.integrator.trace(scene, ray, cone, &mut rng).scale(weight));
+            total_weight += weight;
+        }
+
+        if total_weight <= 0.0 {
+            return scene::lighting::Color::black();
         }
+
+        color.scale(1.0 / total_weight)
     }
 
-    pub fn render(&self, scene: &scene::Scene, max_reflections: u32) -> image::RgbImage {
+    pub fn render(&self, scene: &scene::Scene) -> super::Framebuffer {
         let size = DEPTH * (self.image_width as usize) * (self.image_height as usize);
-        let mut output = std::iter::repeat(0 as u8)
-            .take(size)
-            .collect::<Vec<_>>();
+        let mut output = vec![0_f32; size];
 
         let mut rows = Vec::new();
 
@@ -46,18 +146,11 @@ impl<'a> Camera<'a> {
         let progress = Arc::new(Mutex::new(0));
 
         rows.par_iter_mut().for_each(|(pixel_y, row)| {
-            let screen_y = -2.0 * (*pixel_y as f64) / (self.image_height as f64) + 1.0;
-
             for pixel_x in 0..self.image_width {
-                let screen_x = 2.0 * (pixel_x as f64) / (self.image_width as f64) - 1.0;
-                let ray = self.lens.generate_light_ray(screen_x, screen_y);
-
-                let color = self.trace_ray(scene, ray, 1.0, max_reflections).to_pixel();
+                let color = self.render_pixel(scene, pixel_x, *pixel_y).to_rgb();
 
                 let index = DEPTH * (pixel_x as usize);
-                row[index] = color.0;
-                row[index + 1] = color.1;
-                row[index + 2] = color.2;
+                row[index..index + DEPTH].copy_from_slice(&color.0);
             }
 
             let mut progress = progress.lock().unwrap();
@@ -72,81 +165,69 @@ impl<'a> Camera<'a> {
         image::ImageBuffer::from_raw(self.image_width, self.image_height, output)
             .expect("Should create image successfully")
     }
+}
 
-    fn trace_ray(
-        &self,
-        scene: &scene::Scene,
-        ray: linear::Ray,
-        light_strength: f64,
-        remaining_reflections: u32,
-    ) -> scene::lighting::Color {
-        let (intersection, t, b, c) = scene.find_intersection(&ray);
-        let object = match intersection {
-            Some(object) => object,
-            None => return scene::lighting::Color::black(),
-        };
-
-        let distance = ray.direction.scale(t);
-        let intersection_point = ray.position.add(&distance);
-        let ray = linear::Ray {
-            position: intersection_point,
-            direction: ray.direction,
-        };
-        let normal = object.surface_normal(b, c);
-        let material = &scene.materials[object.material_id()];
-
-        let visible_lights = self.find_visible_lights(scene, intersection_point);
-
-        let (mut surface_color, light_strength, rays) = match object.has_texture() {
-            false => scene::lighting::calculate(
-                &visible_lights,
-                scene.ambient_light,
-                &ray,
-                normal,
-                light_strength,
-                material,
-            ),
-            true => scene::lighting::calculate_with_tex(
-                &visible_lights,
-                scene.ambient_light,
-                &ray,
-                object.uv(b, c),
-                normal,
-                light_strength,
-                material,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linear;
+
+    #[test]
+    fn sample_offsets() {
+        let lens = lens::OrthographicLens::new(
+            2.0,
+            10.0,
+            10.0,
+            lens::CameraPath::new(
+                vec![lens::CameraKeyframe {
+                    time: 0.0,
+                    position: linear::Vector::new(0.0, 0.0, 0.0),
+                    target: linear::Vector::new(0.0, 0.0, 1.0),
+                    roll: 0.0,
+                    focal_length: 1.0,
+                }],
+                lens::Interpolation::Linear,
             ),
-        };
-
-        if remaining_reflections > 0 {
-            for ray in rays {
-                let reflected_color =
-                    self.trace_ray(scene, ray, light_strength, remaining_reflections - 1);
-
-                surface_color.add(reflected_color);
+        );
+        let integrator = integrator::WhittedIntegrator::new(0);
+        let mut rng = SmallRng::seed_from_u64(3);
+
+        // Offsets cover the filter's support, which for a half pixel radius is
+        // the pixel itself. Square counts put a sample in every cell of a grid,
+        // and other counts one in every row and column.
+        for &radius in [0.5, 1.5].iter() {
+            let filter = filter::BoxFilter::new(radius);
+
+            for &count in [1, 2, 5, 9, 16].iter() {
+                let camera = Camera::new(10, 10, &lens, count, &filter, &integrator, (0.0, 0.0));
+                let offsets = camera.sample_offsets(&mut rng);
+                assert_eq!(count as usize, offsets.len());
+
+                let strata = match count {
+                    9 | 16 => (count as f64).sqrt() as usize,
+                    _ => count as usize,
+                };
+                let stratum =
+                    |offset: f64| ((offset / radius + 1.0) * 0.5 * strata as f64) as usize;
+
+                let mut cells = vec![0_usize; strata * strata];
+                for &(x, y) in &offsets {
+                    assert!(x.abs() <= radius && y.abs() <= radius);
+                    cells[stratum(y) * strata + stratum(x)] += 1;
+                }
+
+                if strata * strata == count as usize {
+                    assert!(cells.iter().all(|&cell| cell == 1));
+                }
+
+                let per_row = count as usize / strata;
+                for index in 0..strata {
+                    let row = (0..strata).map(|x| cells[index * strata + x]).sum::<usize>();
+                    let column = (0..strata).map(|y| cells[y * strata + index]).sum::<usize>();
+                    assert_eq!(per_row, row);
+                    assert_eq!(per_row, column);
+                }
             }
         }
-
-        surface_color
-    }
-
-    fn find_visible_lights(
-        &self,
-        scene: &scene::Scene,
-        position: linear::Vector,
-    ) -> Vec<scene::lighting::LightSource> {
-        let mut visible_lights: Vec<scene::lighting::LightSource> = Vec::new();
-        for light in &scene.lights {
-            let light_ray = linear::Ray {
-                position,
-                direction: light.position.subtract(&position),
-            };
-
-            let (_, distance, _, _) = scene.find_intersection(&light_ray);
-            if distance >= 1.0 {
-                visible_lights.push(*light);
-            }
-        }
-
-        visible_lights
     }
 }
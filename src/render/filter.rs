@@ -0,0 +1,188 @@
+// Pixel reconstruction filters. Offsets are measured in pixels from the pixel
+// center, and each filter is zero outside of [-radius, radius] on both axes.
+pub trait Filter: Sync + Send {
+    fn radius(&self) -> f64;
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+pub struct BoxFilter {
+    radius: f64,
+}
+
+impl BoxFilter {
+    pub fn new(radius: f64) -> BoxFilter {
+        BoxFilter { radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        match x.abs() <= self.radius && y.abs() <= self.radius {
+            true => 1.0,
+            false => 0.0,
+        }
+    }
+}
+
+pub struct TentFilter {
+    radius: f64,
+}
+
+impl TentFilter {
+    pub fn new(radius: f64) -> TentFilter {
+        TentFilter { radius }
+    }
+
+    fn tent(&self, x: f64) -> f64 {
+        (1.0 - x.abs() / self.radius).max(0.0)
+    }
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.tent(x) * self.tent(y)
+    }
+}
+
+pub struct GaussianFilter {
+    radius: f64,
+    alpha: f64,
+    edge: f64,
+}
+
+impl GaussianFilter {
+    pub fn new(radius: f64, alpha: f64) -> GaussianFilter {
+        GaussianFilter {
+            radius,
+            alpha,
+            edge: (-alpha * radius * radius).exp(),
+        }
+    }
+
+    // Shifted down so the filter falls to zero at its radius
+    fn gaussian(&self, x: f64) -> f64 {
+        ((-self.alpha * x * x).exp() - self.edge).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+// Mitchell-Netravali cubic, with the B and C parameters controlling the
+// tradeoff between blurring and ringing.
+pub struct MitchellFilter {
+    radius: f64,
+    b: f64,
+    c: f64,
+}
+
+impl MitchellFilter {
+    pub fn new(radius: f64, b: f64, c: f64) -> MitchellFilter {
+        MitchellFilter { radius, b, c }
+    }
+
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = (2.0 * x / self.radius).abs();
+        let (b, c) = (self.b, self.c);
+
+        let value = if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            (-b - 6.0 * c) * x.powi(3)
+                + (6.0 * b + 30.0 * c) * x.powi(2)
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c)
+        } else {
+            (12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+                + (6.0 - 2.0 * b)
+        };
+
+        value / 6.0
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.mitchell(x) * self.mitchell(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Midpoint rule over the filter's support
+    fn integrate(filter: &dyn Filter) -> f64 {
+        let steps = 400;
+        let radius = filter.radius();
+        let step = 2.0 * radius / (steps as f64);
+        let at = |index: usize| -radius + (index as f64 + 0.5) * step;
+
+        let mut total = 0.0;
+        for y in 0..steps {
+            for x in 0..steps {
+                total += filter.evaluate(at(x), at(y)) * step * step;
+            }
+        }
+
+        total
+    }
+
+    #[test]
+    fn filter_support() {
+        // Gaussian totals are (sqrt(pi / alpha) erf(radius sqrt(alpha)) -
+        // 2 radius exp(-alpha radius^2))^2, and the Mitchell cubic integrates
+        // to one over four units, which the radius spans twice over
+        let filters: Vec<(Box<dyn Filter>, f64)> = vec![
+            (Box::new(BoxFilter::new(0.5)), 1.0),
+            (Box::new(BoxFilter::new(1.5)), 9.0),
+            (Box::new(TentFilter::new(2.0)), 4.0),
+            (
+                Box::new(GaussianFilter::new(2.0, 2.0)),
+                1.2518928987736184f64.powi(2),
+            ),
+            (
+                Box::new(GaussianFilter::new(1.5, 2.0)),
+                1.2166034551268203f64.powi(2),
+            ),
+            (
+                Box::new(MitchellFilter::new(2.0, 1.0 / 3.0, 1.0 / 3.0)),
+                1.0,
+            ),
+            (Box::new(MitchellFilter::new(3.0, 0.0, 0.5)), 2.25),
+        ];
+
+        for (filter, total) in &filters {
+            approx::assert_relative_eq!(*total, integrate(filter.as_ref()), max_relative = 1e-3);
+
+            let radius = filter.radius();
+            for &(x, y) in &[
+                (radius + 0.01, 0.0),
+                (0.0, -radius - 0.01),
+                (-1.5 * radius, 1.5 * radius),
+            ] {
+                assert_eq!(0.0, filter.evaluate(x, y));
+            }
+        }
+    }
+}
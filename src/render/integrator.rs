@@ -0,0 +1,396 @@
+use rand::rngs::SmallRng;
+use rand::Rng;
+
+use super::lens;
+use super::linear;
+use super::scene;
+
+pub trait Integrator: Sync + Send {
+    fn trace(
+        &self,
+        scene: &scene::Scene,
+        ray: linear::Ray,
+        cone: lens::RayCone,
+        rng: &mut SmallRng,
+    ) -> scene::lighting::Color;
+}
+
+// Surface properties at the point where a ray hits the scene
+struct Hit<'a> {
+    ray: linear::Ray,
+    normal: linear::Vector,
+    back_face: bool,
+    texture: Option<scene::lighting::TextureLookup>,
+    material: &'a dyn scene::material::Material,
+    cone: lens::RayCone,
+}
+
+fn intersect<'a>(
+    scene: &'a scene::Scene,
+    ray: &linear::Ray,
+    cone: &lens::RayCone,
+) -> Option<Hit<'a>> {
+    let hit = scene.find_intersection(ray)?;
+
+    let distance = ray.direction.scale(hit.t);
+    let intersection_point = ray.position.add(&distance);
+    let cone = cone.at(distance.magnitude());
+
+    let geometric_normal = hit.geometric_normal();
+    let back_face = ray.direction.dot(&geometric_normal) > 0.0;
+
+    // The footprint stretches out as the surface turns away from the ray
+    let texture = match hit.has_texture() {
+        false => None,
+        true => {
+            let cosine = ray.direction.normalize().dot(&geometric_normal).abs();
+
+            Some(scene::lighting::TextureLookup {
+                uv: hit.uv(),
+                footprint: cone.width * hit.uv_density() / cosine.max(1e-3),
+            })
+        }
+    };
+
+    let material = scene.materials[hit.material_id()].as_ref();
+    let normal = material.shading_normal(hit.surface_normal(), hit.uv_derivatives(), texture);
+
+    // Back faces are shaded as seen from behind
+    let normal = match back_face {
+        true => normal.negative(),
+        false => normal,
+    };
+
+    Some(Hit {
+        ray: linear::Ray {
+            position: intersection_point,
+            direction: ray.direction,
+            time: ray.time,
+        },
+        normal,
+        back_face,
+        texture,
+        material,
+        cone,
+    })
+}
+
+// Classic recursive ray tracer, following only mirror reflections and
+// refractions, with a constant ambient term standing in for indirect lighting.
+pub struct WhittedIntegrator {
+    max_reflections: u32,
+}
+
+impl WhittedIntegrator {
+    pub fn new(max_reflections: u32) -> WhittedIntegrator {
+        WhittedIntegrator { max_reflections }
+    }
+
+    fn trace_ray(
+        &self,
+        scene: &scene::Scene,
+        ray: linear::Ray,
+        cone: lens::RayCone,
+        light_strength: f64,
+        remaining_reflections: u32,
+        rng: &mut SmallRng,
+    ) -> scene::lighting::Color {
+        let hit = match intersect(scene, &ray, &cone) {
+            Some(hit) => hit,
+            None => return scene::lighting::Color::black(),
+        };
+
+        let visible_lights = scene.visible_lights(
+            hit.ray.position,
+            hit.ray.time,
+            scene::lighting::Radiometry::Classic,
+            rng,
+        );
+
+        let (mut surface_color, rays) = scene::lighting::calculate(
+            &visible_lights,
+            scene.ambient_light,
+            &hit.ray,
+            hit.texture,
+            hit.normal,
+            hit.back_face,
+            light_strength,
+            hit.material,
+        );
+
+        if remaining_reflections > 0 {
+            // Colored reflections are traced at the strength of their
+            // brightest channel and tinted afterwards
+            for (ray, weight) in rays {
+                let reflected_color = self.trace_ray(
+                    scene,
+                    ray,
+                    hit.cone,
+                    light_strength * weight.max_component(),
+                    remaining_reflections - 1,
+                    rng,
+                );
+
+                surface_color.add(reflected_color.multiply(weight.tint()));
+            }
+        }
+
+        surface_color
+    }
+}
+
+impl Integrator for WhittedIntegrator {
+    fn trace(
+        &self,
+        scene: &scene::Scene,
+        ray: linear::Ray,
+        cone: lens::RayCone,
+        rng: &mut SmallRng,
+    ) -> scene::lighting::Color {
+        self.trace_ray(scene, ray, cone, 1.0, self.max_reflections, rng)
+    }
+}
+
+// Monte Carlo path tracer. Direct lighting is estimated at every bounce with
+// shadow rays to the scene lights, and the path continues in a single direction
+// sampled from the surface's material. Lights fall off physically, so that
+// direct lighting and light arriving from other surfaces add up consistently.
+pub struct PathTracer {
+    max_bounces: u32,
+    roulette_depth: u32,
+}
+
+impl PathTracer {
+    pub fn new(max_bounces: u32, roulette_depth: u32) -> PathTracer {
+        PathTracer {
+            max_bounces,
+            roulette_depth,
+        }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn trace(
+        &self,
+        scene: &scene::Scene,
+        ray: linear::Ray,
+        cone: lens::RayCone,
+        rng: &mut SmallRng,
+    ) -> scene::lighting::Color {
+        let mut radiance = scene::lighting::Color::black();
+        let mut throughput = scene::lighting::Color::new(1.0, 1.0, 1.0);
+        let mut ray = ray;
+        let mut cone = cone;
+
+        // Emissive surfaces are already sampled as lights, so emission is only
+        // counted where the path couldn't have sampled it directly
+        let mut count_emission = true;
+
+        for bounce in 0..=self.max_bounces {
+            let hit = match intersect(scene, &ray, &cone) {
+                Some(hit) => hit,
+                None => break,
+            };
+            cone = hit.cone;
+
+            if count_emission {
+                radiance.add(hit.material.emission().multiply(throughput));
+            }
+
+            let visible_lights = scene.visible_lights(
+                hit.ray.position,
+                hit.ray.time,
+                scene::lighting::Radiometry::Physical,
+                rng,
+            );
+            let direct =
+                hit.material
+                    .direct(&visible_lights, &hit.ray, hit.texture, hit.normal, 1.0);
+            radiance.add(direct.multiply(throughput));
+
+            if bounce == self.max_bounces {
+                break;
+            }
+
+            let scatter =
+                hit.material
+                    .scatter(&hit.ray, hit.texture, hit.normal, hit.back_face, rng);
+            let scatter = match scatter {
+                Some(scatter) => scatter,
+                None => break,
+            };
+
+            throughput = throughput
+                .multiply(scatter.weight)
+                .scale(1.0 / scatter.probability);
+            count_emission = scatter.specular || !scene.samples_emitters();
+            ray = scatter.ray;
+
+            if bounce >= self.roulette_depth {
+                let survival = throughput.max_component().min(0.95);
+                if survival <= 0.0 || rng.gen::<f64>() >= survival {
+                    break;
+                }
+
+                throughput = throughput.scale(1.0 / survival);
+            }
+        }
+
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use rand::SeedableRng;
+    use std::f64::consts;
+
+    const CONE: lens::RayCone = lens::RayCone {
+        width: 0.0,
+        spread: 0.0,
+    };
+
+    // A single shape made of one material
+    fn shape_scene(
+        shape: config::PrimitiveShape,
+        material: config::MaterialConfig,
+        lights: Vec<scene::lighting::LightSource>,
+    ) -> scene::Scene {
+        scene::Scene::new(
+            vec![material.to_material()],
+            vec![scene::instance::Mesh::new(vec![shape.to_primitive(0)])],
+            vec![scene::instance::Instance::new(
+                0,
+                0,
+                None,
+                config::TransformConfig::default().to_transform().unwrap(),
+            )],
+            lights,
+            16,
+        )
+    }
+
+    // Lambertian surface with no highlights or reflections
+    fn diffuse(albedo: f64, emission: f64) -> config::MaterialConfig {
+        config::MaterialConfig {
+            diffuse: scene::lighting::Color::new(albedo, albedo, albedo),
+            specular: scene::lighting::Color::black(),
+            emission: scene::lighting::Color::new(emission, emission, emission),
+            reflectance: 0.0,
+            ..config::MaterialConfig::default()
+        }
+    }
+
+    #[test]
+    fn diffuse_plane() {
+        // A white light has a radiant intensity of pi
+        let light: scene::lighting::LightSource = serde_json::from_str(
+            r#"{"type": "point", "position": {"x": 0, "y": 2, "z": 0},
+                "specular": [1, 1, 1], "diffuse": [1, 1, 1], "ambient": [0, 0, 0]}"#,
+        )
+        .unwrap();
+        let scene = shape_scene(
+            config::PrimitiveShape::Plane,
+            diffuse(0.5, 0.0),
+            vec![light],
+        );
+
+        let mut rng = SmallRng::seed_from_u64(2);
+
+        // The plane reflects albedo / pi of the irradiance, which is the
+        // intensity times the cosine over the squared distance
+        let (distance_squared, cosine) = (1.5 * 1.5 + 2.0 * 2.0, 2.0 / 2.5);
+        let expected = 0.5 / consts::PI * consts::PI * cosine / distance_squared;
+
+        for &bounces in [0, 5].iter() {
+            let ray = linear::Ray {
+                position: linear::Vector::new(1.5, 1.0, 0.0),
+                direction: linear::Vector::new(0.0, -1.0, 0.0),
+                time: 0.0,
+            };
+            let radiance = PathTracer::new(bounces, 3).trace(&scene, ray, CONE, &mut rng);
+            approx::assert_abs_diff_eq!(expected, radiance.average(), epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn furnace() {
+        // Inside a glowing sphere, light bounces back and forth forever, adding
+        // up to emission / (1 - albedo)
+        let mut material = diffuse(0.5, 1.0);
+        material.culling = Some(scene::primitive::Culling::Front);
+        let scene = shape_scene(
+            config::PrimitiveShape::Sphere { radius: 1.0 },
+            material,
+            Vec::new(),
+        );
+
+        let integrator = PathTracer::new(50, 3);
+        let mut rng = SmallRng::seed_from_u64(4);
+
+        let paths = 4000;
+        let mut total = 0.0;
+        for _ in 0..paths {
+            let ray = linear::Ray {
+                position: linear::Vector::new(0.0, 0.0, 0.0),
+                direction: linear::Vector::new(0.0, 0.0, 1.0),
+                time: 0.0,
+            };
+            total += integrator.trace(&scene, ray, CONE, &mut rng).average();
+        }
+
+        approx::assert_relative_eq!(2.0, total / (paths as f64), max_relative = 0.02);
+    }
+
+    #[test]
+    fn refract_through_glass() {
+        // A slab of glass one unit thick, left with its default culling
+        let glass = config::MaterialConfig {
+            transparency: 1.0,
+            index_of_refraction: 1.5,
+            ..config::MaterialConfig::default()
+        };
+        let slab = config::PrimitiveShape::Box {
+            min: linear::Vector::new(-10.0, -1.0, -10.0),
+            max: linear::Vector::new(10.0, 0.0, 10.0),
+        };
+        let scene = shape_scene(slab, glass, Vec::new());
+
+        let direction = linear::Vector::new(1.0, -1.0, 0.0).normalize();
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 1.0, 0.0),
+            direction,
+            time: 0.0,
+        };
+
+        // Each hit splits into a reflected ray and a refracted one
+        let refract = |ray: &linear::Ray, back_face: bool| {
+            let hit = intersect(&scene, ray, &CONE).expect("Ray must hit the glass");
+            assert_eq!(back_face, hit.back_face);
+
+            let mut rays =
+                hit.material
+                    .secondary_rays(&hit.ray, hit.texture, hit.normal, hit.back_face);
+            assert_eq!(2, rays.len());
+            rays.pop().unwrap().0
+        };
+
+        // The ray bends towards the normal inside the glass, leaves through the
+        // far side, and carries on parallel to how it came in
+        let inside = refract(&ray, false);
+        let sin_inside = 0.5f64.sqrt() / 1.5;
+        approx::assert_abs_diff_eq!(sin_inside, inside.direction.component(0), epsilon = 1e-9);
+
+        let outside = refract(&inside, true);
+        let exit_x = 1.0 + sin_inside / (1.0 - sin_inside * sin_inside).sqrt();
+        let exit = linear::Vector::new(exit_x, -1.0, 0.0);
+        approx::assert_abs_diff_eq!(
+            0.0,
+            outside.position.subtract(&exit).magnitude(),
+            epsilon = 1e-9
+        );
+        approx::assert_abs_diff_eq!(1.0, outside.direction.dot(&direction), epsilon = 1e-9);
+    }
+}
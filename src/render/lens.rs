@@ -1,3 +1,7 @@
+use rand::rngs::SmallRng;
+use rand::Rng;
+use std::f64::consts;
+
 use super::linear;
 
 struct ViewPort {
@@ -5,25 +9,31 @@ struct ViewPort {
     height: f64,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct Scope {
     position: linear::Vector,
     right: linear::Vector,
     up: linear::Vector,
     forward: linear::Vector,
+    target_distance: f64,
+    focal_length: f64,
 }
 
 impl Scope {
-    pub fn new(target: linear::Vector, position: linear::Vector, roll: f64) -> Scope {
+    pub fn new(
+        target: linear::Vector,
+        position: linear::Vector,
+        roll: f64,
+        focal_length: f64,
+    ) -> Scope {
         let forward = target.subtract(&position).normalize();
         let vertical = linear::Vector::new(0.0, 1.0, 0.0);
 
-        let right: linear::Vector;
-        if forward.equals(&vertical) {
-            right = linear::Vector::new(1.0, 0.0, 0.0);
+        let right = if forward.equals(&vertical) {
+            linear::Vector::new(1.0, 0.0, 0.0)
         } else {
-            right = forward.cross(&vertical);
-        }
+            forward.cross(&vertical)
+        };
 
         let up = right.cross(&forward);
         let up = up.rotate(-roll, &forward);
@@ -35,46 +45,306 @@ impl Scope {
             right,
             up,
             forward,
+            target_distance: target.subtract(&position).magnitude(),
+            focal_length,
+        }
+    }
+}
+
+// Where the camera is, what it looks at, how far it is rolled and how far it
+// is zoomed at one time
+#[derive(Copy, Clone, Debug)]
+pub struct CameraKeyframe {
+    pub time: f64,
+    pub position: linear::Vector,
+    pub target: linear::Vector,
+    pub roll: f64,
+    pub focal_length: f64,
+}
+
+impl CameraKeyframe {
+    fn values(&self) -> [f64; 8] {
+        [
+            self.position.component(0),
+            self.position.component(1),
+            self.position.component(2),
+            self.target.component(0),
+            self.target.component(1),
+            self.target.component(2),
+            self.roll,
+            self.focal_length,
+        ]
+    }
+
+    fn scope(&self) -> Scope {
+        Scope::new(self.target, self.position, self.roll, self.focal_length)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // Smooth curve through every keyframe, heading at each one from the
+    // keyframe before it towards the keyframe after it
+    CatmullRom,
+}
+
+// Placement of the camera over time, interpolated between keyframes and held
+// before the first and after the last
+pub struct CameraPath {
+    keyframes: Vec<CameraKeyframe>,
+    interpolation: Interpolation,
+    first: Scope,
+    last: Scope,
+}
+
+impl CameraPath {
+    pub fn new(mut keyframes: Vec<CameraKeyframe>, interpolation: Interpolation) -> CameraPath {
+        assert!(
+            !keyframes.is_empty(),
+            "Camera paths need at least one keyframe"
+        );
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+        let first = keyframes[0].scope();
+        let last = keyframes[keyframes.len() - 1].scope();
+
+        CameraPath {
+            keyframes,
+            interpolation,
+            first,
+            last,
+        }
+    }
+
+    fn scope(&self, time: f64) -> Scope {
+        let keyframes = &self.keyframes;
+
+        let index = match keyframes.iter().position(|keyframe| keyframe.time > time) {
+            Some(0) => return self.first,
+            Some(index) => index,
+            None => return self.last,
+        };
+
+        let (start, end) = (keyframes[index - 1], keyframes[index]);
+        let duration = end.time - start.time;
+        let t = (time - start.time) / duration;
+        let (start_values, end_values) = (start.values(), end.values());
+
+        let values: [f64; 8] = match self.interpolation {
+            Interpolation::Linear => std::array::from_fn(|value| {
+                start_values[value] + t * (end_values[value] - start_values[value])
+            }),
+            Interpolation::CatmullRom => {
+                // Rates of change at each end of the segment, from the keyframes
+                // either side, or along the segment at the ends of the path
+                let before = keyframes[index.saturating_sub(2)];
+                let after = keyframes[(index + 1).min(keyframes.len() - 1)];
+                let (before_values, after_values) = (before.values(), after.values());
+                let start_rate = |value: usize| {
+                    (end_values[value] - before_values[value]) / (end.time - before.time)
+                };
+                let end_rate = |value: usize| {
+                    (after_values[value] - start_values[value]) / (after.time - start.time)
+                };
+
+                // Cubic Hermite basis
+                let t2 = t * t;
+                let t3 = t2 * t;
+                let (h00, h10) = (2.0 * t3 - 3.0 * t2 + 1.0, t3 - 2.0 * t2 + t);
+                let (h01, h11) = (-2.0 * t3 + 3.0 * t2, t3 - t2);
+
+                std::array::from_fn(|value| {
+                    h00 * start_values[value]
+                        + h10 * duration * start_rate(value)
+                        + h01 * end_values[value]
+                        + h11 * duration * end_rate(value)
+                })
+            }
+        };
+
+        // Curves can overshoot the keyframes, which could zoom the camera out
+        // past a focal length of zero, so zooms stay between their keyframes
+        let focal_length = values[7].clamp(
+            start.focal_length.min(end.focal_length),
+            start.focal_length.max(end.focal_length),
+        );
+
+        Scope::new(
+            linear::Vector::new(values[3], values[4], values[5]),
+            linear::Vector::new(values[0], values[1], values[2]),
+            values[6],
+            focal_length,
+        )
+    }
+}
+
+// Cone around a ray covering one pixel, used to pick texture detail. The cone
+// is width across at the ray's origin and widens by spread per unit distance.
+#[derive(Copy, Clone, Debug)]
+pub struct RayCone {
+    pub width: f64,
+    pub spread: f64,
+}
+
+impl RayCone {
+    pub fn at(&self, distance: f64) -> RayCone {
+        RayCone {
+            width: self.width + self.spread * distance,
+            spread: self.spread,
         }
     }
 }
 
+// Lenses cast rays through points on the screen at a given time, each with the
+// cone covering one pixel around it
 pub trait Lens: Sync + Send {
-    fn generate_light_ray(&self, x: f64, y: f64) -> linear::Ray;
+    fn generate_light_ray(
+        &self,
+        x: f64,
+        y: f64,
+        time: f64,
+        rng: &mut SmallRng,
+    ) -> (linear::Ray, RayCone);
 }
 
 pub struct OrthographicLens {
     view_port: ViewPort,
-    scope: Scope,
+    path: CameraPath,
+    pixel_width: f64,
 }
 
 impl OrthographicLens {
-    pub fn new(width: f64, image_width: f64, image_height: f64, scope: Scope) -> OrthographicLens {
+    pub fn new(
+        width: f64,
+        image_width: f64,
+        image_height: f64,
+        path: CameraPath,
+    ) -> OrthographicLens {
         let view_port = ViewPort {
             width,
             height: width * (image_height / image_width),
         };
 
-        OrthographicLens { view_port, scope }
+        OrthographicLens {
+            view_port,
+            path,
+            pixel_width: width / image_width,
+        }
     }
 }
 
 impl Lens for OrthographicLens {
-    fn generate_light_ray(&self, x: f64, y: f64) -> linear::Ray {
-        let horizontal = self.scope.right.scale(x * self.view_port.width * 0.5);
-        let vertical = self.scope.up.scale(y * self.view_port.height * 0.5);
+    fn generate_light_ray(
+        &self,
+        x: f64,
+        y: f64,
+        time: f64,
+        _rng: &mut SmallRng,
+    ) -> (linear::Ray, RayCone) {
+        let scope = self.path.scope(time);
+        let horizontal = scope.right.scale(x * self.view_port.width * 0.5);
+        let vertical = scope.up.scale(y * self.view_port.height * 0.5);
+
+        let ray = linear::Ray {
+            position: scope.position.add(&horizontal).add(&vertical),
+            direction: scope.forward,
+            time,
+        };
+        let cone = RayCone {
+            width: self.pixel_width,
+            spread: 0.0,
+        };
+
+        (ray, cone)
+    }
+}
+
+// Out of focus highlights take the shape of the aperture
+#[derive(Copy, Clone, Debug)]
+pub enum ApertureShape {
+    Disk,
+    // Regular polygon, with a corner turned by rotation degrees from the right
+    Polygon { blades: u32, rotation: f64 },
+}
+
+// How wide the aperture opens, either as an f-stop, dividing the focal length
+// into the aperture's diameter so the opening follows the zoom, or as a fixed
+// radius
+#[derive(Copy, Clone, Debug)]
+pub enum ApertureSize {
+    FStop(f64),
+    Radius(f64),
+}
 
-        linear::Ray {
-            position: self.scope.position.add(&horizontal).add(&vertical),
-            direction: self.scope.forward,
+impl ApertureSize {
+    fn radius(&self, focal_length: f64) -> f64 {
+        match *self {
+            ApertureSize::FStop(f_stop) => 0.5 * focal_length / f_stop,
+            ApertureSize::Radius(radius) => radius,
         }
     }
 }
 
+// Opening of a thin lens. Rays leave from points spread over it and meet again
+// at the focus distance in front of the camera, so only things around that
+// distance are sharp. Without a focus distance, the camera focuses on its
+// target.
+pub struct Aperture {
+    size: ApertureSize,
+    focus_distance: Option<f64>,
+    shape: ApertureShape,
+}
+
+impl Aperture {
+    pub fn new(size: ApertureSize, focus_distance: Option<f64>, shape: ApertureShape) -> Aperture {
+        Aperture {
+            size,
+            focus_distance,
+            shape,
+        }
+    }
+
+    // Offset from the center of the aperture, along the camera's right and up
+    // directions, spread uniformly over it at the given focal length
+    fn sample(&self, focal_length: f64, rng: &mut SmallRng) -> (f64, f64) {
+        let radius = self.size.radius(focal_length);
+
+        match self.shape {
+            ApertureShape::Disk => {
+                let distance = radius * rng.gen::<f64>().sqrt();
+                let angle = 2.0 * consts::PI * rng.gen::<f64>();
+
+                (distance * angle.cos(), distance * angle.sin())
+            }
+            ApertureShape::Polygon { blades, rotation } => {
+                let corner = |index: u32| {
+                    let angle =
+                        rotation.to_radians() + 2.0 * consts::PI * (index as f64) / (blades as f64);
+
+                    (radius * angle.cos(), radius * angle.sin())
+                };
+
+                // The center and each side make equally sized triangles
+                let blade = rng.gen_range(0, blades);
+                let (a, b) = (corner(blade), corner(blade + 1));
+
+                let root = rng.gen::<f64>().sqrt();
+                let along_a = root * (1.0 - rng.gen::<f64>());
+                let along_b = root - along_a;
+
+                (along_a * a.0 + along_b * b.0, along_a * a.1 + along_b * b.1)
+            }
+        }
+    }
+}
+
+// Pinhole camera, or a thin lens camera when given an aperture
 pub struct PerspectiveLens {
     view_port: ViewPort,
-    scope: Scope,
-    focal_length: f64,
+    path: CameraPath,
+    pixel_width: f64,
+    aperture: Option<Aperture>,
 }
 
 impl PerspectiveLens {
@@ -82,8 +352,8 @@ impl PerspectiveLens {
         width: f64,
         image_width: u32,
         image_height: u32,
-        scope: Scope,
-        focal_length: f64,
+        path: CameraPath,
+        aperture: Option<Aperture>,
     ) -> PerspectiveLens {
         let view_port = ViewPort {
             width,
@@ -92,23 +362,257 @@ impl PerspectiveLens {
 
         PerspectiveLens {
             view_port,
-            scope,
-            focal_length,
+            path,
+            pixel_width: width / (image_width as f64),
+            aperture,
         }
     }
 }
 
 impl Lens for PerspectiveLens {
-    fn generate_light_ray(&self, x: f64, y: f64) -> linear::Ray {
-        let forward = self.scope.forward.scale(self.focal_length);
-        let horizontal = self.scope.right.scale(x * self.view_port.width * 0.5);
-        let vertical = self.scope.up.scale(y * self.view_port.height * 0.5);
+    fn generate_light_ray(
+        &self,
+        x: f64,
+        y: f64,
+        time: f64,
+        rng: &mut SmallRng,
+    ) -> (linear::Ray, RayCone) {
+        let scope = self.path.scope(time);
+        let cone = RayCone {
+            width: 0.0,
+            spread: self.pixel_width / scope.focal_length,
+        };
+
+        let forward = scope.forward.scale(scope.focal_length);
+        let horizontal = scope.right.scale(x * self.view_port.width * 0.5);
+        let vertical = scope.up.scale(y * self.view_port.height * 0.5);
+
+        let direction = forward.add(&horizontal).add(&vertical);
+
+        let aperture = match &self.aperture {
+            Some(aperture) => aperture,
+            None => {
+                let ray = linear::Ray {
+                    position: scope.position,
+                    direction: direction.normalize(),
+                    time,
+                };
+
+                return (ray, cone);
+            }
+        };
+
+        // Rays from anywhere on the lens meet the pinhole ray at the focus distance
+        let focus_distance = aperture.focus_distance.unwrap_or(scope.target_distance);
+        let focus = scope
+            .position
+            .add(&direction.scale(focus_distance / scope.focal_length));
+
+        let (offset_x, offset_y) = aperture.sample(scope.focal_length, rng);
+        let position = scope
+            .position
+            .add(&scope.right.scale(offset_x))
+            .add(&scope.up.scale(offset_y));
+
+        let ray = linear::Ray {
+            position,
+            direction: focus.subtract(&position).normalize(),
+            time,
+        };
+
+        (ray, cone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn thin_lens_focus() {
+        let position = linear::Vector::new(1.0, 2.0, 3.0);
+        let target = linear::Vector::new(-4.0, 0.0, 10.0);
+        let lens = |aperture| {
+            PerspectiveLens::new(
+                16.0,
+                320,
+                180,
+                CameraPath::new(
+                    vec![CameraKeyframe {
+                        time: 0.0,
+                        position,
+                        target,
+                        roll: 15.0,
+                        focal_length: 20.0,
+                    }],
+                    Interpolation::Linear,
+                ),
+                aperture,
+            )
+        };
+
+        let pinhole = lens(None);
+        let mut rng = SmallRng::seed_from_u64(5);
+        let ray = pinhole.generate_light_ray(0.3, -0.6, 0.0, &mut rng).0;
+        let forward = pinhole.path.first.forward;
+        let focus = ray
+            .position
+            .add(&ray.direction.scale(12.0 / ray.direction.dot(&forward)));
+
+        let shapes = [
+            ApertureShape::Disk,
+            ApertureShape::Polygon {
+                blades: 6,
+                rotation: 10.0,
+            },
+        ];
+        for &shape in shapes.iter() {
+            let thin_lens = lens(Some(Aperture::new(
+                ApertureSize::Radius(0.5),
+                Some(12.0),
+                shape,
+            )));
+
+            // Rays leave from the lens, facing the way the camera looks, and all
+            // cross the focus plane at the same point
+            for _ in 0..100 {
+                let ray = thin_lens.generate_light_ray(0.3, -0.6, 0.0, &mut rng).0;
+                let offset = ray.position.subtract(&position);
+                approx::assert_abs_diff_eq!(0.0, offset.dot(&forward), epsilon = 1e-9);
+                assert!(offset.magnitude() <= 0.5 + 1e-9);
+
+                let distance = focus.subtract(&ray.position).dot(&forward);
+                let crossing = ray
+                    .position
+                    .add(&ray.direction.scale(distance / ray.direction.dot(&forward)));
+                approx::assert_abs_diff_eq!(
+                    0.0,
+                    crossing.subtract(&focus).magnitude(),
+                    epsilon = 1e-9
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn f_stop_zoom() {
+        let keyframe = |time, focal_length| CameraKeyframe {
+            time,
+            position: linear::Vector::new(0.0, 0.0, 0.0),
+            target: linear::Vector::new(0.0, 0.0, 10.0),
+            roll: 0.0,
+            focal_length,
+        };
+        let lens = PerspectiveLens::new(
+            16.0,
+            320,
+            180,
+            CameraPath::new(
+                vec![keyframe(0.0, 2.0), keyframe(1.0, 8.0)],
+                Interpolation::Linear,
+            ),
+            Some(Aperture::new(
+                ApertureSize::FStop(2.0),
+                None,
+                ApertureShape::Disk,
+            )),
+        );
+
+        // The aperture opens up as the camera zooms in, keeping its f-stop
+        let mut rng = SmallRng::seed_from_u64(5);
+        for &(time, radius) in &[(0.0, 0.5), (0.5, 1.25), (1.0, 2.0)] {
+            let widest = (0..1000)
+                .map(|_| {
+                    let ray = lens.generate_light_ray(0.0, 0.0, time, &mut rng).0;
+                    ray.position.magnitude()
+                })
+                .fold(0.0, f64::max);
+            assert!(widest <= radius + 1e-9, "{} {}", time, widest);
+            assert!(widest > 0.95 * radius, "{} {}", time, widest);
+        }
+    }
+
+    #[test]
+    fn camera_path() {
+        let keyframe = |time, x, roll| CameraKeyframe {
+            time,
+            position: linear::Vector::new(x, 0.0, 0.0),
+            target: linear::Vector::new(x, 0.0, 10.0),
+            roll,
+            focal_length: 10.0 + x,
+        };
+        let lens = OrthographicLens::new(
+            2.0,
+            100.0,
+            100.0,
+            CameraPath::new(
+                vec![keyframe(3.0, 4.0, 90.0), keyframe(1.0, 0.0, 0.0)],
+                Interpolation::Linear,
+            ),
+        );
+        let mut rng = SmallRng::seed_from_u64(5);
 
-        let direction = forward.add(&horizontal).add(&vertical).normalize();
+        // The camera waits at the first and last keyframes, and moves and rolls
+        // evenly between them
+        for &(time, x) in [(0.0, 0.0), (1.0, 0.0), (2.0, 2.0), (3.0, 4.0), (5.0, 4.0)].iter() {
+            let (ray, cone) = lens.generate_light_ray(0.0, 0.0, time, &mut rng);
+            approx::assert_ulps_eq!(0.02, cone.width);
+            assert!(ray.position.equals(&linear::Vector::new(x, 0.0, 0.0)));
+            approx::assert_ulps_eq!(time, ray.time);
+        }
+
+        let scope = lens.path.scope(2.0);
+        let up = linear::Vector::new(0.0, 1.0, 0.0).rotate(-45.0, &scope.forward);
+        approx::assert_abs_diff_eq!(1.0, scope.up.dot(&up), epsilon = 1e-9);
+        approx::assert_ulps_eq!(12.0, scope.focal_length);
+
+        // Curves pass through every keyframe, and keyframes evenly spread along
+        // a line, in time as in space, are followed at a steady speed
+        let keyframes = (0..4).map(|key| keyframe(key as f64, 2.0 * key as f64, 0.0));
+        let path = CameraPath::new(keyframes.collect(), Interpolation::CatmullRom);
+        for step in 0..=30 {
+            let time = step as f64 / 10.0;
+            let scope = path.scope(time);
+            approx::assert_abs_diff_eq!(2.0 * time, scope.position.component(0), epsilon = 1e-9);
+            approx::assert_abs_diff_eq!(10.0 + 2.0 * time, scope.focal_length, epsilon = 1e-9);
+        }
+
+        // Away from a line, the path bends smoothly through the keyframes
+        let mut keyframes = vec![
+            keyframe(0.0, 0.0, 0.0),
+            keyframe(1.0, 1.0, 0.0),
+            keyframe(3.0, 0.0, 0.0),
+        ];
+        keyframes[1].position = linear::Vector::new(1.0, 2.0, 0.0);
+        let path = CameraPath::new(keyframes, Interpolation::CatmullRom);
+        assert!(path
+            .scope(1.0)
+            .position
+            .equals(&linear::Vector::new(1.0, 2.0, 0.0)));
+
+        let heading = |time: f64| {
+            path.scope(time + 1e-6)
+                .position
+                .subtract(&path.scope(time - 1e-6).position)
+                .normalize()
+        };
+        approx::assert_abs_diff_eq!(1.0, heading(1.0).dot(&heading(1.0 - 1e-4)), epsilon = 1e-6);
+        assert!(path.scope(0.5).position.component(1) > 1.0);
 
-        linear::Ray {
-            position: self.scope.position,
-            direction,
+        // Zooms don't overshoot their keyframes, where they could pass zero
+        let mut keyframes = vec![
+            keyframe(0.0, 0.0, 0.0),
+            keyframe(1.0, 0.0, 0.0),
+            keyframe(3.0, 0.0, 0.0),
+        ];
+        keyframes[0].focal_length = 10.0;
+        keyframes[1].focal_length = 0.5;
+        keyframes[2].focal_length = 0.5;
+        let path = CameraPath::new(keyframes, Interpolation::CatmullRom);
+        for step in 0..=30 {
+            let focal_length = path.scope(step as f64 / 10.0).focal_length;
+            assert!((0.5..=10.0).contains(&focal_length));
         }
     }
 }
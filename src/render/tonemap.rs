@@ -0,0 +1,194 @@
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+// Tone mapping operators compress scene radiance into the displayable [0, 1]
+// range. Values outside of it are clamped afterwards.
+pub trait ToneMapOperator: Sync + Send {
+    fn map(&self, value: f64) -> f64;
+}
+
+pub struct ClampOperator;
+
+impl ToneMapOperator for ClampOperator {
+    fn map(&self, value: f64) -> f64 {
+        value
+    }
+}
+
+pub struct ReinhardOperator {
+    white: Option<f64>,
+}
+
+impl ReinhardOperator {
+    pub fn new(white: Option<f64>) -> ReinhardOperator {
+        ReinhardOperator { white }
+    }
+}
+
+impl ToneMapOperator for ReinhardOperator {
+    fn map(&self, value: f64) -> f64 {
+        match self.white {
+            Some(white) => value * (1.0 + value / (white * white)) / (1.0 + value),
+            None => value / (1.0 + value),
+        }
+    }
+}
+
+// Narkowicz's curve fit of the ACES filmic reference rendering transform
+pub struct AcesOperator;
+
+impl ToneMapOperator for AcesOperator {
+    fn map(&self, value: f64) -> f64 {
+        (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14)
+    }
+}
+
+// Hable's filmic curve from Uncharted 2, scaled so white maps to 1
+pub struct HableOperator {
+    white_scale: f64,
+}
+
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+impl HableOperator {
+    pub fn new(white: f64) -> HableOperator {
+        HableOperator {
+            white_scale: 1.0 / hable(white),
+        }
+    }
+}
+
+impl ToneMapOperator for HableOperator {
+    fn map(&self, value: f64) -> f64 {
+        hable(value) * self.white_scale
+    }
+}
+
+fn srgb_oetf(value: f64) -> f64 {
+    if value <= 0.003_130_8 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Converts linear radiance to 8 bit pixels: exposure, then tone mapping, then
+// the display transfer function, and finally quantisation
+pub struct OutputTransform<'a> {
+    exposure: f64,
+    operator: &'a dyn ToneMapOperator,
+    srgb: bool,
+    dither: bool,
+}
+
+impl OutputTransform<'_> {
+    pub fn new(
+        exposure: f64,
+        operator: &dyn ToneMapOperator,
+        srgb: bool,
+        dither: bool,
+    ) -> OutputTransform<'_> {
+        OutputTransform {
+            exposure: 2f64.powf(exposure),
+            operator,
+            srgb,
+            dither,
+        }
+    }
+
+    fn encode(&self, value: f64) -> f64 {
+        let mapped = self
+            .operator
+            .map((value * self.exposure).max(0.0))
+            .clamp(0.0, 1.0);
+
+        match self.srgb {
+            true => srgb_oetf(mapped),
+            false => mapped,
+        }
+    }
+
+    // Triangular noise spanning two quantisation steps hides banding in
+    // smooth gradients without biasing the average
+    fn quantize(&self, value: f64, rng: &mut SmallRng) -> u8 {
+        let noise = match self.dither {
+            true => rng.gen::<f64>() - rng.gen::<f64>(),
+            false => 0.0,
+        };
+
+        (value * 255.0 + noise).round().clamp(0.0, 255.0) as u8
+    }
+
+    pub fn apply(&self, framebuffer: &super::Framebuffer) -> image::RgbImage {
+        let width = framebuffer.width();
+
+        image::ImageBuffer::from_fn(width, framebuffer.height(), |x, y| {
+            let mut rng = SmallRng::seed_from_u64((y * width + x) as u64);
+            let pixel = framebuffer.get_pixel(x, y);
+
+            let mut output = [0; 3];
+            for (channel, value) in output.iter_mut().zip(pixel.0.iter()) {
+                *channel = self.quantize(self.encode(*value as f64), &mut rng);
+            }
+
+            image::Rgb(output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+
+    #[test]
+    fn operators() {
+        assert_abs_diff_eq!(0.5, ReinhardOperator::new(None).map(1.0));
+        assert_abs_diff_eq!(1.0, ReinhardOperator::new(Some(4.0)).map(4.0));
+        assert_abs_diff_eq!(1.0, HableOperator::new(11.2).map(11.2));
+        assert_abs_diff_eq!(0.0, AcesOperator.map(0.0), epsilon = 1e-12);
+
+        let operators: [&dyn ToneMapOperator; 3] = [
+            &ReinhardOperator::new(None),
+            &AcesOperator,
+            &HableOperator::new(11.2),
+        ];
+        for operator in operators.iter() {
+            let mut previous = operator.map(0.0);
+            for step in 1..100 {
+                let value = operator.map(step as f64 * 0.1);
+                assert!(value > previous);
+                previous = value;
+            }
+        }
+    }
+
+    #[test]
+    fn srgb() {
+        assert_abs_diff_eq!(0.0, srgb_oetf(0.0));
+        assert_abs_diff_eq!(1.0, srgb_oetf(1.0), epsilon = 1e-12);
+        assert_abs_diff_eq!(
+            12.92 * 0.003_130_8,
+            srgb_oetf(0.003_130_8 + 1e-12),
+            epsilon = 1e-6
+        );
+        assert_abs_diff_eq!(0.735_357, srgb_oetf(0.5), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn quantize() {
+        let mut framebuffer = super::super::Framebuffer::new(2, 1);
+        framebuffer.put_pixel(0, 0, image::Rgb([0.5, 2.0, -1.0]));
+        framebuffer.put_pixel(1, 0, image::Rgb([0.25, 0.25, 0.25]));
+
+        let transform = OutputTransform::new(1.0, &ClampOperator, false, false);
+        let image = transform.apply(&framebuffer);
+
+        assert_eq!(image::Rgb([255, 255, 0]), *image.get_pixel(0, 0));
+        assert_eq!(image::Rgb([128, 128, 128]), *image.get_pixel(1, 0));
+    }
+}
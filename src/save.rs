@@ -0,0 +1,42 @@
+use std::fs;
+use std::io;
+use std::path;
+
+use super::config;
+use super::render;
+
+mod exr;
+mod pfm;
+
+fn to_io_error(error: image::ImageError) -> io::Error {
+    io::Error::other(error)
+}
+
+fn create(file_path: &path::Path) -> Result<io::BufWriter<fs::File>, io::Error> {
+    Ok(io::BufWriter::new(fs::File::create(file_path)?))
+}
+
+pub fn image(
+    framebuffer: &render::Framebuffer,
+    file: &str,
+    format: config::ImageFormat,
+    transform: &render::tonemap::OutputTransform,
+) -> Result<(), io::Error> {
+    let file_path = path::Path::new(file);
+
+    match format {
+        config::ImageFormat::Png => transform
+            .apply(framebuffer)
+            .save_with_format(file_path, image::ImageFormat::Png)
+            .map_err(to_io_error),
+        config::ImageFormat::Exr => exr::write_exr(create(file_path)?, framebuffer),
+        config::ImageFormat::Pfm => pfm::write_pfm(create(file_path)?, framebuffer),
+        config::ImageFormat::Hdr => image::hdr::HdrEncoder::new(create(file_path)?)
+            .encode(
+                &framebuffer.pixels().copied().collect::<Vec<_>>(),
+                framebuffer.width() as usize,
+                framebuffer.height() as usize,
+            )
+            .map_err(to_io_error),
+    }
+}
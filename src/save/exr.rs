@@ -0,0 +1,130 @@
+use std::io;
+use std::io::Write;
+
+use super::render;
+
+const MAGIC: u32 = 20_000_630;
+const VERSION: u32 = 2;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+// Channels are stored in alphabetical order
+const CHANNELS: [(&str, usize); 3] = [("B", 2), ("G", 1), ("R", 0)];
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn header(width: u32, height: u32) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mut channels = Vec::new();
+    for (name, _) in CHANNELS.iter() {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // Linear flag and reserved bytes, then x and y subsampling
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    attribute(&mut header, "channels", "chlist", &channels);
+
+    // No compression
+    attribute(&mut header, "compression", "compression", &[0]);
+
+    let mut window = Vec::new();
+    for value in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+
+    // Scanlines stored from the top of the image down
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+
+    header.push(0);
+    header
+}
+
+// Uncompressed scanline OpenEXR with 32 bit float channels, one scanline per
+// block after the header and the table of block offsets
+pub fn write_exr<W: Write>(
+    mut writer: W,
+    framebuffer: &render::Framebuffer,
+) -> Result<(), io::Error> {
+    let (width, height) = framebuffer.dimensions();
+
+    let header = header(width, height);
+    writer.write_all(&header)?;
+
+    let line_size = CHANNELS.len() * 4 * (width as usize);
+    let block_size = 8 + line_size;
+    let table_end = header.len() + 8 * (height as usize);
+
+    for y in 0..height as usize {
+        let offset = (table_end + y * block_size) as u64;
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+
+    for y in 0..height {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&(line_size as i32).to_le_bytes())?;
+
+        for (_, channel) in CHANNELS.iter() {
+            for x in 0..width {
+                let value = framebuffer.get_pixel(x, y)[*channel];
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let mut framebuffer = render::Framebuffer::new(2, 3);
+        framebuffer.put_pixel(0, 1, image::Rgb([1.0, 2.0, 3.0]));
+
+        let mut data = Vec::new();
+        write_exr(&mut data, &framebuffer).unwrap();
+
+        let header_size = header(2, 3).len();
+        assert_eq!(&[0x76, 0x2f, 0x31, 0x01], &data[..4]);
+        assert_eq!(header_size + 3 * 8 + 3 * (8 + 24), data.len());
+
+        // The second scanline starts with its y coordinate, then blue for the
+        // first pixel
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&data[header_size + 8..header_size + 16]);
+        let offset = u64::from_le_bytes(offset) as usize;
+
+        assert_eq!(&1i32.to_le_bytes(), &data[offset..offset + 4]);
+        assert_eq!(&3f32.to_le_bytes(), &data[offset + 8..offset + 12]);
+    }
+}
@@ -0,0 +1,50 @@
+use std::io;
+use std::io::Write;
+
+use super::render;
+
+// Portable float map: a short text header, then little endian RGB floats with
+// rows stored from the bottom of the image up
+pub fn write_pfm<W: Write>(
+    mut writer: W,
+    framebuffer: &render::Framebuffer,
+) -> Result<(), io::Error> {
+    write!(
+        writer,
+        "PF\n{} {}\n-1.0\n",
+        framebuffer.width(),
+        framebuffer.height()
+    )?;
+
+    for y in (0..framebuffer.height()).rev() {
+        for x in 0..framebuffer.width() {
+            for channel in framebuffer.get_pixel(x, y).0.iter() {
+                writer.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bottom_up() {
+        let mut framebuffer = render::Framebuffer::new(1, 2);
+        framebuffer.put_pixel(0, 0, image::Rgb([1.0, 2.0, 3.0]));
+        framebuffer.put_pixel(0, 1, image::Rgb([4.0, 5.0, 6.0]));
+
+        let mut data = Vec::new();
+        write_pfm(&mut data, &framebuffer).unwrap();
+
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&header[..], &data[..header.len()]);
+        assert_eq!(header.len() + 24, data.len());
+
+        let first = &data[header.len()..header.len() + 4];
+        assert_eq!(&4.0f32.to_le_bytes(), first);
+    }
+}
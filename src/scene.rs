@@ -1,51 +1,593 @@
 use super::linear;
+use rand::rngs::SmallRng;
+use rand::Rng;
 use std::f64;
+use std::f64::consts;
 
+pub mod bvh;
+pub mod instance;
 pub mod lighting;
+pub mod material;
 pub mod primitive;
 
+// Shadow rays stop just short of a sampled point on an emissive triangle so the
+// triangle itself doesn't occlude it
+const EMITTER_OFFSET: f64 = 1e-4;
+
+// Emissive primitives of every instance, with a running total of their power
+// used to pick one with probability proportional to its share of the total.
+struct MeshLights {
+    objects: Vec<(usize, usize)>,
+    cumulative_power: Vec<f64>,
+}
+
+impl MeshLights {
+    fn new(
+        materials: &[Box<dyn material::Material>],
+        meshes: &[instance::Mesh],
+        instances: &[instance::Instance],
+    ) -> MeshLights {
+        let mut lights = MeshLights {
+            objects: Vec::new(),
+            cumulative_power: Vec::new(),
+        };
+
+        let mut total = 0.0;
+        for (instance_index, instance) in instances.iter().enumerate() {
+            for (index, object) in meshes[instance.mesh].objects().iter().enumerate() {
+                let material = &materials[instance.material_id(object.as_ref())];
+                if !material.is_emissive() {
+                    continue;
+                }
+
+                // Infinite planes can't be sampled. Moving lights are picked by
+                // their size at time zero, which only matches how much light
+                // they give off at the time of a ray if they don't change size.
+                // Light samples are still weighted by the probability of the
+                // pick and the area at the ray's time, so the estimate of the
+                // light isn't biased, just noisier.
+                let power = instance.area(object.as_ref(), 0.0) * material.emission().average();
+                if power <= 0.0 || !power.is_finite() {
+                    continue;
+                }
+
+                total += power;
+                lights.objects.push((instance_index, index));
+                lights.cumulative_power.push(total);
+            }
+        }
+
+        lights
+    }
+
+    fn total_power(&self) -> f64 {
+        *self.cumulative_power.last().unwrap_or(&0.0)
+    }
+
+    // Returns a randomly chosen emissive object, as instance and object indices,
+    // and the probability of picking it
+    fn pick(&self, rng: &mut SmallRng) -> ((usize, usize), f64) {
+        let total = self.total_power();
+        let target = rng.gen::<f64>() * total;
+
+        let index = self
+            .cumulative_power
+            .iter()
+            .position(|&power| target < power)
+            .unwrap_or(self.objects.len() - 1);
+
+        let previous = match index {
+            0 => 0.0,
+            _ => self.cumulative_power[index - 1],
+        };
+
+        (
+            self.objects[index],
+            (self.cumulative_power[index] - previous) / total,
+        )
+    }
+}
+
 pub struct Scene {
-    pub materials: Vec<lighting::Material>,
-    pub objects: Vec<primitive::Triangle>,
+    pub materials: Vec<Box<dyn material::Material>>,
+    pub meshes: Vec<instance::Mesh>,
+    pub instances: Vec<instance::Instance>,
     pub lights: Vec<lighting::LightSource>,
     pub ambient_light: lighting::Color,
+    hierarchy: bvh::BoundingVolumeHierarchy,
+    mesh_lights: MeshLights,
+
+    // Shadow rays cast towards emissive geometry from each shaded point
+    mesh_light_samples: u32,
+
+    // Culling of each material, looked up for every primitive tested
+    culling: Vec<primitive::Culling>,
+
+    // Indices of the instances in the hierarchy, and of those without finite
+    // bounds which are checked separately
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
 }
 
 impl Scene {
     pub fn new(
-        materials: Vec<lighting::Material>,
-        objects: Vec<primitive::Triangle>,
+        materials: Vec<Box<dyn material::Material>>,
+        meshes: Vec<instance::Mesh>,
+        instances: Vec<instance::Instance>,
         lights: Vec<lighting::LightSource>,
+        mesh_light_samples: u32,
     ) -> Scene {
         let ambient_light = lighting::LightSource::calculate_ambient(&lights);
 
+        let bounds = instances
+            .iter()
+            .map(|instance| instance.bounds(&meshes[instance.mesh]))
+            .collect::<Vec<_>>();
+
+        let (bounded, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..instances.len()).partition(|&index| bounds[index].is_finite());
+        let hierarchy = bvh::BoundingVolumeHierarchy::new(
+            &bounded
+                .iter()
+                .map(|&index| bounds[index])
+                .collect::<Vec<_>>(),
+        );
+
+        let culling = materials
+            .iter()
+            .map(|material| material.culling())
+            .collect::<Vec<_>>();
+        let mesh_lights = MeshLights::new(&materials, &meshes, &instances);
+
         Scene {
             materials,
-            objects,
+            meshes,
+            instances,
             lights,
             ambient_light,
+            hierarchy,
+            mesh_lights,
+            mesh_light_samples,
+            culling,
+            bounded,
+            unbounded,
+        }
+    }
+
+    // Whether shadow rays are cast towards emissive geometry, which otherwise
+    // only lights what paths happen to find
+    pub fn samples_emitters(&self) -> bool {
+        self.mesh_light_samples > 0
+    }
+
+    fn culling(
+        &self,
+        instance: &instance::Instance,
+        object: &dyn primitive::Primitive,
+    ) -> primitive::Culling {
+        instance
+            .culling
+            .unwrap_or(self.culling[instance.material_id(object)])
+    }
+
+    // Alpha tested textures cut holes in surfaces, which rays pass through
+    fn is_cut_out(
+        &self,
+        instance: &instance::Instance,
+        object: &dyn primitive::Primitive,
+        b: f64,
+        c: f64,
+    ) -> bool {
+        object.has_texture()
+            && self.materials[instance.material_id(object)].is_cut_out(object.uv(b, c))
+    }
+
+    // Nearest hit on one instance, as its distance, and the index and surface
+    // coordinates of the primitive hit
+    fn intersect_instance(
+        &self,
+        instance_index: usize,
+        ray: &linear::Ray,
+    ) -> Option<(f64, (usize, f64, f64))> {
+        let instance = &self.instances[instance_index];
+        let objects = self.meshes[instance.mesh].objects();
+        let object_ray = instance.object_ray(ray);
+
+        self.meshes[instance.mesh]
+            .hierarchy()
+            .nearest_hit(&object_ray, |index| {
+                let object = objects[index].as_ref();
+                let (intersected, t, b, c) =
+                    object.intersect(&object_ray, f64::MAX, self.culling(instance, object));
+                if intersected && !self.is_cut_out(instance, object, b, c) {
+                    Some((t, (b, c)))
+                } else {
+                    None
+                }
+            })
+            .map(|(index, t, (b, c))| (t, (index, b, c)))
+    }
+
+    fn occludes(&self, instance_index: usize, ray: &linear::Ray, max_distance: f64) -> bool {
+        let instance = &self.instances[instance_index];
+        let objects = self.meshes[instance.mesh].objects();
+        let object_ray = instance.object_ray(ray);
+
+        self.meshes[instance.mesh]
+            .hierarchy()
+            .any_hit(&object_ray, max_distance, |index| {
+                let object = objects[index].as_ref();
+                let (intersected, _, b, c) =
+                    object.intersect(&object_ray, max_distance, self.culling(instance, object));
+                intersected && !self.is_cut_out(instance, object, b, c)
+            })
+    }
+
+    pub fn find_intersection<'a>(&'a self, ray: &linear::Ray) -> Option<instance::Hit<'a>> {
+        let mut closest = self
+            .hierarchy
+            .nearest_hit(ray, |index| {
+                self.intersect_instance(self.bounded[index], ray)
+            })
+            .map(|(index, t, data)| (self.bounded[index], t, data));
+
+        // Ties go to the lowest instance index, as they do in the hierarchy
+        for &instance_index in &self.unbounded {
+            if let Some((t, data)) = self.intersect_instance(instance_index, ray) {
+                let closer = match closest {
+                    None => true,
+                    Some((closest_index, closest_t, _)) => {
+                        t < closest_t || (t == closest_t && instance_index < closest_index)
+                    }
+                };
+
+                if closer {
+                    closest = Some((instance_index, t, data));
+                }
+            }
+        }
+
+        closest.map(|(instance_index, t, (index, b, c))| {
+            let instance = &self.instances[instance_index];
+
+            instance::Hit {
+                object: self.meshes[instance.mesh].objects()[index].as_ref(),
+                instance,
+                t,
+                b,
+                c,
+                time: ray.time,
+            }
+        })
+    }
+
+    pub fn is_occluded(&self, ray: &linear::Ray, max_distance: f64) -> bool {
+        self.hierarchy.any_hit(ray, max_distance, |index| {
+            self.occludes(self.bounded[index], ray, max_distance)
+        }) || self
+            .unbounded
+            .iter()
+            .any(|&instance_index| self.occludes(instance_index, ray, max_distance))
+    }
+
+    // Casts shadow rays from position to each light, returning a point light for
+    // every unoccluded sample. Area lights are split evenly across their samples.
+    // Emissive primitives are always lit physically.
+    pub fn visible_lights(
+        &self,
+        position: linear::Vector,
+        time: f64,
+        radiometry: lighting::Radiometry,
+        rng: &mut SmallRng,
+    ) -> Vec<lighting::LightSample> {
+        let mut visible_lights: Vec<lighting::LightSample> = Vec::new();
+        for light in &self.lights {
+            for _ in 0..light.sample_count() {
+                let sample = match light.sample(&position, radiometry, rng) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+
+                let light_ray = linear::Ray {
+                    position,
+                    direction: sample.offset,
+                    time,
+                };
+
+                if !self.is_occluded(&light_ray, sample.range) {
+                    visible_lights.push(sample);
+                }
+            }
+        }
+
+        self.sample_mesh_lights(position, time, rng, &mut visible_lights);
+
+        visible_lights
+    }
+
+    // Monte Carlo estimate of the light arriving from emissive primitives. Each
+    // visible sample becomes a light sample scaled so that, like the other lights,
+    // a diffuse surface facing it is lit by diffuse * light * cos(theta).
+    fn sample_mesh_lights(
+        &self,
+        position: linear::Vector,
+        time: f64,
+        rng: &mut SmallRng,
+        visible_lights: &mut Vec<lighting::LightSample>,
+    ) {
+        if self.mesh_lights.objects.is_empty() {
+            return;
+        }
+
+        for _ in 0..self.mesh_light_samples {
+            let ((instance_index, index), probability) = self.mesh_lights.pick(rng);
+            let instance = &self.instances[instance_index];
+            let object = self.meshes[instance.mesh].objects()[index].as_ref();
+
+            let (b, c) = object.sample(rng.gen(), rng.gen());
+            let sample = instance.point(&object.point_at(b, c), time);
+
+            let direction = sample.subtract(&position);
+            let distance_squared = direction.dot(&direction);
+            if distance_squared == 0.0 {
+                continue;
+            }
+
+            // Surfaces emit from the sides of them rays can hit
+            let normal = instance.normal(&object.geometric_normal(b, c), time);
+            let cos_emitter = -direction.dot(&normal) / distance_squared.sqrt();
+            if cos_emitter == 0.0 || !self.culling(instance, object).keeps(cos_emitter > 0.0) {
+                continue;
+            }
+            let cos_emitter = cos_emitter.abs();
+
+            let light_ray = linear::Ray {
+                position,
+                direction,
+                time,
+            };
+
+            if self.is_occluded(&light_ray, 1.0 - EMITTER_OFFSET) {
+                continue;
+            }
+
+            let pdf = probability / instance.area(object, time);
+            let scale = cos_emitter
+                / (consts::PI * distance_squared * pdf * (self.mesh_light_samples as f64));
+
+            let emission = self.materials[instance.material_id(object)].emission();
+            visible_lights.push(lighting::LightSample::emitter(
+                direction,
+                1.0 - EMITTER_OFFSET,
+                emission.scale(scale),
+            ));
         }
     }
 
-    pub fn find_intersection<'a>(
+    // Brute force search over every object, kept to check the hierarchy against
+    #[cfg(test)]
+    pub fn find_intersection_linear<'a>(
         &'a self,
         ray: &linear::Ray,
-    ) -> (Option<&'a primitive::Triangle>, f64, f64, f64) {
-        let mut t = f64::MAX;
-        let mut intersection = Option::None;
-        let mut a = 0.0;
-        let mut b = 0.0;
-
-        self.objects.iter().for_each(|object| {
-            let (intersected, temp_t, temp_a, temp_b) = object.intersect(ray, t);
-            if intersected {
-                intersection = Some(object);
-                t = temp_t;
-                a = temp_a;
-                b = temp_b;
-            }
-        });
-
-        (intersection, t, a, b)
+    ) -> Option<instance::Hit<'a>> {
+        let mut closest: Option<instance::Hit<'a>> = None;
+
+        for instance in &self.instances {
+            let object_ray = instance.object_ray(ray);
+
+            for object in self.meshes[instance.mesh].objects() {
+                let object = object.as_ref();
+                let max_range = closest.as_ref().map_or(f64::MAX, |hit| hit.t);
+                let (intersected, t, b, c) =
+                    object.intersect(&object_ray, max_range, self.culling(instance, object));
+                if intersected && !self.is_cut_out(instance, object, b, c) {
+                    closest = Some(instance::Hit {
+                        object,
+                        instance,
+                        t,
+                        b,
+                        c,
+                        time: ray.time,
+                    });
+                }
+            }
+        }
+
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use approx::assert_abs_diff_eq;
+    use rand::SeedableRng;
+
+    // A two by two square, one unit above the origin and facing down
+    fn quad() -> instance::Mesh {
+        let corner = |x, z| linear::Vector::new(x, 1.0, z);
+
+        instance::Mesh::new(vec![
+            Box::new(primitive::Triangle::new(
+                corner(-1.0, -1.0),
+                corner(1.0, -1.0),
+                corner(1.0, 1.0),
+                0,
+                None,
+                None,
+            )),
+            Box::new(primitive::Triangle::new(
+                corner(-1.0, -1.0),
+                corner(1.0, 1.0),
+                corner(-1.0, 1.0),
+                0,
+                None,
+                None,
+            )),
+        ])
+    }
+
+    fn material(albedo: f64, emission: f64) -> Box<dyn material::Material> {
+        config::MaterialConfig {
+            diffuse: lighting::Color::new(albedo, albedo, albedo),
+            specular: lighting::Color::black(),
+            emission: lighting::Color::new(emission, emission, emission),
+            ..config::MaterialConfig::default()
+        }
+        .to_material()
+    }
+
+    fn place(mesh: usize, material_offset: usize, translate: linear::Vector) -> instance::Instance {
+        let transform = config::TransformConfig {
+            translate,
+            ..config::TransformConfig::default()
+        };
+
+        instance::Instance::new(
+            mesh,
+            material_offset,
+            None,
+            transform.to_transform().unwrap(),
+        )
+    }
+
+    #[test]
+    fn mesh_light_selection() {
+        // Two quads glowing with different strengths, and a glowing plane and a
+        // dark quad which can't be picked
+        let materials = vec![material(0.5, 0.0), material(0.5, 1.0), material(0.5, 3.0)];
+        let meshes = vec![
+            quad(),
+            instance::Mesh::new(vec![Box::new(primitive::Plane::new(0))]),
+        ];
+        let origin = linear::Vector::new(0.0, 0.0, 0.0);
+        let instances = vec![
+            place(0, 0, origin),
+            place(0, 1, origin),
+            place(1, 1, origin),
+            place(0, 2, linear::Vector::new(5.0, 0.0, 0.0)),
+        ];
+
+        let lights = MeshLights::new(&materials, &meshes, &instances);
+        assert_eq!(vec![(1, 0), (1, 1), (3, 0), (3, 1)], lights.objects);
+        assert_abs_diff_eq!(16.0, lights.total_power());
+
+        // Triangles are picked in proportion to their power
+        let expected = [0.125, 0.125, 0.375, 0.375];
+        let mut counts = [0; 4];
+        let mut rng = SmallRng::seed_from_u64(3);
+
+        let picks = 40000;
+        for _ in 0..picks {
+            let (object, probability) = lights.pick(&mut rng);
+            let index = lights.objects.iter().position(|&o| o == object).unwrap();
+            assert_abs_diff_eq!(expected[index], probability);
+            counts[index] += 1;
+        }
+
+        for (count, probability) in counts.iter().zip(expected.iter()) {
+            assert_abs_diff_eq!(*probability, *count as f64 / picks as f64, epsilon = 0.01);
+        }
+    }
+
+    #[test]
+    fn emissive_quad() {
+        let scene = Scene::new(
+            vec![material(0.5, 0.0), material(0.0, 1.0)],
+            vec![
+                instance::Mesh::new(vec![Box::new(primitive::Plane::new(0))]),
+                quad(),
+            ],
+            vec![
+                place(0, 0, linear::Vector::new(0.0, 0.0, 0.0)),
+                place(1, 1, linear::Vector::new(0.0, 0.0, 0.0)),
+            ],
+            Vec::new(),
+            16,
+        );
+
+        let mut rng = SmallRng::seed_from_u64(5);
+        let normal = linear::Vector::new(0.0, 1.0, 0.0);
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 0.0),
+            direction: normal.negative(),
+            time: 0.0,
+        };
+
+        let frames = 4000;
+        let mut total = 0.0;
+        for _ in 0..frames {
+            let lights =
+                scene.visible_lights(ray.position, 0.0, lighting::Radiometry::Physical, &mut rng);
+            assert_eq!(16, lights.len());
+            assert!(lights.iter().all(|light| light.emitter));
+
+            total += scene.materials[0]
+                .direct(&lights, &ray, None, normal, 1.0)
+                .average();
+        }
+
+        // A diffuse surface reflects albedo times the form factor of the quad,
+        // made up of four squares with a corner above the origin
+        let side = 1.0_f64 / 2.0_f64.sqrt();
+        let corner = side * side.atan() / consts::PI;
+        assert_abs_diff_eq!(0.5 * 4.0 * corner, total / frames as f64, epsilon = 0.003);
+    }
+
+    #[test]
+    fn moving_emitter() {
+        // A quad doubling in size over time, which keeps the same form factor
+        // from the origin as it rises, and a distant quad of the same power at
+        // time zero
+        let grow = (0..2)
+            .map(|time| config::TransformKeyframe {
+                time: time as f64,
+                transform: config::TransformConfig {
+                    scale: linear::Vector::new(1.0, 1.0, 1.0).scale(time as f64 + 1.0),
+                    ..config::TransformConfig::default()
+                },
+            })
+            .collect::<Vec<_>>();
+        let growing =
+            instance::Instance::animated(1, 1, None, config::to_animated_transform(&grow).unwrap());
+
+        let scene = Scene::new(
+            vec![material(0.5, 0.0), material(0.0, 1.0)],
+            vec![
+                instance::Mesh::new(vec![Box::new(primitive::Plane::new(0))]),
+                quad(),
+            ],
+            vec![
+                place(0, 0, linear::Vector::new(0.0, 0.0, 0.0)),
+                growing,
+                place(1, 1, linear::Vector::new(100.0, 0.0, 0.0)),
+            ],
+            Vec::new(),
+            16,
+        );
+
+        let mut rng = SmallRng::seed_from_u64(6);
+        let normal = linear::Vector::new(0.0, 1.0, 0.0);
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 0.0),
+            direction: normal.negative(),
+            time: 1.0,
+        };
+
+        // Picking the quads by their power at time zero doesn't bias the light
+        let frames = 4000;
+        let mut total = 0.0;
+        for _ in 0..frames {
+            let lights =
+                scene.visible_lights(ray.position, 1.0, lighting::Radiometry::Physical, &mut rng);
+            total += scene.materials[0]
+                .direct(&lights, &ray, None, normal, 1.0)
+                .average();
+        }
+
+        let side = 1.0_f64 / 2.0_f64.sqrt();
+        let corner = side * side.atan() / consts::PI;
+        assert_abs_diff_eq!(0.5 * 4.0 * corner, total / frames as f64, epsilon = 0.005);
     }
 }
@@ -0,0 +1,700 @@
+use super::linear;
+
+const BIN_COUNT: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f64 = 1.0;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BoundingBox {
+    pub min: linear::Vector,
+    pub max: linear::Vector,
+}
+
+impl BoundingBox {
+    pub fn empty() -> BoundingBox {
+        BoundingBox {
+            min: linear::Vector::new(f64::MAX, f64::MAX, f64::MAX),
+            max: linear::Vector::new(f64::MIN, f64::MIN, f64::MIN),
+        }
+    }
+
+    // Covers all of space, for shapes such as planes which go on forever
+    pub fn infinite() -> BoundingBox {
+        BoundingBox {
+            min: linear::Vector::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            max: linear::Vector::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[linear::Vector]) -> BoundingBox {
+        points
+            .iter()
+            .fold(BoundingBox::empty(), |bounds, point| bounds.include(point))
+    }
+
+    pub fn include(&self, point: &linear::Vector) -> BoundingBox {
+        BoundingBox {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        (0..3).all(|axis| {
+            self.min.component(axis).is_finite() && self.max.component(axis).is_finite()
+        })
+    }
+
+    pub fn centroid(&self) -> linear::Vector {
+        self.min.add(&self.max).scale(0.5)
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let extent = self.max.subtract(&self.min);
+        let (x, y, z) = (
+            extent.component(0),
+            extent.component(1),
+            extent.component(2),
+        );
+        if x < 0.0 || y < 0.0 || z < 0.0 {
+            return 0.0;
+        }
+
+        2.0 * (x * y + y * z + z * x)
+    }
+
+    fn largest_axis(&self) -> usize {
+        let extent = self.max.subtract(&self.min);
+        if extent.component(0) >= extent.component(1) && extent.component(0) >= extent.component(2)
+        {
+            0
+        } else if extent.component(1) >= extent.component(2) {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Slab test, returns distance along the ray at which the box is entered.
+    // NaNs produced by axis-parallel rays lying in a slab plane are ignored by
+    // f64::min/max, which keeps the test conservative.
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        inverse_direction: &linear::Vector,
+        max_range: f64,
+    ) -> Option<f64> {
+        let mut near = 0.0_f64;
+        let mut far = max_range;
+
+        for axis in 0..3 {
+            let origin = ray.position.component(axis);
+            let inverse = inverse_direction.component(axis);
+
+            let t1 = (self.min.component(axis) - origin) * inverse;
+            let t2 = (self.max.component(axis) - origin) * inverse;
+
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+}
+
+enum Node {
+    Leaf {
+        bounds: BoundingBox,
+        start: usize,
+        count: usize,
+    },
+    Interior {
+        bounds: BoundingBox,
+        second_child: usize,
+        axis: usize,
+    },
+}
+
+impl Node {
+    fn bounds(&self) -> &BoundingBox {
+        match self {
+            Node::Leaf { bounds, .. } => bounds,
+            Node::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+struct BuildEntry {
+    index: usize,
+    bounds: BoundingBox,
+    centroid: linear::Vector,
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds: BoundingBox,
+    count: usize,
+}
+
+pub struct BoundingVolumeHierarchy {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+}
+
+impl BoundingVolumeHierarchy {
+    pub fn new(bounds: &[BoundingBox]) -> BoundingVolumeHierarchy {
+        let mut entries = bounds
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| BuildEntry {
+                index,
+                bounds: *bounds,
+                centroid: bounds.centroid(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut hierarchy = BoundingVolumeHierarchy {
+            nodes: Vec::new(),
+            indices: Vec::with_capacity(bounds.len()),
+        };
+
+        if !entries.is_empty() {
+            hierarchy.build(&mut entries[..]);
+        }
+
+        hierarchy
+    }
+
+    fn build(&mut self, entries: &mut [BuildEntry]) -> usize {
+        let bounds = entries.iter().fold(BoundingBox::empty(), |bounds, entry| {
+            bounds.union(&entry.bounds)
+        });
+
+        let node_index = self.nodes.len();
+
+        match Self::find_split(entries, &bounds) {
+            None => {
+                self.nodes.push(Node::Leaf {
+                    bounds,
+                    start: self.indices.len(),
+                    count: entries.len(),
+                });
+                self.indices.extend(entries.iter().map(|entry| entry.index));
+            }
+            Some((axis, split)) => {
+                // Placeholder, replaced once the second child's index is known
+                self.nodes.push(Node::Leaf {
+                    bounds,
+                    start: 0,
+                    count: 0,
+                });
+
+                let (first, second) = entries.split_at_mut(split);
+                self.build(first);
+                let second_child = self.build(second);
+
+                self.nodes[node_index] = Node::Interior {
+                    bounds,
+                    second_child,
+                    axis,
+                };
+            }
+        }
+
+        node_index
+    }
+
+    // Binned surface area heuristic. Partitions `entries` in place and returns the
+    // split axis and partition point, or None if a leaf is cheaper.
+    fn find_split(entries: &mut [BuildEntry], bounds: &BoundingBox) -> Option<(usize, usize)> {
+        let count = entries.len();
+        if count <= 1 {
+            return None;
+        }
+
+        let centroid_bounds = entries.iter().fold(BoundingBox::empty(), |bounds, entry| {
+            bounds.include(&entry.centroid)
+        });
+
+        let axis = centroid_bounds.largest_axis();
+        let low = centroid_bounds.min.component(axis);
+        let high = centroid_bounds.max.component(axis);
+
+        if high <= low {
+            if count <= MAX_LEAF_SIZE {
+                return None;
+            }
+
+            // Every centroid coincides, so fall back to splitting the list in half
+            return Some((axis, count / 2));
+        }
+
+        let bin_of = |entry: &BuildEntry| {
+            let offset = (entry.centroid.component(axis) - low) / (high - low);
+            ((offset * BIN_COUNT as f64) as usize).min(BIN_COUNT - 1)
+        };
+
+        let mut bins = [Bin {
+            bounds: BoundingBox::empty(),
+            count: 0,
+        }; BIN_COUNT];
+
+        for entry in entries.iter() {
+            let bin = &mut bins[bin_of(entry)];
+            bin.bounds = bin.bounds.union(&entry.bounds);
+            bin.count += 1;
+        }
+
+        let parent_area = bounds.surface_area();
+        let mut best_cost = f64::MAX;
+        let mut best_bin = 0;
+
+        for split in 1..BIN_COUNT {
+            let (left, right) = bins.split_at(split);
+            let merge = |bins: &[Bin]| {
+                bins.iter()
+                    .fold((BoundingBox::empty(), 0), |(bounds, count), bin| {
+                        (bounds.union(&bin.bounds), count + bin.count)
+                    })
+            };
+
+            let (left_bounds, left_count) = merge(left);
+            let (right_bounds, right_count) = merge(right);
+
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + (left_bounds.surface_area() * left_count as f64
+                    + right_bounds.surface_area() * right_count as f64)
+                    / parent_area;
+
+            if cost < best_cost {
+                best_cost = cost;
+                best_bin = split;
+            }
+        }
+
+        if best_bin == 0 || (count <= MAX_LEAF_SIZE && best_cost >= count as f64) {
+            return None;
+        }
+
+        let mut split = 0;
+        for i in 0..count {
+            if bin_of(&entries[i]) < best_bin {
+                entries.swap(i, split);
+                split += 1;
+            }
+        }
+
+        Some((axis, split))
+    }
+
+    // Finds the closest primitive accepted by `intersect`, which returns the hit
+    // distance and any extra hit data. Ties are resolved in favour of the lowest
+    // primitive index, so results match a linear scan over the primitives.
+    pub fn nearest_hit<T, F>(&self, ray: &linear::Ray, mut intersect: F) -> Option<(usize, f64, T)>
+    where
+        F: FnMut(usize) -> Option<(f64, T)>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inverse_direction = inverse(&ray.direction);
+        let mut closest: Option<(usize, f64, T)> = None;
+        let mut max_range = f64::MAX;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node
+                .bounds()
+                .intersect(ray, &inverse_direction, max_range)
+                .is_none()
+            {
+                continue;
+            }
+
+            match node {
+                Node::Leaf { start, count, .. } => {
+                    for &index in &self.indices[*start..(*start + *count)] {
+                        if let Some((t, data)) = intersect(index) {
+                            let closer = match &closest {
+                                None => t < max_range,
+                                Some((closest_index, closest_t, _)) => {
+                                    t < *closest_t || (t == *closest_t && index < *closest_index)
+                                }
+                            };
+
+                            if closer {
+                                max_range = t;
+                                closest = Some((index, t, data));
+                            }
+                        }
+                    }
+                }
+                Node::Interior {
+                    second_child, axis, ..
+                } => {
+                    // Visit the child nearer the ray origin first
+                    if ray.direction.component(*axis) < 0.0 {
+                        stack.push(node_index + 1);
+                        stack.push(*second_child);
+                    } else {
+                        stack.push(*second_child);
+                        stack.push(node_index + 1);
+                    }
+                }
+            }
+        }
+
+        closest
+    }
+
+    // Returns true as soon as `intersect` accepts any primitive whose bounds the
+    // ray enters before `max_range`.
+    pub fn any_hit<F>(&self, ray: &linear::Ray, max_range: f64, mut intersect: F) -> bool
+    where
+        F: FnMut(usize) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inverse_direction = inverse(&ray.direction);
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node
+                .bounds()
+                .intersect(ray, &inverse_direction, max_range)
+                .is_none()
+            {
+                continue;
+            }
+
+            match node {
+                Node::Leaf { start, count, .. } => {
+                    if self.indices[*start..(*start + *count)]
+                        .iter()
+                        .any(|&index| intersect(index))
+                    {
+                        return true;
+                    }
+                }
+                Node::Interior { second_child, .. } => {
+                    stack.push(*second_child);
+                    stack.push(node_index + 1);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+fn inverse(direction: &linear::Vector) -> linear::Vector {
+    linear::Vector::new(
+        1.0 / direction.component(0),
+        1.0 / direction.component(1),
+        1.0 / direction.component(2),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use super::super::instance;
+    use super::super::primitive;
+    use super::super::Scene;
+    use super::*;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> f64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 11) as f64) / ((1u64 << 53) as f64)
+        }
+
+        fn vector(&mut self, scale: f64) -> linear::Vector {
+            linear::Vector::new(
+                scale * (2.0 * self.next() - 1.0),
+                scale * (2.0 * self.next() - 1.0),
+                scale * (2.0 * self.next() - 1.0),
+            )
+        }
+    }
+
+    fn random_mesh(random: &mut Random, count: usize) -> instance::Mesh {
+        let mut objects: Vec<Box<dyn primitive::Primitive>> = Vec::new();
+        for _ in 0..count {
+            let a = random.vector(10.0);
+            let b = a.add(&random.vector(1.0));
+            let c = a.add(&random.vector(1.0));
+            objects.push(Box::new(primitive::Triangle::new(a, b, c, 0, None, None)));
+        }
+
+        instance::Mesh::new(objects)
+    }
+
+    fn random_scene(random: &mut Random, count: usize) -> Scene {
+        let materials = vec![config::MaterialConfig::default().to_material()];
+        let meshes = vec![random_mesh(random, count)];
+        let instances = vec![instance::Instance::new(
+            0,
+            0,
+            None,
+            config::TransformConfig::default().to_transform().unwrap(),
+        )];
+
+        Scene::new(materials, meshes, instances, Vec::new(), 0)
+    }
+
+    // Copies of a few meshes, moved, rotated, scaled and mirrored, some of
+    // them over time
+    fn random_instanced_scene(random: &mut Random, count: usize) -> Scene {
+        let materials = vec![config::MaterialConfig::default().to_material()];
+        let meshes = (0..3)
+            .map(|_| random_mesh(random, count / 3))
+            .collect::<Vec<_>>();
+
+        let mut random_transform = || config::TransformConfig {
+            translate: random.vector(10.0),
+            rotate: random.vector(180.0),
+            scale: random.vector(2.0),
+        };
+
+        // Every fourth instance moves, keeping the signs of its scale
+        let instances = (0..12)
+            .map(|index| {
+                let transform = random_transform();
+                if index % 4 != 3 {
+                    let transform = transform.to_transform().unwrap();
+                    return instance::Instance::new(index % 3, 0, None, transform);
+                }
+
+                let keyframes = (0..3)
+                    .map(|key| {
+                        let mut keyframe = random_transform();
+                        let scale = |axis: usize| {
+                            let scale = keyframe.scale.component(axis).abs().max(0.1);
+                            scale.copysign(transform.scale.component(axis))
+                        };
+                        keyframe.scale = linear::Vector::new(scale(0), scale(1), scale(2));
+
+                        config::TransformKeyframe {
+                            time: key as f64 * 0.4,
+                            transform: keyframe,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                let motion = config::to_animated_transform(&keyframes).unwrap();
+                instance::Instance::animated(index % 3, 0, None, motion)
+            })
+            .collect::<Vec<_>>();
+
+        Scene::new(materials, meshes, instances, Vec::new(), 0)
+    }
+
+    // Analytic shapes, including a plane outside the hierarchy, with each kind
+    // of culling
+    fn random_primitive_scene(random: &mut Random, count: usize) -> Scene {
+        let materials = vec![config::MaterialConfig::default().to_material()];
+        let shapes = [
+            config::PrimitiveShape::Sphere { radius: 1.0 },
+            config::PrimitiveShape::Box {
+                min: linear::Vector::new(-1.0, -0.5, 0.0),
+                max: linear::Vector::new(1.0, 0.5, 2.0),
+            },
+            config::PrimitiveShape::Cylinder {
+                radius: 0.5,
+                height: 2.0,
+            },
+            config::PrimitiveShape::Cone {
+                radius: 1.0,
+                height: 1.5,
+            },
+            config::PrimitiveShape::Disk { radius: 1.0 },
+        ];
+        let culling = [
+            None,
+            Some(primitive::Culling::Front),
+            Some(primitive::Culling::TwoSided),
+        ];
+
+        let mut meshes = vec![instance::Mesh::new(vec![
+            config::PrimitiveShape::Plane.to_primitive(0)
+        ])];
+        let mut instances = vec![instance::Instance::new(
+            0,
+            0,
+            None,
+            config::TransformConfig {
+                rotate: linear::Vector::new(10.0, 0.0, 5.0),
+                translate: linear::Vector::new(0.0, -12.0, 0.0),
+                ..config::TransformConfig::default()
+            }
+            .to_transform()
+            .unwrap(),
+        )];
+
+        for index in 0..count {
+            let transform = config::TransformConfig {
+                translate: random.vector(10.0),
+                rotate: random.vector(180.0),
+                scale: random.vector(2.0),
+            };
+
+            meshes.push(instance::Mesh::new(vec![
+                shapes[index % shapes.len()].to_primitive(0)
+            ]));
+            instances.push(instance::Instance::new(
+                meshes.len() - 1,
+                0,
+                culling[index % culling.len()],
+                transform.to_transform().unwrap(),
+            ));
+        }
+
+        Scene::new(materials, meshes, instances, Vec::new(), 0)
+    }
+
+    #[test]
+    fn bounding_box() {
+        let bounds = BoundingBox::from_points(&[
+            linear::Vector::new(1.0, -2.0, 0.0),
+            linear::Vector::new(-1.0, 3.0, 2.0),
+        ]);
+
+        assert!(bounds.min.equals(&linear::Vector::new(-1.0, -2.0, 0.0)));
+        assert!(bounds.max.equals(&linear::Vector::new(1.0, 3.0, 2.0)));
+        approx::assert_ulps_eq!(
+            2.0 * (2.0 * 5.0 + 5.0 * 2.0 + 2.0 * 2.0),
+            bounds.surface_area()
+        );
+
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, -5.0),
+            direction: linear::Vector::new(0.0, 0.0, 1.0),
+            time: 0.0,
+        };
+        let t = bounds.intersect(&ray, &inverse(&ray.direction), f64::MAX);
+        approx::assert_ulps_eq!(5.0, t.unwrap());
+
+        let ray = linear::Ray {
+            position: linear::Vector::new(5.0, 0.0, -5.0),
+            direction: linear::Vector::new(0.0, 0.0, 1.0),
+            time: 0.0,
+        };
+        assert!(bounds
+            .intersect(&ray, &inverse(&ray.direction), f64::MAX)
+            .is_none());
+    }
+
+    fn check_against_linear_scan(random: &mut Random, scene: &Scene) {
+        for _ in 0..2000 {
+            let ray = linear::Ray {
+                position: random.vector(15.0),
+                direction: random.vector(1.0),
+                time: random.next(),
+            };
+
+            let expected = scene.find_intersection_linear(&ray);
+            let actual = scene.find_intersection(&ray);
+
+            assert_eq!(
+                expected
+                    .as_ref()
+                    .map(|hit| (hit.object as *const _, hit.instance as *const _)),
+                actual
+                    .as_ref()
+                    .map(|hit| (hit.object as *const _, hit.instance as *const _))
+            );
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                assert_eq!(expected.t, actual.t);
+                assert_eq!(expected.b, actual.b);
+                assert_eq!(expected.c, actual.c);
+            }
+
+            let shadow_ray = linear::Ray {
+                position: ray.position,
+                direction: random.vector(20.0),
+                time: ray.time,
+            };
+            let distance = scene
+                .find_intersection_linear(&shadow_ray)
+                .map_or(f64::MAX, |hit| hit.t);
+            assert_eq!(distance < 1.0, scene.is_occluded(&shadow_ray, 1.0));
+        }
+    }
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut random = Random(7);
+        let scene = random_scene(&mut random, 2000);
+
+        check_against_linear_scan(&mut random, &scene);
+    }
+
+    #[test]
+    fn instances_match_linear_scan() {
+        let mut random = Random(11);
+        let scene = random_instanced_scene(&mut random, 600);
+
+        check_against_linear_scan(&mut random, &scene);
+    }
+
+    #[test]
+    fn primitives_match_linear_scan() {
+        let mut random = Random(13);
+        let scene = random_primitive_scene(&mut random, 60);
+
+        check_against_linear_scan(&mut random, &scene);
+
+        // Rays missing everything else still reach the plane
+        let ray = linear::Ray {
+            position: linear::Vector::new(100.0, 0.0, 100.0),
+            direction: linear::Vector::new(0.0, -1.0, 0.0),
+            time: 0.0,
+        };
+        assert!(scene.find_intersection(&ray).is_some());
+        assert!(scene.is_occluded(&ray, f64::MAX));
+    }
+
+    #[test]
+    fn empty_scene() {
+        let mut random = Random(3);
+        let scene = random_scene(&mut random, 0);
+        let ray = linear::Ray {
+            position: random.vector(1.0),
+            direction: random.vector(1.0),
+            time: 0.0,
+        };
+
+        assert!(scene.find_intersection(&ray).is_none());
+        assert!(!scene.is_occluded(&ray, 1.0));
+    }
+}
@@ -0,0 +1,286 @@
+use super::bvh;
+use super::lighting;
+use super::linear;
+use super::primitive;
+
+// Primitives loaded from one model file, or a single analytic shape, with their
+// own hierarchy. Meshes are shared by every instance of the model.
+pub struct Mesh {
+    objects: Vec<Box<dyn primitive::Primitive>>,
+    hierarchy: bvh::BoundingVolumeHierarchy,
+    bounds: bvh::BoundingBox,
+}
+
+impl Mesh {
+    pub fn new(objects: Vec<Box<dyn primitive::Primitive>>) -> Mesh {
+        let bounds = objects
+            .iter()
+            .map(|object| object.bounds())
+            .collect::<Vec<_>>();
+        let hierarchy = bvh::BoundingVolumeHierarchy::new(&bounds);
+
+        let bounds = bounds
+            .iter()
+            .fold(bvh::BoundingBox::empty(), |total, bounds| {
+                total.union(bounds)
+            });
+
+        Mesh {
+            objects,
+            hierarchy,
+            bounds,
+        }
+    }
+
+    pub fn objects(&self) -> &[Box<dyn primitive::Primitive>] {
+        &self.objects
+    }
+
+    pub fn hierarchy(&self) -> &bvh::BoundingVolumeHierarchy {
+        &self.hierarchy
+    }
+}
+
+// A mesh placed in the scene. Rays are moved into the mesh's own coordinates
+// to be intersected, with their directions left unnormalized so that hit
+// distances are the same in both. Material ids of the mesh's triangles are
+// offset into the scene's material list, and the instance's culling, if any,
+// replaces that of the materials. Moving instances are placed at the time of
+// each ray.
+pub struct Instance {
+    pub mesh: usize,
+    pub material_offset: usize,
+    pub culling: Option<primitive::Culling>,
+    transform: linear::Transform,
+    motion: Option<linear::AnimatedTransform>,
+
+    // Instances without a transform skip it entirely
+    identity: bool,
+}
+
+impl Instance {
+    pub fn new(
+        mesh: usize,
+        material_offset: usize,
+        culling: Option<primitive::Culling>,
+        transform: linear::Transform,
+    ) -> Instance {
+        Instance {
+            mesh,
+            material_offset,
+            culling,
+            transform,
+            motion: None,
+            identity: transform.is_identity(),
+        }
+    }
+
+    pub fn animated(
+        mesh: usize,
+        material_offset: usize,
+        culling: Option<primitive::Culling>,
+        motion: linear::AnimatedTransform,
+    ) -> Instance {
+        Instance {
+            mesh,
+            material_offset,
+            culling,
+            transform: motion.at(motion.keyframes()[0].0),
+            motion: Some(motion),
+            identity: false,
+        }
+    }
+
+    // Unbounded meshes, such as planes, stay unbounded. Moving instances are
+    // bounded over all of their motion.
+    pub fn bounds(&self, mesh: &Mesh) -> bvh::BoundingBox {
+        if self.identity || mesh.objects.is_empty() {
+            return mesh.bounds;
+        }
+
+        if !mesh.bounds.is_finite() {
+            return bvh::BoundingBox::infinite();
+        }
+
+        let (min, max) = (mesh.bounds.min, mesh.bounds.max);
+        let corners = (0..8)
+            .map(|corner| {
+                let pick = |axis: usize| match corner & (1 << axis) {
+                    0 => min.component(axis),
+                    _ => max.component(axis),
+                };
+
+                linear::Vector::new(pick(0), pick(1), pick(2))
+            })
+            .collect::<Vec<_>>();
+
+        let transformed_corners = |transform: &linear::Transform| {
+            let points = corners
+                .iter()
+                .map(|corner| transform.point(corner))
+                .collect::<Vec<_>>();
+
+            bvh::BoundingBox::from_points(&points)
+        };
+
+        let motion = match &self.motion {
+            Some(motion) => motion,
+            None => return transformed_corners(&self.transform),
+        };
+
+        let keyframes = motion.keyframes();
+        let mut bounds = keyframes
+            .iter()
+            .fold(bvh::BoundingBox::empty(), |bounds, &(time, _)| {
+                bounds.union(&transformed_corners(&motion.at(time)))
+            });
+
+        // Without rotation every corner moves in a straight line between
+        // keyframes. Rotating corners stay within reach of the translation.
+        for pair in keyframes.windows(2) {
+            let ((_, start), (_, end)) = (pair[0], pair[1]);
+            if start.rotation == end.rotation {
+                continue;
+            }
+
+            let reach = corners
+                .iter()
+                .map(|corner| {
+                    let scaled = |axis: usize| {
+                        corner.component(axis)
+                            * start.scale.component(axis).abs().max(end.scale.component(axis).abs())
+                    };
+
+                    linear::Vector::new(scaled(0), scaled(1), scaled(2)).magnitude()
+                })
+                .fold(0.0, f64::max);
+            let reach = linear::Vector::new(reach, reach, reach);
+
+            bounds = bounds.union(&bvh::BoundingBox::from_points(&[
+                start.translate.min(&end.translate).subtract(&reach),
+                start.translate.max(&end.translate).add(&reach),
+            ]));
+        }
+
+        bounds
+    }
+
+    fn transform_at(&self, time: f64) -> linear::Transform {
+        match &self.motion {
+            Some(motion) => motion.at(time),
+            None => self.transform,
+        }
+    }
+
+    pub fn object_ray(&self, ray: &linear::Ray) -> linear::Ray {
+        match self.identity {
+            true => linear::Ray {
+                position: ray.position,
+                direction: ray.direction,
+                time: ray.time,
+            },
+            false => self.transform_at(ray.time).inverse().ray(ray),
+        }
+    }
+
+    pub fn point(&self, point: &linear::Vector, time: f64) -> linear::Vector {
+        match self.identity {
+            true => *point,
+            false => self.transform_at(time).point(point),
+        }
+    }
+
+    pub fn vector(&self, vector: &linear::Vector, time: f64) -> linear::Vector {
+        match self.identity {
+            true => *vector,
+            false => self.transform_at(time).vector(vector),
+        }
+    }
+
+    pub fn normal(&self, normal: &linear::Vector, time: f64) -> linear::Vector {
+        match self.identity {
+            true => *normal,
+            false => self.transform_at(time).normal(normal).normalize(),
+        }
+    }
+
+    pub fn area(&self, object: &dyn primitive::Primitive, time: f64) -> f64 {
+        match self.identity {
+            true => object.area(),
+            false => object.transformed_area(&self.transform_at(time)),
+        }
+    }
+
+    pub fn material_id(&self, object: &dyn primitive::Primitive) -> usize {
+        self.material_offset + object.material_id()
+    }
+}
+
+// Where a ray hit one of the primitives of an instance, with surface
+// coordinates b and c. Surface properties are given in world space, where the
+// instance was at the time of the ray.
+pub struct Hit<'a> {
+    pub object: &'a dyn primitive::Primitive,
+    pub instance: &'a Instance,
+    pub t: f64,
+    pub b: f64,
+    pub c: f64,
+    pub time: f64,
+}
+
+impl<'a> Hit<'a> {
+    pub fn material_id(&self) -> usize {
+        self.instance.material_id(self.object)
+    }
+
+    pub fn geometric_normal(&self) -> linear::Vector {
+        self.instance
+            .normal(&self.object.geometric_normal(self.b, self.c), self.time)
+    }
+
+    pub fn surface_normal(&self) -> linear::Vector {
+        self.instance
+            .normal(&self.object.surface_normal(self.b, self.c), self.time)
+    }
+
+    pub fn has_texture(&self) -> bool {
+        self.object.has_texture()
+    }
+
+    pub fn uv(&self) -> lighting::UV {
+        self.object.uv(self.b, self.c)
+    }
+
+    // Scaling the instance spreads the same texture over a larger area. Shapes
+    // without a finite area are measured by how far the instance stretches the
+    // texture's axes instead.
+    pub fn uv_density(&self) -> f64 {
+        if !self.object.area().is_finite() {
+            let uv_area = match self.uv_derivatives() {
+                Some((dpdu, dpdv)) => dpdu.cross(&dpdv).magnitude(),
+                None => return 0.0,
+            };
+
+            return match uv_area > 0.0 {
+                true => (1.0 / uv_area).sqrt(),
+                false => 0.0,
+            };
+        }
+
+        let area = self.instance.area(self.object, self.time);
+        if area == 0.0 {
+            return 0.0;
+        }
+
+        self.object.uv_density() * (self.object.area() / area).sqrt()
+    }
+
+    pub fn uv_derivatives(&self) -> Option<(linear::Vector, linear::Vector)> {
+        let (dpdu, dpdv) = self.object.uv_derivatives(self.b, self.c)?;
+
+        Some((
+            self.instance.vector(&dpdu, self.time),
+            self.instance.vector(&dpdv, self.time),
+        ))
+    }
+}
@@ -1,6 +1,10 @@
+use rand::rngs::SmallRng;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::f64::consts;
 
 use super::linear;
+use super::material;
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct UV {
@@ -26,7 +30,7 @@ impl Color {
         Color(red, green, blue)
     }
 
-    fn combine(coef: f64, first: Color, second: Color) -> Color {
+    pub fn combine(coef: f64, first: Color, second: Color) -> Color {
         Color(
             coef * first.0 * second.0,
             coef * first.1 * second.1,
@@ -34,234 +38,1070 @@ impl Color {
         )
     }
 
+    pub fn scale(&self, scale: f64) -> Color {
+        Color(scale * self.0, scale * self.1, scale * self.2)
+    }
+
+    pub fn multiply(&self, other: Color) -> Color {
+        Color::combine(1.0, *self, other)
+    }
+
+    pub fn average(&self) -> f64 {
+        (self.0 + self.1 + self.2) / 3.0
+    }
+
+    pub fn max_component(&self) -> f64 {
+        self.0.max(self.1).max(self.2)
+    }
+
+    // Color with its largest component scaled to one
+    pub fn tint(&self) -> Color {
+        let max = self.max_component();
+        match max > 0.0 {
+            true => self.scale(1.0 / max),
+            false => *self,
+        }
+    }
+
     pub fn add(&mut self, other: Color) {
         self.0 += other.0;
         self.1 += other.1;
         self.2 += other.2;
     }
 
-    pub fn to_pixel(&self) -> (u8, u8, u8) {
-        let red = (self.0 * 255.0).min(255.0) as u8;
-        let green = (self.1 * 255.0).min(255.0) as u8;
-        let blue = (self.2 * 255.0).min(255.0) as u8;
+    pub fn to_rgb(self) -> image::Rgb<f32> {
+        image::Rgb([self.0 as f32, self.1 as f32, self.2 as f32])
+    }
+}
 
-        (red, green, blue)
+// Texture coordinates where a ray hit a surface, along with the width of the
+// ray's footprint measured in texture coordinates
+#[derive(Copy, Clone, Debug)]
+pub struct TextureLookup {
+    pub uv: UV,
+    pub footprint: f64,
+}
+
+// How texture coordinates outside of [0, 1] are mapped back onto the texture
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WrapMode {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    fn apply(self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+
+        let index = match self {
+            WrapMode::Repeat => index.rem_euclid(size),
+            WrapMode::Clamp => index.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let index = index.rem_euclid(2 * size);
+                match index < size {
+                    true => index,
+                    false => 2 * size - 1 - index,
+                }
+            }
+        };
+
+        index as usize
     }
 }
 
-pub struct Texture {
-    data: Vec<u8>,
+// Textures are stored as RGBA
+const CHANNELS: usize = 4;
+
+// Surfaces are cut out where their texture alpha falls below this
+const ALPHA_THRESHOLD: f64 = 0.5;
+
+type Texel = [f64; CHANNELS];
+
+fn mix(first: Texel, second: Texel, fraction: f64) -> Texel {
+    let mut mixed = first;
+    for (channel, value) in mixed.iter_mut().zip(second.iter()) {
+        *channel += (value - *channel) * fraction;
+    }
+
+    mixed
+}
+
+struct MipLevel {
+    data: Vec<f32>,
     width: usize,
     height: usize,
 }
 
-impl Texture {
-    pub fn new(data: Vec<u8>, width: usize, height: usize) -> Texture {
-        Texture {
+impl MipLevel {
+    fn texel(&self, x: usize, y: usize) -> Texel {
+        let index = CHANNELS * (y * self.width + x);
+
+        let mut texel = [0.0; CHANNELS];
+        for (channel, value) in texel.iter_mut().zip(&self.data[index..index + CHANNELS]) {
+            *channel = *value as f64;
+        }
+
+        texel
+    }
+
+    // Each texel averages the (up to) 2x2 block of texels it covers, so the last
+    // row and column of odd sized levels are kept on their own
+    fn downsample(&self) -> MipLevel {
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+
+        let mut data = Vec::with_capacity(CHANNELS * width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let xs = (2 * x)..(2 * x + 2).min(self.width);
+                let ys = (2 * y)..(2 * y + 2).min(self.height);
+                let count = (xs.len() * ys.len()) as f32;
+
+                for channel in 0..CHANNELS {
+                    let mut sum = 0.0;
+                    for source_y in ys.clone() {
+                        for source_x in xs.clone() {
+                            sum +=
+                                self.data[CHANNELS * (source_y * self.width + source_x) + channel];
+                        }
+                    }
+
+                    data.push(sum / count);
+                }
+            }
+        }
+
+        MipLevel {
             data,
             width,
             height,
         }
     }
+}
+
+// Image texture with a mip-map pyramid, sampled with trilinear filtering
+pub struct Texture {
+    levels: Vec<MipLevel>,
+    wrap: WrapMode,
+    has_alpha: bool,
+}
+
+impl Texture {
+    // Data is RGBA, with 8 bits per channel
+    pub fn new(data: Vec<u8>, width: usize, height: usize, wrap: WrapMode) -> Texture {
+        let mut levels = Vec::new();
+        let has_alpha = data
+            .iter()
+            .skip(CHANNELS - 1)
+            .step_by(CHANNELS)
+            .any(|alpha| *alpha < 255);
+
+        if width > 0 && height > 0 {
+            let mut level = MipLevel {
+                data: data.iter().map(|value| (*value as f32) / 255.0).collect(),
+                width,
+                height,
+            };
+
+            while level.width > 1 || level.height > 1 {
+                let next = level.downsample();
+                levels.push(level);
+                level = next;
+            }
+            levels.push(level);
+        }
+
+        Texture {
+            levels,
+            wrap,
+            has_alpha,
+        }
+    }
+
+    fn bilinear(&self, level: &MipLevel, uv: UV) -> Texel {
+        // Texel centers sit at half integer coordinates
+        let x = uv.u * (level.width as f64) - 0.5;
+        let y = (1.0 - uv.v) * (level.height as f64) - 0.5;
+
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let xs = [
+            self.wrap.apply(x0 as i64, level.width),
+            self.wrap.apply(x0 as i64 + 1, level.width),
+        ];
+        let ys = [
+            self.wrap.apply(y0 as i64, level.height),
+            self.wrap.apply(y0 as i64 + 1, level.height),
+        ];
+
+        let top = mix(level.texel(xs[0], ys[0]), level.texel(xs[1], ys[0]), fx);
+        let bottom = mix(level.texel(xs[0], ys[1]), level.texel(xs[1], ys[1]), fx);
+
+        mix(top, bottom, fy)
+    }
+
+    // The level of detail is picked so that one texel roughly covers the ray
+    // footprint, returned as a level index and the fraction towards the next
+    fn level_of_detail(&self, footprint: f64) -> (usize, f64) {
+        let base = &self.levels[0];
+        let size = base.width.max(base.height) as f64;
+
+        let lod = (footprint * size).log2();
+        let lod = match lod.is_nan() {
+            true => 0.0,
+            false => lod.clamp(0.0, (self.levels.len() - 1) as f64),
+        };
+
+        (lod.floor() as usize, lod - lod.floor())
+    }
+
+    fn texel_at(&self, lookup: TextureLookup) -> Texel {
+        if self.levels.is_empty() {
+            return [1.0; CHANNELS];
+        }
+
+        let (lower, fraction) = self.level_of_detail(lookup.footprint);
+
+        let texel = self.bilinear(&self.levels[lower], lookup.uv);
+        match fraction > 0.0 {
+            true => mix(
+                texel,
+                self.bilinear(&self.levels[lower + 1], lookup.uv),
+                fraction,
+            ),
+            false => texel,
+        }
+    }
 
-    fn color_at(&self, uv: UV) -> Color {
-        let u = uv.u.rem_euclid(1.0);
-        let v = uv.v.rem_euclid(1.0);
-        let x = (u * (self.width as f64)) as usize;
-        let y = ((1.0 - v) * (self.height as f64)) as usize;
+    fn color_at(&self, lookup: TextureLookup) -> Color {
+        let texel = self.texel_at(lookup);
 
-        let index = 3 * (y * self.width + x);
+        Color(texel[0], texel[1], texel[2])
+    }
+
+    // Scalar textures are read as the average of their color channels
+    fn value_at(&self, lookup: TextureLookup) -> f64 {
+        self.color_at(lookup).average()
+    }
 
-        if index >= 3 * self.width * self.height {
-            println!("{}, {}", u, v);
-            return Color::black();
+    // Masks use the alpha channel when the image has one, and otherwise the
+    // color like other scalar textures
+    pub fn mask_at(&self, lookup: TextureLookup) -> f64 {
+        match self.has_alpha {
+            true => self.texel_at(lookup)[3],
+            false => self.value_at(lookup),
         }
+    }
+
+    // Rate of change of the texture value along u and v, using central
+    // differences one texel apart at the level of detail being sampled
+    fn gradient(&self, lookup: TextureLookup) -> (f64, f64) {
+        if self.levels.is_empty() {
+            return (0.0, 0.0);
+        }
+
+        let (lower, _) = self.level_of_detail(lookup.footprint);
+        let level = &self.levels[lower];
 
-        let r = self.data[index] as f64;
-        let g = self.data[index + 1] as f64;
-        let b = self.data[index + 2] as f64;
+        let du = 1.0 / (level.width as f64);
+        let dv = 1.0 / (level.height as f64);
+        let UV { u, v } = lookup.uv;
 
-        Color(r / 255.0, g / 255.0, b / 255.0)
+        let value = |u, v| {
+            let texel = self.bilinear(level, UV::new(u, v));
+            (texel[0] + texel[1] + texel[2]) / 3.0
+        };
+
+        (
+            (value(u + du, v) - value(u - du, v)) / (2.0 * du),
+            (value(u, v + dv) - value(u, v - dv)) / (2.0 * dv),
+        )
     }
 }
 
-pub struct Material {
-    specular: Color,
-    diffuse: Color,
-    ambient: Color,
-    alpha: f64,
-    reflectance: f64,
-    transparency: f64,
-    index_of_refraction: f64,
-    texture: Texture,
-}
-
-impl Material {
-    pub fn new(
-        specular: Color,
-        diffuse: Color,
-        ambient: Color,
-        alpha: f64,
-        reflectance: f64,
-        transparency: f64,
-        index_of_refraction: f64,
-        texture: Texture,
-    ) -> Material {
-        Material {
-            specular,
-            diffuse,
-            ambient,
-            alpha,
-            reflectance,
-            transparency,
-            index_of_refraction,
-            texture,
+// Height map whose values are scaled by the multiplier before displacing the
+// surface along its normal
+pub struct BumpMap {
+    pub texture: Texture,
+    pub multiplier: f64,
+}
+
+// Optional textures for each channel of a material. Color textures multiply
+// the material's color, and scalar textures multiply its scalar value.
+#[derive(Default)]
+pub struct TextureMaps {
+    pub diffuse: Option<Texture>,
+    pub specular: Option<Texture>,
+    pub ambient: Option<Texture>,
+    pub shininess: Option<Texture>,
+    pub opacity: Option<Texture>,
+    pub bump: Option<BumpMap>,
+    pub normal: Option<Texture>,
+    pub metallic: Option<Texture>,
+    pub roughness: Option<Texture>,
+}
+
+pub fn textured_color(
+    color: Color,
+    texture: &Option<Texture>,
+    lookup: Option<TextureLookup>,
+) -> Color {
+    match (texture, lookup) {
+        (Some(texture), Some(lookup)) => Color::combine(1.0, texture.color_at(lookup), color),
+        _ => color,
+    }
+}
+
+pub fn textured_value(value: f64, texture: &Option<Texture>, lookup: Option<TextureLookup>) -> f64 {
+    match (texture, lookup) {
+        (Some(texture), Some(lookup)) => value * texture.value_at(lookup),
+        _ => value,
+    }
+}
+
+impl TextureMaps {
+    // Whether the surface is cut away at these texture coordinates, by the
+    // alpha of its diffuse texture or by its opacity map
+    pub fn is_cut_out(&self, uv: UV) -> bool {
+        let lookup = TextureLookup { uv, footprint: 0.0 };
+        let mut coverage = 1.0;
+
+        if let Some(diffuse) = &self.diffuse {
+            if diffuse.has_alpha {
+                coverage *= diffuse.texel_at(lookup)[3];
+            }
         }
+
+        if let Some(opacity) = &self.opacity {
+            coverage *= opacity.mask_at(lookup);
+        }
+
+        coverage < ALPHA_THRESHOLD
     }
+
+    // Applies the normal and bump maps to a surface normal. The derivatives are
+    // the rates of change of the surface position along u and v, which orient
+    // the maps on the surface.
+    pub fn shading_normal(
+        &self,
+        normal: linear::Vector,
+        derivatives: Option<(linear::Vector, linear::Vector)>,
+        texture: Option<TextureLookup>,
+    ) -> linear::Vector {
+        if self.normal.is_none() && self.bump.is_none() {
+            return normal;
+        }
+
+        let (lookup, (dpdu, dpdv)) = match (texture, derivatives) {
+            (Some(lookup), Some(derivatives)) => (lookup, derivatives),
+            _ => return normal,
+        };
+
+        let normal = normal.normalize();
+        let dpdu = dpdu.subtract(&normal.scale(normal.dot(&dpdu)));
+        let dpdv = dpdv.subtract(&normal.scale(normal.dot(&dpdv)));
+
+        let mut shading_normal = normal;
+
+        // Tangent space normal maps store x along u, y along v and z along the
+        // surface normal
+        if let Some(normal_map) = &self.normal {
+            let Color(x, y, z) = normal_map.color_at(lookup);
+
+            let tangent = dpdu.normalize();
+            let mut bitangent = normal.cross(&tangent);
+            if bitangent.dot(&dpdv) < 0.0 {
+                bitangent = bitangent.negative();
+            }
+
+            shading_normal = tangent
+                .scale(2.0 * x - 1.0)
+                .add(&bitangent.scale(2.0 * y - 1.0))
+                .add(&normal.scale(2.0 * z - 1.0))
+                .normalize();
+        }
+
+        // Displacing the surface by the height map tilts its derivatives, and
+        // the bumped normal is perpendicular to both
+        if let Some(bump) = &self.bump {
+            let (height_u, height_v) = bump.texture.gradient(lookup);
+
+            let bumped_u = dpdu.add(&shading_normal.scale(bump.multiplier * height_u));
+            let bumped_v = dpdv.add(&shading_normal.scale(bump.multiplier * height_v));
+
+            let mut bumped = bumped_u.cross(&bumped_v).normalize();
+            if bumped.dot(&shading_normal) < 0.0 {
+                bumped = bumped.negative();
+            }
+
+            shading_normal = bumped;
+        }
+
+        shading_normal
+    }
+}
+
+// Emitting shape of a light, centered on its position. Area lights are sampled
+// with several shadow rays to produce soft shadows.
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LightShape {
+    #[default]
+    Point,
+    Rectangle {
+        edge1: linear::Vector,
+        edge2: linear::Vector,
+    },
+    Disk {
+        normal: linear::Vector,
+        radius: f64,
+    },
+    Sphere {
+        radius: f64,
+    },
+}
+
+fn default_light_samples() -> u32 {
+    16
+}
+
+fn default_inner_angle() -> f64 {
+    0.0
+}
+
+// How the intensity of a spot light falls off with distance
+#[derive(Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Attenuation {
+    #[default]
+    None,
+    Linear,
+    InverseSquare,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum LightKind {
+    Point {
+        position: linear::Vector,
+
+        #[serde(default)]
+        shape: LightShape,
+
+        // Number of shadow rays used for area lights
+        #[serde(default = "default_light_samples")]
+        samples: u32,
+    },
+    // Infinitely distant light, such as the sun, shining along direction
+    Directional {
+        direction: linear::Vector,
+    },
+    // Light shining along direction from position, at full intensity within
+    // the inner cone angle and fading out towards the outer cone angle (degrees)
+    #[serde(rename_all = "camelCase")]
+    Spot {
+        position: linear::Vector,
+        direction: linear::Vector,
+
+        #[serde(default = "default_inner_angle")]
+        inner_angle: f64,
+        outer_angle: f64,
+
+        #[serde(default)]
+        attenuation: Attenuation,
+    },
+}
+
+// How lights fall off with distance. A white diffuse surface facing a light
+// reflects the light's color, at any distance under classic (Whitted) lighting,
+// apart from spot lights with an attenuation. Physical lighting, which adds up
+// consistently with light bouncing between surfaces, takes the color as pi
+// times the radiant intensity of point, spot and area lights, so they fall off
+// with the square of the distance.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Radiometry {
+    Classic,
+    Physical,
+}
+
+// Lights written before there were several kinds have no type, and are point
+// lights. Lights of any other type never fall back to being point lights.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LightKindData {
+    Typed(LightKind),
+    Untyped {
+        #[serde(rename = "type")]
+        _kind: Option<PointType>,
+        position: linear::Vector,
+
+        #[serde(default)]
+        shape: LightShape,
+
+        #[serde(default = "default_light_samples")]
+        samples: u32,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum PointType {
+    Point,
+}
+
+fn deserialize_kind<'de, D>(deserializer: D) -> Result<LightKind, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match LightKindData::deserialize(deserializer)? {
+        LightKindData::Typed(kind) => kind,
+        LightKindData::Untyped {
+            position,
+            shape,
+            samples,
+            ..
+        } => LightKind::Point {
+            position,
+            shape,
+            samples,
+        },
+    })
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct LightSource {
-    pub position: linear::Vector,
     specular: Color,
     diffuse: Color,
     ambient: Color,
+
+    #[serde(flatten, deserialize_with = "deserialize_kind")]
+    kind: LightKind,
+}
+
+// Light arriving at a point from one sample of a light source. The offset points
+// from the lit point towards the light, and the light is visible if a shadow ray
+// along it is unoccluded up to range (measured in multiples of the offset).
+// Samples of emissive surfaces are marked as emitters, since unlike light
+// sources they can also be seen in reflections.
+pub struct LightSample {
+    pub offset: linear::Vector,
+    pub range: f64,
+    pub specular: Color,
+    pub diffuse: Color,
+    pub emitter: bool,
+}
+
+impl LightSample {
+    pub fn new(offset: linear::Vector, range: f64, color: Color) -> LightSample {
+        LightSample {
+            offset,
+            range,
+            specular: color,
+            diffuse: color,
+            emitter: false,
+        }
+    }
+
+    pub fn emitter(offset: linear::Vector, range: f64, color: Color) -> LightSample {
+        LightSample {
+            emitter: true,
+            ..LightSample::new(offset, range, color)
+        }
+    }
+}
+
+// Uniformly distributed point on a disk with the given center, normal and radius
+fn sample_disk(
+    center: &linear::Vector,
+    normal: &linear::Vector,
+    radius: f64,
+    rng: &mut SmallRng,
+) -> linear::Vector {
+    let (tangent, bitangent) = normal.normalize().orthonormal_basis();
+
+    let r = radius * rng.gen::<f64>().sqrt();
+    let theta = 2.0 * consts::PI * rng.gen::<f64>();
+
+    center
+        .add(&tangent.scale(r * theta.cos()))
+        .add(&bitangent.scale(r * theta.sin()))
+}
+
+// Picks a point on a light's shape to cast a shadow ray towards from `target`
+fn sample_shape(
+    position: &linear::Vector,
+    shape: &LightShape,
+    target: &linear::Vector,
+    rng: &mut SmallRng,
+) -> linear::Vector {
+    match *shape {
+        LightShape::Point => *position,
+        LightShape::Rectangle { edge1, edge2 } => position
+            .add(&edge1.scale(rng.gen::<f64>() - 0.5))
+            .add(&edge2.scale(rng.gen::<f64>() - 0.5)),
+        LightShape::Disk { normal, radius } => sample_disk(position, &normal, radius, rng),
+        // A sphere looks like a disk facing the point being lit
+        LightShape::Sphere { radius } => {
+            let facing = target.subtract(position);
+            if facing.magnitude() == 0.0 {
+                return *position;
+            }
+
+            sample_disk(position, &facing, radius, rng)
+        }
+    }
+}
+
+// Smoothly fades from 1 inside the inner cone to 0 outside the outer cone
+fn spot_falloff(cos_angle: f64, inner_angle: f64, outer_angle: f64) -> f64 {
+    let cos_inner = inner_angle.min(outer_angle).to_radians().cos();
+    let cos_outer = outer_angle.to_radians().cos();
+
+    if cos_angle >= cos_inner {
+        return 1.0;
+    }
+    if cos_angle <= cos_outer {
+        return 0.0;
+    }
+
+    let x = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+    x * x * (3.0 - 2.0 * x)
 }
 
 impl LightSource {
+    pub fn sample_count(&self) -> u32 {
+        match self.kind {
+            LightKind::Point {
+                shape: LightShape::Point,
+                ..
+            } => 1,
+            LightKind::Point { samples, .. } => samples.max(1),
+            _ => 1,
+        }
+    }
+
+    // Light reaching `target` from one of this light's samples, or None if the
+    // light can't reach it at all
+    pub fn sample(
+        &self,
+        target: &linear::Vector,
+        radiometry: Radiometry,
+        rng: &mut SmallRng,
+    ) -> Option<LightSample> {
+        let fraction = 1.0 / (self.sample_count() as f64);
+
+        let (offset, range, intensity) = match self.kind {
+            LightKind::Point {
+                position, shape, ..
+            } => {
+                let offset = sample_shape(&position, &shape, target, rng).subtract(target);
+                let attenuation = match radiometry {
+                    Radiometry::Classic => 1.0,
+                    Radiometry::Physical => 1.0 / offset.dot(&offset),
+                };
+                if !attenuation.is_finite() {
+                    return None;
+                }
+
+                (offset, 1.0, fraction * attenuation)
+            }
+            LightKind::Directional { direction } => {
+                (direction.negative().normalize(), f64::MAX, fraction)
+            }
+            LightKind::Spot {
+                position,
+                direction,
+                inner_angle,
+                outer_angle,
+                attenuation,
+            } => {
+                let offset = position.subtract(target);
+                let distance = offset.magnitude();
+                if distance == 0.0 {
+                    return None;
+                }
+
+                let cos_angle = -offset.dot(&direction.normalize()) / distance;
+                let falloff = spot_falloff(cos_angle, inner_angle, outer_angle);
+                if falloff <= 0.0 {
+                    return None;
+                }
+
+                let attenuation = match (radiometry, attenuation) {
+                    (Radiometry::Physical, _) => 1.0 / (distance * distance),
+                    (_, Attenuation::None) => 1.0,
+                    (_, Attenuation::Linear) => 1.0 / distance,
+                    (_, Attenuation::InverseSquare) => 1.0 / (distance * distance),
+                };
+
+                (offset, 1.0, fraction * falloff * attenuation)
+            }
+        };
+
+        Some(LightSample {
+            offset,
+            range,
+            specular: self.specular.scale(intensity),
+            diffuse: self.diffuse.scale(intensity),
+            emitter: false,
+        })
+    }
+
+    // Lights can only shine along directions, and disks only face normals, which
+    // aren't zero
+    pub fn is_valid(&self) -> bool {
+        let nonzero = |vector: &linear::Vector| vector.magnitude() > 0.0;
+
+        match self.kind {
+            LightKind::Point { shape, .. } => match shape {
+                LightShape::Disk { normal, .. } => nonzero(&normal),
+                _ => true,
+            },
+            LightKind::Directional { direction } => nonzero(&direction),
+            LightKind::Spot { direction, .. } => nonzero(&direction),
+        }
+    }
+
     pub fn calculate_ambient(lights: &Vec<LightSource>) -> Color {
         let mut red = 0.0;
         let mut green = 0.0;
         let mut blue = 0.0;
 
         for light in lights {
-            red = red + light.ambient.0;
-            green = green + light.ambient.1;
-            blue = blue + light.ambient.2;
+            red += light.ambient.0;
+            green += light.ambient.1;
+            blue += light.ambient.2;
         }
 
         Color(red, green, blue)
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn calculate(
-    lights: &Vec<LightSource>,
+    lights: &[LightSample],
     ambient_light: Color,
     ray: &linear::Ray,
+    texture: Option<TextureLookup>,
     normal: linear::Vector,
+    back_face: bool,
     light_strength: f64,
-    material: &Material,
-) -> (Color, f64, Vec<linear::Ray>) {
-    let mut color = Color(0.0, 0.0, 0.0);
+    material: &dyn material::Material,
+) -> (Color, Vec<(linear::Ray, Color)>) {
+    let mut color = material.direct(lights, ray, texture, normal, light_strength);
 
-    for light in lights {
-        let dist = light.position.subtract(&ray.position);
+    color.add(material.emission().scale(light_strength));
+    color.add(material.ambient(ambient_light, texture, light_strength));
 
-        if normal.dot(&dist) <= 0.0 {
-            continue;
-        }
+    let rays = material.secondary_rays(ray, texture, normal, back_face);
 
-        let dist = dist.normalize();
+    (color, rays)
+}
 
-        let reflection = dist.reflect_across(&normal);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use rand::SeedableRng;
 
-        let diffuse_coef = dist.dot(&normal);
-        let diffuse = Color::combine(
-            diffuse_coef * light_strength,
-            light.diffuse,
-            material.diffuse,
-        );
-        color.add(diffuse);
+    #[test]
+    fn wrap_modes() {
+        assert_eq!(1, WrapMode::Repeat.apply(-3, 4));
+        assert_eq!(0, WrapMode::Clamp.apply(-3, 4));
+        assert_eq!(3, WrapMode::Clamp.apply(7, 4));
+        assert_eq!(2, WrapMode::Mirror.apply(-3, 4));
+        assert_eq!(2, WrapMode::Mirror.apply(5, 4));
+    }
+
+    #[test]
+    fn texture_filtering() {
+        // Black and white columns average out to grey in the next level
+        let data = vec![
+            0, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255,
+        ];
+        let texture = Texture::new(data, 2, 2, WrapMode::Clamp);
+        assert_eq!(2, texture.levels.len());
 
-        let mut specular_base = reflection.dot(&ray.direction.negative());
-        if specular_base < 0.0 {
-            specular_base = 0.0;
+        let sharp = TextureLookup {
+            uv: UV::new(0.25, 0.5),
+            footprint: 0.0,
+        };
+        assert_abs_diff_eq!(0.0, texture.color_at(sharp).0);
+
+        let between = TextureLookup {
+            uv: UV::new(0.5, 0.5),
+            footprint: 0.0,
+        };
+        assert_abs_diff_eq!(0.5, texture.color_at(between).0);
+
+        let blurred = TextureLookup {
+            uv: UV::new(0.25, 0.5),
+            footprint: 1.0,
+        };
+        assert_abs_diff_eq!(0.5, texture.color_at(blurred).0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn odd_mip_levels() {
+        // A white column at the right edge of a 3x3 texture isn't dropped
+        let mut data = Vec::new();
+        for _ in 0..3 {
+            data.extend(&[0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255, 255]);
         }
+        let texture = Texture::new(data, 3, 3, WrapMode::Clamp);
+
+        let sizes = texture
+            .levels
+            .iter()
+            .map(|level| (level.width, level.height))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(3, 3), (2, 2), (1, 1)], sizes);
+
+        assert_abs_diff_eq!(0.0, texture.levels[1].texel(0, 1)[0]);
+        assert_abs_diff_eq!(1.0, texture.levels[1].texel(1, 1)[0]);
+        assert_abs_diff_eq!(0.5, texture.levels[2].texel(0, 0)[0]);
+    }
+
+    #[test]
+    fn normal_maps() {
+        let normal = linear::Vector::new(0.0, 0.0, 1.0);
+        let derivatives = Some((
+            linear::Vector::new(2.0, 0.0, 0.0),
+            linear::Vector::new(0.0, 2.0, 0.0),
+        ));
+        let lookup = Some(TextureLookup {
+            uv: UV::new(0.5, 0.5),
+            footprint: 0.0,
+        });
 
-        let specular_coef = specular_base.powf(material.alpha);
+        // Fully tilted towards u
+        let tilted = TextureMaps {
+            normal: Some(Texture::new(
+                vec![255, 128, 128, 255],
+                1,
+                1,
+                WrapMode::Repeat,
+            )),
+            ..TextureMaps::default()
+        };
+        let shading_normal = tilted.shading_normal(normal, derivatives, lookup);
+        assert_abs_diff_eq!(
+            1.0,
+            shading_normal.dot(&linear::Vector::new(1.0, 0.0, 0.0)),
+            epsilon = 1e-4
+        );
 
-        let specular = Color::combine(
-            specular_coef * light_strength,
-            light.specular,
-            material.specular,
+        // Height rising along u tilts the normal back towards -u
+        let ramp = Texture::new(
+            vec![0, 0, 0, 255, 255, 255, 255, 255],
+            2,
+            1,
+            WrapMode::Clamp,
         );
-        color.add(specular);
+        let bumped = TextureMaps {
+            bump: Some(BumpMap {
+                texture: ramp,
+                multiplier: 1.0,
+            }),
+            ..TextureMaps::default()
+        };
+        let shading_normal = bumped.shading_normal(normal, derivatives, lookup);
+        assert!(shading_normal.dot(&linear::Vector::new(1.0, 0.0, 0.0)) < -0.1);
+        assert_abs_diff_eq!(
+            0.0,
+            shading_normal.dot(&linear::Vector::new(0.0, 1.0, 0.0)),
+            epsilon = 1e-9
+        );
+
+        // Without texture coordinates the normal is left alone
+        assert!(bumped
+            .shading_normal(normal, derivatives, None)
+            .equals(&normal));
     }
 
-    color.add(Color::combine(
-        light_strength,
-        ambient_light,
-        material.ambient,
-    ));
+    #[test]
+    fn cutouts() {
+        // Left half transparent, right half opaque
+        let rgba = vec![255, 255, 255, 0, 255, 255, 255, 255];
+        let leaf = TextureMaps {
+            diffuse: Some(Texture::new(rgba, 2, 1, WrapMode::Clamp)),
+            ..TextureMaps::default()
+        };
+        assert!(leaf.is_cut_out(UV::new(0.1, 0.5)));
+        assert!(!leaf.is_cut_out(UV::new(0.9, 0.5)));
 
-    let reflection = linear::Ray {
-        position: ray.position,
-        direction: ray.direction.negative().normalize().reflect_across(&normal),
-    };
+        // Masks without alpha use their brightness
+        let mask = vec![0, 0, 0, 255, 255, 255, 255, 255];
+        let fence = TextureMaps {
+            opacity: Some(Texture::new(mask, 2, 1, WrapMode::Clamp)),
+            ..TextureMaps::default()
+        };
+        assert!(fence.is_cut_out(UV::new(0.1, 0.5)));
+        assert!(!fence.is_cut_out(UV::new(0.9, 0.5)));
 
-    (
-        color,
-        light_strength * material.reflectance,
-        vec![reflection],
-    )
-}
+        assert!(!TextureMaps::default().is_cut_out(UV::new(0.1, 0.5)));
+    }
 
-pub fn calculate_with_tex(
-    lights: &Vec<LightSource>,
-    ambient_light: Color,
-    ray: &linear::Ray,
-    uv: UV,
-    normal: linear::Vector,
-    light_strength: f64,
-    material: &Material,
-) -> (Color, f64, Vec<linear::Ray>) {
-    let mut color = Color(0.0, 0.0, 0.0);
+    #[test]
+    fn spot_falloff_cone() {
+        assert_abs_diff_eq!(1.0, spot_falloff(1.0, 10.0, 20.0));
+        assert_abs_diff_eq!(0.0, spot_falloff(25f64.to_radians().cos(), 10.0, 20.0));
 
-    for light in lights {
-        let dist = light.position.subtract(&ray.position);
+        let between = spot_falloff(15f64.to_radians().cos(), 10.0, 20.0);
+        assert!(between > 0.0 && between < 1.0);
+    }
 
-        if normal.dot(&dist) <= 0.0 {
-            continue;
-        }
+    #[test]
+    fn sample_kinds() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let target = linear::Vector::new(0.0, 0.0, 0.0);
 
-        let dist = dist.normalize();
+        let light: LightSource = serde_json::from_str(
+            r#"{"type": "directional", "direction": {"x": 0, "y": -2, "z": 0},
+                "specular": [1, 1, 1], "diffuse": [1, 1, 1], "ambient": [0, 0, 0]}"#,
+        )
+        .unwrap();
+        let sample = light
+            .sample(&target, Radiometry::Classic, &mut rng)
+            .unwrap();
+        assert!(sample.offset.equals(&linear::Vector::new(0.0, 1.0, 0.0)));
+        assert_eq!(f64::MAX, sample.range);
 
-        let reflection = dist.reflect_across(&normal);
+        // Directional lights don't fall off either way
+        let sample = light
+            .sample(&target, Radiometry::Physical, &mut rng)
+            .unwrap();
+        assert_abs_diff_eq!(1.0, sample.diffuse.0);
 
-        let diffuse_coef = dist.dot(&normal);
-        let diffuse = Color::combine(1.0, material.texture.color_at(uv), material.diffuse);
-        let diffuse = Color::combine(diffuse_coef * light_strength, light.diffuse, diffuse);
-        color.add(diffuse);
+        let light: LightSource = serde_json::from_str(
+            r#"{"type": "spot", "position": {"x": 0, "y": 2, "z": 0},
+                "direction": {"x": 0, "y": -1, "z": 0}, "outerAngle": 30,
+                "attenuation": "inverseSquare",
+                "specular": [1, 1, 1], "diffuse": [1, 1, 1], "ambient": [0, 0, 0]}"#,
+        )
+        .unwrap();
+        let sample = light
+            .sample(&target, Radiometry::Classic, &mut rng)
+            .unwrap();
+        assert_abs_diff_eq!(0.25, sample.diffuse.0);
 
-        let mut specular_base = reflection.dot(&ray.direction.negative());
-        if specular_base < 0.0 {
-            specular_base = 0.0;
-        }
+        let outside = linear::Vector::new(5.0, 0.0, 0.0);
+        assert!(light
+            .sample(&outside, Radiometry::Classic, &mut rng)
+            .is_none());
 
-        let specular_coef = specular_base.powf(material.alpha);
+        // Physical lights fall off with the square of the distance, whatever
+        // their attenuation
+        let light: LightSource = serde_json::from_str(
+            r#"{"type": "point", "position": {"x": 0, "y": 2, "z": 0},
+                "specular": [1, 1, 1], "diffuse": [1, 1, 1], "ambient": [0, 0, 0]}"#,
+        )
+        .unwrap();
+        let sample = light
+            .sample(&target, Radiometry::Classic, &mut rng)
+            .unwrap();
+        assert_abs_diff_eq!(1.0, sample.diffuse.0);
+        let sample = light
+            .sample(&target, Radiometry::Physical, &mut rng)
+            .unwrap();
+        assert_abs_diff_eq!(0.25, sample.diffuse.0);
+    }
 
-        let specular = Color::combine(
-            specular_coef * light_strength,
-            light.specular,
-            material.specular,
-        );
-        color.add(specular);
+    #[test]
+    fn area_light_samples() {
+        let mut rng = SmallRng::seed_from_u64(7);
+        let center = linear::Vector::new(1.0, 2.0, 3.0);
+        let target = linear::Vector::new(1.0, 10.0, 3.0);
+        let up = linear::Vector::new(0.0, 1.0, 0.0);
+
+        // Each shape lies in the y = 2 plane (spheres facing the target) with an
+        // area, and an inner part that is a quarter of it
+        let rectangle = LightShape::Rectangle {
+            edge1: linear::Vector::new(2.0, 0.0, 0.0),
+            edge2: linear::Vector::new(0.0, 0.0, 4.0),
+        };
+        let within = |shape: &LightShape, offset: &linear::Vector, scale: f64| match shape {
+            LightShape::Rectangle { .. } => {
+                offset.component(0).abs() <= scale && offset.component(2).abs() <= 2.0 * scale
+            }
+            _ => offset.magnitude() <= 2.0 * scale,
+        };
+        let shapes = [
+            (rectangle, 8.0),
+            (
+                LightShape::Disk {
+                    normal: up,
+                    radius: 2.0,
+                },
+                4.0 * consts::PI,
+            ),
+            (LightShape::Sphere { radius: 2.0 }, 4.0 * consts::PI),
+        ];
+
+        for (shape, area) in shapes.iter() {
+            let samples = 20000;
+            let mut inner = 0;
+            for _ in 0..samples {
+                let offset = sample_shape(&center, shape, &target, &mut rng).subtract(&center);
+                assert_abs_diff_eq!(0.0, offset.dot(&up), epsilon = 1e-9);
+                assert!(within(shape, &offset, 1.0 + 1e-9));
+
+                if within(shape, &offset, 0.5) {
+                    inner += 1;
+                }
+            }
+
+            // Points are spread uniformly, with a density of 1 / area
+            let inner_area = area / 4.0;
+            let fraction = inner as f64 / samples as f64;
+            assert_abs_diff_eq!(inner_area / area, fraction, epsilon = 0.01);
+        }
+
+        // Every shadow ray carries an equal share of the light, as befits
+        // samples drawn uniformly over the shape
+        let light: LightSource = serde_json::from_str(
+            r#"{"type": "point", "position": {"x": 1, "y": 2, "z": 3},
+                "shape": {"type": "disk", "normal": {"x": 0, "y": 1, "z": 0}, "radius": 2},
+                "samples": 8,
+                "specular": [1, 1, 1], "diffuse": [1, 1, 1], "ambient": [0, 0, 0]}"#,
+        )
+        .unwrap();
+        assert_eq!(8, light.sample_count());
+        for _ in 0..8 {
+            let sample = light
+                .sample(&target, Radiometry::Classic, &mut rng)
+                .unwrap();
+            assert_abs_diff_eq!(0.125, sample.diffuse.0);
+            let offset = target.add(&sample.offset).subtract(&center);
+            assert!(within(&shapes[1].0, &offset, 1.0 + 1e-9));
+        }
     }
 
-    color.add(Color::combine(
-        light_strength,
-        ambient_light,
-        material.ambient,
-    ));
+    #[test]
+    fn light_types() {
+        let parse = |kind: &str| {
+            serde_json::from_str::<LightSource>(&format!(
+                r#"{{{} "specular": [1, 1, 1], "diffuse": [1, 1, 1], "ambient": [0, 0, 0]}}"#,
+                kind
+            ))
+        };
+
+        // Lights without a type are point lights
+        let light = parse(r#""position": {"x": 0, "y": 2, "z": 0},"#).unwrap();
+        assert!(matches!(light.kind, LightKind::Point { samples: 16, .. }));
 
-    let reflection = linear::Ray {
-        position: ray.position,
-        direction: ray.direction.negative().normalize().reflect_across(&normal),
-    };
+        // Other lights must be complete, rather than being taken as point lights
+        assert!(parse(r#""type": "spot", "position": {"x": 0, "y": 2, "z": 0},"#).is_err());
+        assert!(parse(r#""type": "sun", "position": {"x": 0, "y": 2, "z": 0},"#).is_err());
 
-    (
-        color,
-        light_strength * material.reflectance,
-        vec![reflection],
-    )
+        // Lights can't shine along a zero direction
+        let light = parse(r#""type": "directional", "direction": {"x": 0, "y": -1, "z": 0},"#);
+        assert!(light.unwrap().is_valid());
+        let light = parse(r#""type": "directional", "direction": {"x": 0, "y": 0, "z": 0},"#);
+        assert!(!light.unwrap().is_valid());
+    }
 }
@@ -0,0 +1,839 @@
+use rand::rngs::SmallRng;
+use rand::Rng;
+use std::f64::consts;
+
+use super::lighting;
+use super::linear;
+use super::primitive;
+
+// Roughness is kept above zero so the microfacet distribution stays finite
+const MIN_ROUGHNESS: f64 = 0.02;
+
+// Direction a path continues in after hitting a surface. The path's throughput
+// is multiplied by weight / probability, and specular bounces are ones that
+// the light sampling at the surface can't account for.
+pub struct Scatter {
+    pub ray: linear::Ray,
+    pub weight: lighting::Color,
+    pub probability: f64,
+    pub specular: bool,
+}
+
+// Normals given to a material are turned towards the side of the surface the
+// ray hit, and back_face is set when that is the back, which for a closed mesh
+// means the ray is leaving the material.
+pub trait Material: Sync + Send {
+    fn emission(&self) -> lighting::Color;
+    fn textures(&self) -> &lighting::TextureMaps;
+
+    // Sides of surfaces made of this material which rays can hit
+    fn culling(&self) -> primitive::Culling;
+
+    // Light reflected back along the ray from each of the given (visible) lights
+    fn direct(
+        &self,
+        lights: &[lighting::LightSample],
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        light_strength: f64,
+    ) -> lighting::Color;
+
+    fn ambient(
+        &self,
+        ambient_light: lighting::Color,
+        texture: Option<lighting::TextureLookup>,
+        light_strength: f64,
+    ) -> lighting::Color;
+
+    // Mirror reflection and refraction rays for the Whitted integrator, each
+    // paired with the fraction of light it carries
+    fn secondary_rays(
+        &self,
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        back_face: bool,
+    ) -> Vec<(linear::Ray, lighting::Color)>;
+
+    // Randomly picks the direction a path continues in, or None if it ends
+    fn scatter(
+        &self,
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        back_face: bool,
+        rng: &mut SmallRng,
+    ) -> Option<Scatter>;
+
+    fn is_emissive(&self) -> bool {
+        self.emission().max_component() > 0.0
+    }
+
+    fn is_cut_out(&self, uv: lighting::UV) -> bool {
+        self.textures().is_cut_out(uv)
+    }
+
+    fn shading_normal(
+        &self,
+        normal: linear::Vector,
+        derivatives: Option<(linear::Vector, linear::Vector)>,
+        texture: Option<lighting::TextureLookup>,
+    ) -> linear::Vector {
+        self.textures().shading_normal(normal, derivatives, texture)
+    }
+}
+
+// Cosine weighted direction in the hemisphere around a unit normal
+fn sample_hemisphere(normal: &linear::Vector, rng: &mut SmallRng) -> linear::Vector {
+    let (tangent, bitangent) = normal.orthonormal_basis();
+
+    let r = rng.gen::<f64>().sqrt();
+    let phi = 2.0 * consts::PI * rng.gen::<f64>();
+
+    tangent
+        .scale(r * phi.cos())
+        .add(&bitangent.scale(r * phi.sin()))
+        .add(&normal.scale((1.0 - r * r).max(0.0).sqrt()))
+}
+
+// Normal flipped to the side of the surface the ray arrived from
+fn facing(ray: &linear::Ray, normal: linear::Vector) -> linear::Vector {
+    match ray.direction.dot(&normal) > 0.0 {
+        true => normal.negative(),
+        false => normal,
+    }
+    .normalize()
+}
+
+// Schlick's approximation of the Fresnel reflectance
+fn schlick(cos_theta: f64, n1: f64, n2: f64) -> f64 {
+    let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+// Classic Phong shading, with a constant reflectance and optional refraction
+pub struct PhongMaterial {
+    specular: lighting::Color,
+    diffuse: lighting::Color,
+    ambient: lighting::Color,
+    emission: lighting::Color,
+    alpha: f64,
+    reflectance: f64,
+    transparency: f64,
+    index_of_refraction: f64,
+    textures: lighting::TextureMaps,
+    culling: primitive::Culling,
+}
+
+impl PhongMaterial {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        specular: lighting::Color,
+        diffuse: lighting::Color,
+        ambient: lighting::Color,
+        emission: lighting::Color,
+        alpha: f64,
+        reflectance: f64,
+        transparency: f64,
+        index_of_refraction: f64,
+        textures: lighting::TextureMaps,
+        culling: primitive::Culling,
+    ) -> PhongMaterial {
+        PhongMaterial {
+            specular,
+            diffuse,
+            ambient,
+            emission,
+            alpha,
+            reflectance,
+            transparency,
+            index_of_refraction,
+            textures,
+            culling,
+        }
+    }
+
+    fn transparency(&self, texture: Option<lighting::TextureLookup>) -> f64 {
+        match (&self.textures.opacity, texture) {
+            (Some(_), Some(_)) => 1.0 - self.opacity(texture),
+            _ => self.transparency,
+        }
+    }
+
+    fn opacity(&self, texture: Option<lighting::TextureLookup>) -> f64 {
+        match (&self.textures.opacity, texture) {
+            (Some(opacity), Some(lookup)) => (1.0 - self.transparency) * opacity.mask_at(lookup),
+            _ => 1.0 - self.transparency,
+        }
+    }
+
+    fn diffuse_color(&self, texture: Option<lighting::TextureLookup>) -> lighting::Color {
+        lighting::textured_color(self.diffuse, &self.textures.diffuse, texture)
+    }
+
+    fn specular_color(&self, texture: Option<lighting::TextureLookup>) -> lighting::Color {
+        lighting::textured_color(self.specular, &self.textures.specular, texture)
+    }
+
+    fn ambient_color(&self, texture: Option<lighting::TextureLookup>) -> lighting::Color {
+        lighting::textured_color(self.ambient, &self.textures.ambient, texture)
+    }
+
+    fn shininess(&self, texture: Option<lighting::TextureLookup>) -> f64 {
+        lighting::textured_value(self.alpha, &self.textures.shininess, texture)
+    }
+}
+
+impl Material for PhongMaterial {
+    fn emission(&self) -> lighting::Color {
+        self.emission
+    }
+
+    fn textures(&self) -> &lighting::TextureMaps {
+        &self.textures
+    }
+
+    fn culling(&self) -> primitive::Culling {
+        self.culling
+    }
+
+    fn direct(
+        &self,
+        lights: &[lighting::LightSample],
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        light_strength: f64,
+    ) -> lighting::Color {
+        let mut color = lighting::Color::black();
+        let opacity = self.opacity(texture);
+        let diffuse_color = self.diffuse_color(texture);
+        let specular_color = self.specular_color(texture);
+        let shininess = self.shininess(texture);
+
+        // Highlights stand in for reflections of the lights, but emissive
+        // surfaces are already seen in this material's mirror reflections
+        let mirrored = self.reflectance > 0.0 || self.transparency(texture) > 0.0;
+
+        for light in lights {
+            let dist = light.offset;
+
+            if normal.dot(&dist) <= 0.0 {
+                continue;
+            }
+
+            let dist = dist.normalize();
+
+            let reflection = dist.reflect_across(&normal);
+
+            let diffuse_coef = dist.dot(&normal);
+            let diffuse = lighting::Color::combine(
+                diffuse_coef * light_strength * opacity,
+                light.diffuse,
+                diffuse_color,
+            );
+            color.add(diffuse);
+
+            if light.emitter && mirrored {
+                continue;
+            }
+
+            let mut specular_base = reflection.dot(&ray.direction.negative());
+            if specular_base < 0.0 {
+                specular_base = 0.0;
+            }
+
+            let specular_coef = specular_base.powf(shininess);
+
+            let specular = lighting::Color::combine(
+                specular_coef * light_strength,
+                light.specular,
+                specular_color,
+            );
+            color.add(specular);
+        }
+
+        color
+    }
+
+    fn ambient(
+        &self,
+        ambient_light: lighting::Color,
+        texture: Option<lighting::TextureLookup>,
+        light_strength: f64,
+    ) -> lighting::Color {
+        lighting::Color::combine(
+            light_strength * self.opacity(texture),
+            ambient_light,
+            self.ambient_color(texture),
+        )
+    }
+
+    fn secondary_rays(
+        &self,
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        back_face: bool,
+    ) -> Vec<(linear::Ray, lighting::Color)> {
+        let direction = ray.direction.normalize();
+        let transparency = self.transparency(texture);
+        let gray = |strength| lighting::Color::new(strength, strength, strength);
+
+        let reflection = linear::Ray {
+            position: ray.position,
+            direction: direction.negative().reflect_across(&normal),
+            time: ray.time,
+        };
+
+        if transparency <= 0.0 {
+            return vec![(reflection, gray(self.reflectance))];
+        }
+
+        // Rays hitting the back of the surface are leaving the material
+        let (n1, n2) = match back_face {
+            false => (1.0, self.index_of_refraction),
+            true => (self.index_of_refraction, 1.0),
+        };
+        let normal = match direction.dot(&normal) < 0.0 {
+            true => normal,
+            false => normal.negative(),
+        };
+
+        let cos_incident = -direction.dot(&normal);
+        let (fresnel, refraction) = match direction.refract(&normal, n1 / n2) {
+            None => (1.0, None),
+            Some(refracted) => {
+                let cos_theta = match n1 > n2 {
+                    true => -refracted.dot(&normal),
+                    false => cos_incident,
+                };
+
+                let refraction = linear::Ray {
+                    position: ray.position,
+                    direction: refracted,
+                    time: ray.time,
+                };
+
+                (schlick(cos_theta, n1, n2), Some(refraction))
+            }
+        };
+
+        let opacity = 1.0 - transparency;
+        let reflected_strength = opacity * self.reflectance + transparency * fresnel;
+
+        let mut rays = vec![(reflection, gray(reflected_strength))];
+        if let Some(refraction) = refraction {
+            rays.push((refraction, gray(transparency * (1.0 - fresnel))));
+        }
+
+        rays
+    }
+
+    // Picks between the reflected and refracted rays and a diffuse bounce, in
+    // proportion to the light each carries
+    fn scatter(
+        &self,
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        back_face: bool,
+        rng: &mut SmallRng,
+    ) -> Option<Scatter> {
+        // The diffuse lobe scatters into the hemisphere the ray arrived from
+        let facing_normal = facing(ray, normal);
+        let albedo = self.diffuse_color(texture).scale(self.opacity(texture));
+
+        let mut lobes = self.secondary_rays(ray, texture, normal, back_face);
+        lobes.push((
+            linear::Ray {
+                position: ray.position,
+                direction: facing_normal,
+                time: ray.time,
+            },
+            albedo,
+        ));
+
+        let total = lobes
+            .iter()
+            .map(|(_, weight)| weight.average())
+            .sum::<f64>();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let mut choice = rng.gen::<f64>() * total;
+        let mut selected = lobes.len() - 1;
+        for (index, (_, weight)) in lobes.iter().enumerate() {
+            if choice < weight.average() {
+                selected = index;
+                break;
+            }
+            choice -= weight.average();
+        }
+
+        let diffuse = selected == lobes.len() - 1;
+        let (next_ray, weight) = lobes.swap_remove(selected);
+        let probability = weight.average() / total;
+
+        let ray = match diffuse {
+            true => linear::Ray {
+                position: next_ray.position,
+                direction: sample_hemisphere(&facing_normal, rng),
+                time: next_ray.time,
+            },
+            false => next_ray,
+        };
+
+        Some(Scatter {
+            ray,
+            weight,
+            probability,
+            specular: !diffuse,
+        })
+    }
+}
+
+// Metallic-roughness material with a Lambertian base and a GGX microfacet
+// (Cook-Torrance) specular lobe. Dielectrics reflect according to their index
+// of refraction, while metals tint their reflections with the base color.
+pub struct PbrMaterial {
+    base_color: lighting::Color,
+    metallic: f64,
+    roughness: f64,
+    index_of_refraction: f64,
+    emission: lighting::Color,
+    textures: lighting::TextureMaps,
+    culling: primitive::Culling,
+}
+
+// Material parameters after applying textures at one point on a surface
+struct Surface {
+    diffuse: lighting::Color,
+    f0: lighting::Color,
+    alpha: f64,
+}
+
+fn fresnel(f0: lighting::Color, cos_theta: f64) -> lighting::Color {
+    let weight = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+
+    let mut reflectance = f0.scale(1.0 - weight);
+    reflectance.add(lighting::Color::new(weight, weight, weight));
+    reflectance
+}
+
+// GGX (Trowbridge-Reitz) distribution of microfacet normals
+fn ggx_distribution(alpha: f64, cos_half: f64) -> f64 {
+    let alpha2 = alpha * alpha;
+    let denominator = cos_half * cos_half * (alpha2 - 1.0) + 1.0;
+
+    alpha2 / (consts::PI * denominator * denominator)
+}
+
+// Smith masking for one direction
+fn smith_masking(alpha: f64, cos_theta: f64) -> f64 {
+    let alpha2 = alpha * alpha;
+
+    2.0 * cos_theta / (cos_theta + (alpha2 + (1.0 - alpha2) * cos_theta * cos_theta).sqrt())
+}
+
+impl PbrMaterial {
+    pub fn new(
+        base_color: lighting::Color,
+        metallic: f64,
+        roughness: f64,
+        index_of_refraction: f64,
+        emission: lighting::Color,
+        textures: lighting::TextureMaps,
+        culling: primitive::Culling,
+    ) -> PbrMaterial {
+        PbrMaterial {
+            base_color,
+            metallic,
+            roughness,
+            index_of_refraction,
+            emission,
+            textures,
+            culling,
+        }
+    }
+
+    fn base_color(&self, texture: Option<lighting::TextureLookup>) -> lighting::Color {
+        lighting::textured_color(self.base_color, &self.textures.diffuse, texture)
+    }
+
+    fn surface(&self, texture: Option<lighting::TextureLookup>) -> Surface {
+        let base_color = self.base_color(texture);
+        let metallic = lighting::textured_value(self.metallic, &self.textures.metallic, texture)
+            .clamp(0.0, 1.0);
+        let roughness = lighting::textured_value(self.roughness, &self.textures.roughness, texture)
+            .clamp(MIN_ROUGHNESS, 1.0);
+
+        let dielectric =
+            ((self.index_of_refraction - 1.0) / (self.index_of_refraction + 1.0)).powi(2);
+
+        let mut f0 = lighting::Color::new(dielectric, dielectric, dielectric).scale(1.0 - metallic);
+        f0.add(base_color.scale(metallic));
+
+        Surface {
+            diffuse: base_color.scale((1.0 - metallic) * (1.0 - dielectric)),
+            f0,
+            alpha: roughness * roughness,
+        }
+    }
+}
+
+impl Material for PbrMaterial {
+    fn emission(&self) -> lighting::Color {
+        self.emission
+    }
+
+    fn textures(&self) -> &lighting::TextureMaps {
+        &self.textures
+    }
+
+    fn culling(&self) -> primitive::Culling {
+        self.culling
+    }
+
+    // Lights follow the same convention as for Phong shading, where a white
+    // diffuse surface facing a light reflects the light's color, so the BRDF
+    // is scaled up by pi
+    fn direct(
+        &self,
+        lights: &[lighting::LightSample],
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        light_strength: f64,
+    ) -> lighting::Color {
+        let mut color = lighting::Color::black();
+        let surface = self.surface(texture);
+
+        let normal = normal.normalize();
+        let view = ray.direction.normalize().negative();
+        let cos_view = normal.dot(&view).max(1e-4);
+
+        for light in lights {
+            if normal.dot(&light.offset) <= 0.0 {
+                continue;
+            }
+
+            let to_light = light.offset.normalize();
+            let cos_light = normal.dot(&to_light);
+
+            color.add(
+                surface
+                    .diffuse
+                    .multiply(light.diffuse)
+                    .scale(cos_light * light_strength),
+            );
+
+            let half = to_light.add(&view).normalize();
+            let cos_half = normal.dot(&half).max(0.0);
+
+            let distribution = ggx_distribution(surface.alpha, cos_half);
+            let masking =
+                smith_masking(surface.alpha, cos_light) * smith_masking(surface.alpha, cos_view);
+            let specular = distribution * masking / (4.0 * cos_light * cos_view);
+
+            color.add(
+                fresnel(surface.f0, view.dot(&half))
+                    .multiply(light.specular)
+                    .scale(consts::PI * specular * cos_light * light_strength),
+            );
+        }
+
+        color
+    }
+
+    // Ambient light is reflected by the diffuse lobe, and roughly by the
+    // specular lobe at its head on reflectance, which tints it for metals
+    fn ambient(
+        &self,
+        ambient_light: lighting::Color,
+        texture: Option<lighting::TextureLookup>,
+        light_strength: f64,
+    ) -> lighting::Color {
+        let surface = self.surface(texture);
+
+        let mut albedo = surface.diffuse;
+        albedo.add(surface.f0);
+        albedo.multiply(ambient_light).scale(light_strength)
+    }
+
+    // A single mirror reflection, fading out as the surface gets rougher
+    fn secondary_rays(
+        &self,
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        _back_face: bool,
+    ) -> Vec<(linear::Ray, lighting::Color)> {
+        let surface = self.surface(texture);
+        let direction = ray.direction.normalize();
+
+        let reflection = linear::Ray {
+            position: ray.position,
+            direction: direction.negative().reflect_across(&normal),
+            time: ray.time,
+        };
+
+        let cos_view = -direction.dot(&normal.normalize());
+        let glossiness = (1.0 - surface.alpha.sqrt()).powi(2);
+
+        vec![(reflection, fresnel(surface.f0, cos_view).scale(glossiness))]
+    }
+
+    // Picks the specular lobe in proportion to its reflectance at the viewing
+    // angle, sampling microfacet normals from the GGX distribution, and
+    // otherwise bounces diffusely
+    fn scatter(
+        &self,
+        ray: &linear::Ray,
+        texture: Option<lighting::TextureLookup>,
+        normal: linear::Vector,
+        _back_face: bool,
+        rng: &mut SmallRng,
+    ) -> Option<Scatter> {
+        let surface = self.surface(texture);
+
+        let normal = facing(ray, normal);
+        let view = ray.direction.normalize().negative();
+        let cos_view = normal.dot(&view).max(1e-4);
+
+        let specular_weight = fresnel(surface.f0, cos_view).average();
+        let total = specular_weight + surface.diffuse.average();
+        if total <= 0.0 {
+            return None;
+        }
+
+        let specular_probability = specular_weight / total;
+
+        if rng.gen::<f64>() >= specular_probability {
+            return Some(Scatter {
+                ray: linear::Ray {
+                    position: ray.position,
+                    direction: sample_hemisphere(&normal, rng),
+                    time: ray.time,
+                },
+                weight: surface.diffuse,
+                probability: 1.0 - specular_probability,
+                specular: false,
+            });
+        }
+
+        let (tangent, bitangent) = normal.orthonormal_basis();
+        let r = rng.gen::<f64>();
+        let phi = 2.0 * consts::PI * rng.gen::<f64>();
+        let theta = (surface.alpha * (r / (1.0 - r)).sqrt()).atan();
+
+        let half = tangent
+            .scale(theta.sin() * phi.cos())
+            .add(&bitangent.scale(theta.sin() * phi.sin()))
+            .add(&normal.scale(theta.cos()));
+
+        let cos_view_half = view.dot(&half);
+        let direction = half.scale(2.0 * cos_view_half).subtract(&view);
+
+        let cos_light = normal.dot(&direction);
+        if cos_light <= 0.0 || cos_view_half <= 0.0 {
+            return None;
+        }
+
+        // With half vectors sampled in proportion to D * cos, the distribution
+        // cancels out of the BRDF * cos / pdf
+        let masking =
+            smith_masking(surface.alpha, cos_light) * smith_masking(surface.alpha, cos_view);
+        let weight = fresnel(surface.f0, cos_view_half)
+            .scale(masking * cos_view_half / (cos_view * theta.cos()));
+
+        Some(Scatter {
+            ray: linear::Ray {
+                position: ray.position,
+                direction,
+                time: ray.time,
+            },
+            weight,
+            probability: specular_probability,
+            specular: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_abs_diff_eq;
+    use rand::SeedableRng;
+
+    fn light_above() -> Vec<lighting::LightSample> {
+        vec![lighting::LightSample::new(
+            linear::Vector::new(0.0, 0.0, 1.0),
+            1.0,
+            lighting::Color::new(1.0, 1.0, 1.0),
+        )]
+    }
+
+    fn ray_down() -> linear::Ray {
+        linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 0.0),
+            direction: linear::Vector::new(0.0, 0.0, -1.0),
+            time: 0.0,
+        }
+    }
+
+    #[test]
+    fn pbr_direct() {
+        let normal = linear::Vector::new(0.0, 0.0, 1.0);
+        let white = lighting::Color::new(1.0, 1.0, 1.0);
+
+        // A rough dielectric is close to a white diffuse surface
+        let rough = PbrMaterial::new(
+            white,
+            0.0,
+            1.0,
+            1.5,
+            lighting::Color::black(),
+            lighting::TextureMaps::default(),
+            primitive::Culling::Back,
+        );
+        let color = rough.direct(&light_above(), &ray_down(), None, normal, 1.0);
+        assert_abs_diff_eq!(1.0, color.average(), epsilon = 0.05);
+
+        // A smooth gold metal has no diffuse reflection, so its highlight takes
+        // the metal's color
+        let gold = lighting::Color::new(1.0, 0.8, 0.3);
+        let metal = PbrMaterial::new(
+            gold,
+            1.0,
+            0.3,
+            1.5,
+            lighting::Color::black(),
+            lighting::TextureMaps::default(),
+            primitive::Culling::Back,
+        );
+        let color = metal.direct(&light_above(), &ray_down(), None, normal, 1.0);
+        assert!(color.max_component() > 1.0);
+        assert_abs_diff_eq!(0.7, color.tint().average(), epsilon = 1e-9);
+
+        // Ambient light is reflected by both lobes, so only the metal tints it
+        let ambient = rough.ambient(white, None, 1.0);
+        assert_abs_diff_eq!(1.0, ambient.average(), epsilon = 1e-9);
+        assert_abs_diff_eq!(1.0, ambient.tint().average(), epsilon = 1e-9);
+        let ambient = metal.ambient(white, None, 1.0);
+        assert_abs_diff_eq!(gold.average(), ambient.average(), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn pbr_scatter_energy() {
+        // The average weight of scattered paths is the surface's albedo, which
+        // can't exceed one
+        let normal = linear::Vector::new(0.0, 0.0, 1.0);
+        let mut rng = SmallRng::seed_from_u64(1);
+
+        for &(metallic, roughness) in &[(0.0, 0.2), (0.0, 0.8), (1.0, 0.2), (1.0, 0.8)] {
+            let material = PbrMaterial::new(
+                lighting::Color::new(1.0, 1.0, 1.0),
+                metallic,
+                roughness,
+                1.5,
+                lighting::Color::black(),
+                lighting::TextureMaps::default(),
+                primitive::Culling::Back,
+            );
+
+            let samples = 20000;
+            let mut total = 0.0;
+            for _ in 0..samples {
+                if let Some(scatter) = material.scatter(&ray_down(), None, normal, false, &mut rng)
+                {
+                    assert!(scatter.ray.direction.dot(&normal) > 0.0);
+                    total += scatter.weight.average() / scatter.probability;
+                }
+            }
+
+            let albedo = total / (samples as f64);
+            assert!(albedo > 0.5 && albedo < 1.02, "albedo {}", albedo);
+        }
+    }
+
+    #[test]
+    fn phong_secondary_rays() {
+        let black = lighting::Color::black();
+        let glass = PhongMaterial::new(
+            black,
+            black,
+            black,
+            black,
+            1.0,
+            0.1,
+            1.0,
+            1.5,
+            lighting::TextureMaps::default(),
+            primitive::Culling::TwoSided,
+        );
+
+        let normal = linear::Vector::new(0.0, 0.0, 1.0);
+        let rays = glass.secondary_rays(&ray_down(), None, normal, false);
+        assert_eq!(2, rays.len());
+
+        // Head on, glass reflects 4% of the light and transmits the rest
+        assert_abs_diff_eq!(0.04, rays[0].1.average(), epsilon = 1e-9);
+        assert_abs_diff_eq!(0.96, rays[1].1.average(), epsilon = 1e-9);
+        assert!(rays[1]
+            .0
+            .direction
+            .equals(&linear::Vector::new(0.0, 0.0, -1.0)));
+
+        // Leaving the glass at a steep angle, all the light is reflected back in
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 0.0),
+            direction: linear::Vector::new(3.0f64.sqrt(), 0.0, -1.0),
+            time: 0.0,
+        };
+        let rays = glass.secondary_rays(&ray, None, normal, true);
+        assert_eq!(1, rays.len());
+        assert_abs_diff_eq!(1.0, rays[0].1.average(), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn phong_emitter_highlights() {
+        let black = lighting::Color::black();
+        let white = lighting::Color::new(1.0, 1.0, 1.0);
+        let normal = linear::Vector::new(0.0, 0.0, 1.0);
+        let above = light_above().pop().unwrap();
+        let emitter = || {
+            vec![lighting::LightSample::emitter(
+                above.offset,
+                above.range,
+                above.diffuse,
+            )]
+        };
+
+        // A mirror sees emissive surfaces in its reflections, so only other
+        // lights leave a highlight
+        for &(reflectance, emitter_highlight) in &[(0.0, 1.0), (0.5, 0.0)] {
+            let mirror = PhongMaterial::new(
+                white,
+                black,
+                black,
+                black,
+                1.0,
+                reflectance,
+                0.0,
+                1.0,
+                lighting::TextureMaps::default(),
+                primitive::Culling::Back,
+            );
+
+            let color = mirror.direct(&light_above(), &ray_down(), None, normal, 1.0);
+            assert_abs_diff_eq!(1.0, color.average(), epsilon = 1e-9);
+
+            let color = mirror.direct(&emitter(), &ray_down(), None, normal, 1.0);
+            assert_abs_diff_eq!(emitter_highlight, color.average(), epsilon = 1e-9);
+        }
+    }
+}
@@ -1,6 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts;
+
+use super::bvh;
 use super::lighting;
 use super::linear;
 
+// Small distance hits must be beyond, so rays leaving a surface don't hit it again
+const MIN_DISTANCE: f64 = 1e-4;
+
+// Which sides of a surface rays can hit. The front of a surface is the side
+// its normal points out of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Culling {
+    #[default]
+    Back,
+    Front,
+    TwoSided,
+}
+
+impl Culling {
+    pub fn keeps(self, front: bool) -> bool {
+        match self {
+            Culling::Back => front,
+            Culling::Front => !front,
+            Culling::TwoSided => true,
+        }
+    }
+
+    // Rays refracted into a transparent surface have to hit it again from the
+    // inside to leave, so transparent materials are two-sided unless told
+    // otherwise
+    pub fn default_for(transparency: f64) -> Culling {
+        match transparency > 0.0 {
+            true => Culling::TwoSided,
+            false => Culling::Back,
+        }
+    }
+}
+
+// A surface rays can hit. Points on the surface are located by two coordinates,
+// b and c, which are barycentric coordinates for triangles and each shape's own
+// parameterization of its surface for the analytic shapes.
+pub trait Primitive: Sync + Send {
+    // Whether the ray hits a side of the surface the culling keeps before
+    // max_range, the distance along the ray, and the surface coordinates of
+    // the hit
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64);
+
+    fn bounds(&self) -> bvh::BoundingBox;
+
+    fn area(&self) -> f64;
+
+    // Area once transformed, exact for triangles but otherwise only for
+    // transforms which scale evenly along every axis
+    fn transformed_area(&self, transform: &linear::Transform) -> f64 {
+        self.area() * transform.determinant().abs().powf(2.0 / 3.0)
+    }
+
+    fn material_id(&self) -> usize;
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector;
+
+    // Surface coordinates of a point spread uniformly over the surface, given
+    // two uniformly distributed random numbers
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64);
+
+    fn geometric_normal(&self, b: f64, c: f64) -> linear::Vector;
+
+    fn surface_normal(&self, b: f64, c: f64) -> linear::Vector {
+        self.geometric_normal(b, c)
+    }
+
+    fn has_texture(&self) -> bool {
+        true
+    }
+
+    fn uv(&self, b: f64, c: f64) -> lighting::UV {
+        lighting::UV::new(b, c)
+    }
+
+    // Texture coordinate distance per unit of distance across the surface
+    fn uv_density(&self) -> f64 {
+        (1.0 / self.area()).sqrt()
+    }
+
+    // Rates of change of the position across the surface along u and v
+    fn uv_derivatives(&self, b: f64, c: f64) -> Option<(linear::Vector, linear::Vector)>;
+}
+
 pub struct Triangle {
     material_id: usize,
     normal: linear::Vector,
@@ -36,8 +129,19 @@ impl Triangle {
         }
     }
 
-    pub fn intersect(&self, ray: &linear::Ray, max_range: f64) -> (bool, f64, f64, f64) {
-        if self.normal.dot(&ray.direction) >= 0.0 {
+    fn has_normal_map(&self) -> bool {
+        self.normal_map.is_some()
+    }
+}
+
+impl Primitive for Triangle {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        if !culling.keeps(self.normal.dot(&ray.direction) < 0.0) {
             return (false, max_range, 0.0, 0.0);
         }
 
@@ -53,7 +157,7 @@ impl Triangle {
         let delta = ray.position.subtract(&self.a);
 
         let u = delta.dot(&h) * inv_det;
-        if u < 0.0 || u > 1.0 {
+        if !(0.0..=1.0).contains(&u) {
             return (false, max_range, 0.0, 0.0);
         }
 
@@ -65,13 +169,51 @@ impl Triangle {
         }
 
         let t = self.edge2.dot(&q) * inv_det;
-        if t > 1e-4 && t < max_range {
+        if t > MIN_DISTANCE && t < max_range {
             return (true, t, u, v);
         }
-        return (false, max_range, 0.0, 0.0);
+
+        (false, max_range, 0.0, 0.0)
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        let b = self.a.add(&self.edge1);
+        let c = self.a.add(&self.edge2);
+
+        bvh::BoundingBox::from_points(&[self.a, b, c])
+    }
+
+    fn area(&self) -> f64 {
+        0.5 * self.edge1.cross(&self.edge2).magnitude()
+    }
+
+    fn transformed_area(&self, transform: &linear::Transform) -> f64 {
+        let edge1 = transform.vector(&self.edge1);
+        let edge2 = transform.vector(&self.edge2);
+
+        0.5 * edge1.cross(&edge2).magnitude()
     }
 
-    pub fn surface_normal(&self, b: f64, c: f64) -> linear::Vector {
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        self.a.add(&self.edge1.scale(b)).add(&self.edge2.scale(c))
+    }
+
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        let root = r1.sqrt();
+        let b = root * (1.0 - r2);
+
+        (b, root - b)
+    }
+
+    fn geometric_normal(&self, _b: f64, _c: f64) -> linear::Vector {
+        self.normal
+    }
+
+    fn surface_normal(&self, b: f64, c: f64) -> linear::Vector {
         if !self.has_normal_map() {
             return self.normal;
         }
@@ -87,11 +229,11 @@ impl Triangle {
         a_vec.add(&b_vec).add(&c_vec)
     }
 
-    pub fn material_id(&self) -> usize {
-        return self.material_id;
+    fn has_texture(&self) -> bool {
+        self.texture_map.is_some()
     }
 
-    pub fn uv(&self, b: f64, c: f64) -> lighting::UV {
+    fn uv(&self, b: f64, c: f64) -> lighting::UV {
         let a = 1.0 - b - c;
 
         let texture_uv = self.texture_map.unwrap();
@@ -102,17 +244,966 @@ impl Triangle {
         lighting::UV::new(u, v)
     }
 
-    pub fn has_texture(&self) -> bool {
-        match self.texture_map {
-            None => false,
-            Some(_) => true,
+    fn uv_density(&self) -> f64 {
+        let (a, b, c) = match self.texture_map {
+            Some(texture_uv) => texture_uv,
+            None => return 0.0,
+        };
+
+        let uv_area = 0.5 * ((b.u - a.u) * (c.v - a.v) - (c.u - a.u) * (b.v - a.v)).abs();
+        let area = self.area();
+        if area == 0.0 {
+            return 0.0;
+        }
+
+        (uv_area / area).sqrt()
+    }
+
+    fn uv_derivatives(&self, _b: f64, _c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        let (a, b, c) = self.texture_map?;
+
+        let (du1, dv1) = (b.u - a.u, b.v - a.v);
+        let (du2, dv2) = (c.u - a.u, c.v - a.v);
+
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            return None;
         }
+
+        let dpdu = self.edge1.scale(dv2).subtract(&self.edge2.scale(dv1));
+        let dpdv = self.edge2.scale(du1).subtract(&self.edge1.scale(du2));
+
+        Some((dpdu.scale(1.0 / det), dpdv.scale(1.0 / det)))
     }
+}
 
-    fn has_normal_map(&self) -> bool {
-        match self.normal_map {
-            None => false,
-            Some(_) => true,
+// Fraction of a turn about the y axis, from x towards -z, of a point. Round
+// shapes use it as their u coordinate, so their textures wrap once around them.
+fn turn(x: f64, z: f64) -> f64 {
+    let angle = (-z).atan2(x);
+    if angle < 0.0 {
+        (angle + 2.0 * consts::PI) / (2.0 * consts::PI)
+    } else {
+        angle / (2.0 * consts::PI)
+    }
+}
+
+// Unit vector at a fraction of a turn about the y axis, in the y = 0 plane
+fn around(u: f64) -> linear::Vector {
+    let angle = 2.0 * consts::PI * u;
+
+    linear::Vector::new(angle.cos(), 0.0, -angle.sin())
+}
+
+// Roots of a t^2 + 2 half_b t + c = 0, nearest first
+fn solve_quadratic(a: f64, half_b: f64, c: f64) -> Option<(f64, f64)> {
+    if a == 0.0 {
+        return None;
+    }
+
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((-half_b - root) / a, (-half_b + root) / a);
+
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+// Sphere around the origin. Its coordinates are u, around the y axis, and v,
+// from the bottom to the top.
+pub struct Sphere {
+    radius: f64,
+    material_id: usize,
+}
+
+impl Sphere {
+    pub fn new(radius: f64, material_id: usize) -> Sphere {
+        Sphere {
+            radius,
+            material_id,
+        }
+    }
+}
+
+impl Primitive for Sphere {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        let roots = solve_quadratic(
+            ray.direction.dot(&ray.direction),
+            ray.position.dot(&ray.direction),
+            ray.position.dot(&ray.position) - self.radius * self.radius,
+        );
+
+        // The near root is on the front of the sphere, and the far root is
+        // where the ray leaves through the back
+        let (t0, t1) = match roots {
+            Some(roots) => roots,
+            None => return (false, max_range, 0.0, 0.0),
+        };
+        let t = match [(t0, true), (t1, false)]
+            .iter()
+            .find(|&&(t, front)| culling.keeps(front) && t > MIN_DISTANCE && t < max_range)
+        {
+            Some(&(t, _)) => t,
+            None => return (false, max_range, 0.0, 0.0),
+        };
+
+        let point = ray.position.add(&ray.direction.scale(t));
+        let height = (point.component(1) / self.radius).clamp(-1.0, 1.0);
+
+        (
+            true,
+            t,
+            turn(point.component(0), point.component(2)),
+            1.0 - height.acos() / consts::PI,
+        )
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        let corner = linear::Vector::new(self.radius, self.radius, self.radius);
+
+        bvh::BoundingBox::from_points(&[corner, corner.negative()])
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * consts::PI * self.radius * self.radius
+    }
+
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        let polar = consts::PI * (1.0 - c);
+
+        around(b)
+            .scale(self.radius * polar.sin())
+            .add(&linear::Vector::new(0.0, self.radius * polar.cos(), 0.0))
+    }
+
+    // Heights are spread evenly over a sphere's surface
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        (r1, 1.0 - (1.0 - 2.0 * r2).acos() / consts::PI)
+    }
+
+    fn geometric_normal(&self, b: f64, c: f64) -> linear::Vector {
+        self.point_at(b, c).scale(1.0 / self.radius)
+    }
+
+    fn uv_derivatives(&self, b: f64, c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        let polar = consts::PI * (1.0 - c);
+        if polar.sin() < 1e-9 {
+            return None;
+        }
+
+        let direction = around(b);
+        let dpdu = linear::Vector::new(-direction.component(2), 0.0, direction.component(0))
+            .scale(-2.0 * consts::PI * self.radius * polar.sin());
+        let dpdv = direction
+            .scale(polar.cos())
+            .add(&linear::Vector::new(0.0, -polar.sin(), 0.0))
+            .scale(-consts::PI * self.radius);
+
+        Some((dpdu, dpdv))
+    }
+}
+
+// The y = 0 plane, facing up, with coordinates x and -z. Textures repeat every
+// unit across it.
+pub struct Plane {
+    material_id: usize,
+}
+
+impl Plane {
+    pub fn new(material_id: usize) -> Plane {
+        Plane { material_id }
+    }
+}
+
+impl Primitive for Plane {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        let direction = ray.direction.component(1);
+        if direction == 0.0 || !culling.keeps(direction < 0.0) {
+            return (false, max_range, 0.0, 0.0);
+        }
+
+        let t = -ray.position.component(1) / direction;
+        if t <= MIN_DISTANCE || t >= max_range {
+            return (false, max_range, 0.0, 0.0);
+        }
+
+        let point = ray.position.add(&ray.direction.scale(t));
+
+        (true, t, point.component(0), -point.component(2))
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        bvh::BoundingBox {
+            min: linear::Vector::new(f64::NEG_INFINITY, 0.0, f64::NEG_INFINITY),
+            max: linear::Vector::new(f64::INFINITY, 0.0, f64::INFINITY),
+        }
+    }
+
+    fn area(&self) -> f64 {
+        f64::INFINITY
+    }
+
+    fn transformed_area(&self, _transform: &linear::Transform) -> f64 {
+        f64::INFINITY
+    }
+
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        linear::Vector::new(b, 0.0, -c)
+    }
+
+    // Having no finite area, planes are never sampled as lights
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        (r1, r2)
+    }
+
+    fn geometric_normal(&self, _b: f64, _c: f64) -> linear::Vector {
+        linear::Vector::new(0.0, 1.0, 0.0)
+    }
+
+    fn uv_density(&self) -> f64 {
+        1.0
+    }
+
+    fn uv_derivatives(&self, _b: f64, _c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        Some((
+            linear::Vector::new(1.0, 0.0, 0.0),
+            linear::Vector::new(0.0, 0.0, -1.0),
+        ))
+    }
+}
+
+// Box between two corners, aligned with the axes. Each face is covered by its
+// own copy of the texture. Face f, from 0 to 5, is the face facing along axis
+// f / 2, towards its minimum for even f. Its points have coordinates b from 2f
+// to 2f + 1, which leaves gaps so the face can't be mistaken for its neighbour,
+// and c from 0 to 1.
+pub struct AxisAlignedBox {
+    min: linear::Vector,
+    max: linear::Vector,
+    material_id: usize,
+}
+
+impl AxisAlignedBox {
+    pub fn new(min: linear::Vector, max: linear::Vector, material_id: usize) -> AxisAlignedBox {
+        AxisAlignedBox {
+            min: min.min(&max),
+            max: min.max(&max),
+            material_id,
+        }
+    }
+
+    fn face_area(&self, face: usize) -> f64 {
+        let extent = self.max.subtract(&self.min);
+        let axis = face / 2;
+
+        extent.component((axis + 1) % 3) * extent.component((axis + 2) % 3)
+    }
+
+    // Face, and coordinates across it, of surface coordinates b and c. Faces
+    // towards the minimum run their u axis backwards, so the texture isn't
+    // mirrored when seen from outside.
+    fn face_at(&self, b: f64, c: f64) -> (usize, f64, f64) {
+        let face = ((b / 2.0).floor().max(0.0) as usize).min(5);
+        let u = b - 2.0 * face as f64;
+
+        match face % 2 {
+            0 => (face, 1.0 - u, c),
+            _ => (face, u, c),
+        }
+    }
+
+    fn coordinates(&self, face: usize, u: f64, v: f64) -> (f64, f64) {
+        match face % 2 {
+            0 => (2.0 * face as f64 + 1.0 - u, v),
+            _ => (2.0 * face as f64 + u, v),
+        }
+    }
+}
+
+impl Primitive for AxisAlignedBox {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        let mut near = f64::NEG_INFINITY;
+        let mut far = f64::INFINITY;
+        let (mut near_face, mut far_face) = (0, 0);
+
+        for axis in 0..3 {
+            let origin = ray.position.component(axis);
+            let direction = ray.direction.component(axis);
+            let (min, max) = (self.min.component(axis), self.max.component(axis));
+
+            if direction == 0.0 {
+                if origin < min || origin > max {
+                    return (false, max_range, 0.0, 0.0);
+                }
+
+                continue;
+            }
+
+            // Rays going up an axis enter through the face towards its minimum
+            let (entry, exit, entry_face, exit_face) = match direction > 0.0 {
+                true => (
+                    (min - origin) / direction,
+                    (max - origin) / direction,
+                    2 * axis,
+                    2 * axis + 1,
+                ),
+                false => (
+                    (max - origin) / direction,
+                    (min - origin) / direction,
+                    2 * axis + 1,
+                    2 * axis,
+                ),
+            };
+
+            if entry > near {
+                near = entry;
+                near_face = entry_face;
+            }
+            if exit < far {
+                far = exit;
+                far_face = exit_face;
+            }
+        }
+
+        if near > far {
+            return (false, max_range, 0.0, 0.0);
+        }
+
+        // Rays enter through the front of a face and leave through the back of
+        // another, which is all rays starting inside can reach
+        let (t, face) = match [(near, near_face, true), (far, far_face, false)]
+            .iter()
+            .find(|&&(t, _, front)| culling.keeps(front) && t > MIN_DISTANCE && t < max_range)
+        {
+            Some(&(t, face, _)) => (t, face),
+            None => return (false, max_range, 0.0, 0.0),
+        };
+
+        let point = ray.position.add(&ray.direction.scale(t));
+        let axis = face / 2;
+        let across = |offset: usize| {
+            let other = (axis + offset) % 3;
+            let extent = self.max.component(other) - self.min.component(other);
+
+            ((point.component(other) - self.min.component(other)) / extent).clamp(0.0, 1.0)
+        };
+
+        let (b, c) = self.coordinates(face, across(1), across(2));
+        (true, t, b, c)
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        bvh::BoundingBox {
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    fn area(&self) -> f64 {
+        (0..6).map(|face| self.face_area(face)).sum()
+    }
+
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        let (face, u, v) = self.face_at(b, c);
+        let axis = face / 2;
+
+        let mut point = [0.0; 3];
+        point[axis] = match face % 2 {
+            0 => self.min.component(axis),
+            _ => self.max.component(axis),
+        };
+        for (offset, fraction) in [(1, u), (2, v)].iter() {
+            let other = (axis + offset) % 3;
+            point[other] = self.min.component(other)
+                + fraction * (self.max.component(other) - self.min.component(other));
+        }
+
+        linear::Vector::new(point[0], point[1], point[2])
+    }
+
+    // Picks a face in proportion to its area, reusing the rest of r1 to place
+    // the point across it
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        let mut target = r1 * self.area();
+        for face in 0..6 {
+            let area = self.face_area(face);
+            if target < area || face == 5 {
+                let u = match area {
+                    area if area > 0.0 => (target / area).clamp(0.0, 1.0),
+                    _ => 0.0,
+                };
+
+                return self.coordinates(face, u, r2);
+            }
+
+            target -= area;
+        }
+
+        unreachable!()
+    }
+
+    fn geometric_normal(&self, b: f64, c: f64) -> linear::Vector {
+        let (face, _, _) = self.face_at(b, c);
+
+        let mut normal = [0.0; 3];
+        normal[face / 2] = match face % 2 {
+            0 => -1.0,
+            _ => 1.0,
+        };
+
+        linear::Vector::new(normal[0], normal[1], normal[2])
+    }
+
+    fn uv(&self, b: f64, c: f64) -> lighting::UV {
+        let (face, _, _) = self.face_at(b, c);
+
+        lighting::UV::new(b - 2.0 * face as f64, c)
+    }
+
+    // Every face holds a whole texture, so this is the average over the faces
+    fn uv_density(&self) -> f64 {
+        (6.0 / self.area()).sqrt()
+    }
+
+    fn uv_derivatives(&self, b: f64, c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        let (face, _, _) = self.face_at(b, c);
+        let axis = face / 2;
+
+        let along = |offset: usize, scale: f64| {
+            let other = (axis + offset) % 3;
+            let mut vector = [0.0; 3];
+            vector[other] = scale * (self.max.component(other) - self.min.component(other));
+
+            linear::Vector::new(vector[0], vector[1], vector[2])
+        };
+
+        match face % 2 {
+            0 => Some((along(1, -1.0), along(2, 1.0))),
+            _ => Some((along(1, 1.0), along(2, 1.0))),
+        }
+    }
+}
+
+// Open tube around the y axis, from y = 0 up to its height, with coordinates u
+// around the axis and v up it. Disks can be used to close the ends.
+pub struct Cylinder {
+    radius: f64,
+    height: f64,
+    material_id: usize,
+}
+
+impl Cylinder {
+    pub fn new(radius: f64, height: f64, material_id: usize) -> Cylinder {
+        Cylinder {
+            radius,
+            height,
+            material_id,
+        }
+    }
+}
+
+impl Primitive for Cylinder {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        let (px, pz) = (ray.position.component(0), ray.position.component(2));
+        let (dx, dz) = (ray.direction.component(0), ray.direction.component(2));
+
+        // The near root is where the ray enters the infinite cylinder from
+        // outside, the far root is on its inside
+        let (t0, t1) = match solve_quadratic(
+            dx * dx + dz * dz,
+            px * dx + pz * dz,
+            px * px + pz * pz - self.radius * self.radius,
+        ) {
+            Some(roots) => roots,
+            None => return (false, max_range, 0.0, 0.0),
+        };
+
+        for &(t, front) in [(t0, true), (t1, false)].iter() {
+            if !culling.keeps(front) || t <= MIN_DISTANCE || t >= max_range {
+                continue;
+            }
+
+            let point = ray.position.add(&ray.direction.scale(t));
+            let y = point.component(1);
+            if y < 0.0 || y > self.height {
+                continue;
+            }
+
+            return (
+                true,
+                t,
+                turn(point.component(0), point.component(2)),
+                y / self.height,
+            );
+        }
+
+        (false, max_range, 0.0, 0.0)
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        bvh::BoundingBox {
+            min: linear::Vector::new(-self.radius, 0.0, -self.radius),
+            max: linear::Vector::new(self.radius, self.height, self.radius),
+        }
+    }
+
+    fn area(&self) -> f64 {
+        2.0 * consts::PI * self.radius * self.height
+    }
+
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        around(b)
+            .scale(self.radius)
+            .add(&linear::Vector::new(0.0, c * self.height, 0.0))
+    }
+
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        (r1, r2)
+    }
+
+    fn geometric_normal(&self, b: f64, _c: f64) -> linear::Vector {
+        around(b)
+    }
+
+    fn uv_derivatives(&self, b: f64, _c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        let direction = around(b);
+        let dpdu = linear::Vector::new(-direction.component(2), 0.0, direction.component(0))
+            .scale(-2.0 * consts::PI * self.radius);
+
+        Some((dpdu, linear::Vector::new(0.0, self.height, 0.0)))
+    }
+}
+
+// Open cone around the y axis, with its base at y = 0 and its tip at its
+// height. Coordinates are u around the axis and v up it.
+pub struct Cone {
+    radius: f64,
+    height: f64,
+    material_id: usize,
+}
+
+impl Cone {
+    pub fn new(radius: f64, height: f64, material_id: usize) -> Cone {
+        Cone {
+            radius,
+            height,
+            material_id,
+        }
+    }
+}
+
+impl Primitive for Cone {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        let slope = self.radius / self.height;
+        let slope2 = slope * slope;
+
+        // Measured down from the tip, the radius is slope * depth
+        let (px, pz) = (ray.position.component(0), ray.position.component(2));
+        let (dx, dz) = (ray.direction.component(0), ray.direction.component(2));
+        let depth = self.height - ray.position.component(1);
+        let dy = -ray.direction.component(1);
+
+        let (t0, t1) = match solve_quadratic(
+            dx * dx + dz * dz - slope2 * dy * dy,
+            px * dx + pz * dz - slope2 * depth * dy,
+            px * px + pz * pz - slope2 * depth * depth,
+        ) {
+            Some(roots) => roots,
+            None => return (false, max_range, 0.0, 0.0),
+        };
+
+        // The quadratic also describes a second cone above the tip, and either
+        // root may be on the front or the back, so both roots are checked
+        for &t in [t0, t1].iter() {
+            if t <= MIN_DISTANCE || t >= max_range {
+                continue;
+            }
+
+            let point = ray.position.add(&ray.direction.scale(t));
+            let y = point.component(1);
+            if y < 0.0 || y > self.height {
+                continue;
+            }
+
+            let (b, c) = (
+                turn(point.component(0), point.component(2)),
+                y / self.height,
+            );
+            if culling.keeps(self.geometric_normal(b, c).dot(&ray.direction) < 0.0) {
+                return (true, t, b, c);
+            }
+        }
+
+        (false, max_range, 0.0, 0.0)
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        bvh::BoundingBox {
+            min: linear::Vector::new(-self.radius, 0.0, -self.radius),
+            max: linear::Vector::new(self.radius, self.height, self.radius),
+        }
+    }
+
+    fn area(&self) -> f64 {
+        consts::PI * self.radius * (self.radius * self.radius + self.height * self.height).sqrt()
+    }
+
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        around(b)
+            .scale(self.radius * (1.0 - c))
+            .add(&linear::Vector::new(0.0, c * self.height, 0.0))
+    }
+
+    // The circumference shrinks linearly towards the tip
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        (r1, 1.0 - r2.sqrt())
+    }
+
+    fn geometric_normal(&self, b: f64, _c: f64) -> linear::Vector {
+        around(b)
+            .scale(self.height)
+            .add(&linear::Vector::new(0.0, self.radius, 0.0))
+            .normalize()
+    }
+
+    fn uv_derivatives(&self, b: f64, c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        let radius = self.radius * (1.0 - c);
+        if radius < 1e-9 {
+            return None;
+        }
+
+        let direction = around(b);
+        let dpdu = linear::Vector::new(-direction.component(2), 0.0, direction.component(0))
+            .scale(-2.0 * consts::PI * radius);
+        let dpdv = direction
+            .scale(-self.radius)
+            .add(&linear::Vector::new(0.0, self.height, 0.0));
+
+        Some((dpdu, dpdv))
+    }
+}
+
+// Disk in the y = 0 plane around the origin, facing up. Coordinates are u
+// around the center and v in from the edge.
+pub struct Disk {
+    radius: f64,
+    material_id: usize,
+}
+
+impl Disk {
+    pub fn new(radius: f64, material_id: usize) -> Disk {
+        Disk {
+            radius,
+            material_id,
+        }
+    }
+}
+
+impl Primitive for Disk {
+    fn intersect(
+        &self,
+        ray: &linear::Ray,
+        max_range: f64,
+        culling: Culling,
+    ) -> (bool, f64, f64, f64) {
+        let direction = ray.direction.component(1);
+        if direction == 0.0 || !culling.keeps(direction < 0.0) {
+            return (false, max_range, 0.0, 0.0);
+        }
+
+        let t = -ray.position.component(1) / direction;
+        if t <= MIN_DISTANCE || t >= max_range {
+            return (false, max_range, 0.0, 0.0);
+        }
+
+        let point = ray.position.add(&ray.direction.scale(t));
+        let (x, z) = (point.component(0), point.component(2));
+        let distance = (x * x + z * z).sqrt();
+        if distance > self.radius {
+            return (false, max_range, 0.0, 0.0);
+        }
+
+        (true, t, turn(x, z), 1.0 - distance / self.radius)
+    }
+
+    fn bounds(&self) -> bvh::BoundingBox {
+        bvh::BoundingBox {
+            min: linear::Vector::new(-self.radius, 0.0, -self.radius),
+            max: linear::Vector::new(self.radius, 0.0, self.radius),
+        }
+    }
+
+    fn area(&self) -> f64 {
+        consts::PI * self.radius * self.radius
+    }
+
+    fn material_id(&self) -> usize {
+        self.material_id
+    }
+
+    fn point_at(&self, b: f64, c: f64) -> linear::Vector {
+        around(b).scale(self.radius * (1.0 - c))
+    }
+
+    fn sample(&self, r1: f64, r2: f64) -> (f64, f64) {
+        (r1, 1.0 - r2.sqrt())
+    }
+
+    fn geometric_normal(&self, _b: f64, _c: f64) -> linear::Vector {
+        linear::Vector::new(0.0, 1.0, 0.0)
+    }
+
+    fn uv_derivatives(&self, b: f64, c: f64) -> Option<(linear::Vector, linear::Vector)> {
+        let radius = self.radius * (1.0 - c);
+        if radius < 1e-9 {
+            return None;
+        }
+
+        let direction = around(b);
+        let dpdu = linear::Vector::new(-direction.component(2), 0.0, direction.component(0))
+            .scale(-2.0 * consts::PI * radius);
+
+        Some((dpdu, direction.scale(-self.radius)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes() -> Vec<Box<dyn Primitive>> {
+        vec![
+            Box::new(Sphere::new(2.0, 0)),
+            Box::new(Plane::new(0)),
+            Box::new(AxisAlignedBox::new(
+                linear::Vector::new(-1.0, 0.0, 2.0),
+                linear::Vector::new(3.0, 1.0, -1.0),
+                0,
+            )),
+            Box::new(Cylinder::new(1.5, 2.0, 0)),
+            Box::new(Cone::new(1.0, 3.0, 0)),
+            Box::new(Disk::new(2.0, 0)),
+            Box::new(Triangle::new(
+                linear::Vector::new(0.0, 0.0, 0.0),
+                linear::Vector::new(1.0, 0.0, 0.0),
+                linear::Vector::new(0.0, 1.0, 0.0),
+                0,
+                None,
+                None,
+            )),
+        ]
+    }
+
+    #[test]
+    fn sphere() {
+        let sphere = Sphere::new(2.0, 3);
+        assert_eq!(3, sphere.material_id());
+
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 5.0),
+            direction: linear::Vector::new(0.0, 0.0, -2.0),
+            time: 0.0,
+        };
+        let (intersected, t, b, c) = sphere.intersect(&ray, f64::MAX, Culling::Back);
+        assert!(intersected);
+        approx::assert_abs_diff_eq!(1.5, t, epsilon = 1e-12);
+        assert!(sphere
+            .geometric_normal(b, c)
+            .equals(&linear::Vector::new(0.0, 0.0, 1.0)));
+
+        // Only the back is hit from inside, and nothing beyond the range
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 0.0),
+            direction: linear::Vector::new(0.0, 0.0, 1.0),
+            time: 0.0,
+        };
+        assert!(!sphere.intersect(&ray, f64::MAX, Culling::Back).0);
+        let (intersected, t, _, _) = sphere.intersect(&ray, f64::MAX, Culling::TwoSided);
+        assert!(intersected);
+        approx::assert_abs_diff_eq!(2.0, t, epsilon = 1e-12);
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 0.0, 5.0),
+            direction: linear::Vector::new(0.0, 0.0, -1.0),
+            time: 0.0,
+        };
+        assert!(!sphere.intersect(&ray, 2.0, Culling::Back).0);
+
+        approx::assert_ulps_eq!(16.0 * consts::PI, sphere.area());
+    }
+
+    #[test]
+    fn axis_aligned_box() {
+        let cuboid = AxisAlignedBox::new(
+            linear::Vector::new(0.0, 0.0, 0.0),
+            linear::Vector::new(1.0, 2.0, 3.0),
+            0,
+        );
+        approx::assert_ulps_eq!(22.0, cuboid.area());
+
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.5, -4.0, 1.5),
+            direction: linear::Vector::new(0.0, 1.0, 0.0),
+            time: 0.0,
+        };
+        let (intersected, t, b, c) = cuboid.intersect(&ray, f64::MAX, Culling::Back);
+        assert!(intersected);
+        approx::assert_ulps_eq!(4.0, t);
+        assert!(cuboid
+            .geometric_normal(b, c)
+            .equals(&linear::Vector::new(0.0, -1.0, 0.0)));
+
+        // Rays running along a face's plane can still hit the next face
+        let ray = linear::Ray {
+            position: linear::Vector::new(0.0, 1.0, -1.0),
+            direction: linear::Vector::new(0.0, 0.0, 1.0),
+            time: 0.0,
+        };
+        assert!(cuboid.intersect(&ray, f64::MAX, Culling::Back).0);
+    }
+
+    // Every shape, at points spread over it, is hit from in front but not from
+    // behind unless culling allows it, and its coordinates lead back to the
+    // point hit
+    #[test]
+    fn surfaces() {
+        for shape in shapes() {
+            for i in 0..7 {
+                for j in 0..7 {
+                    let (r1, r2) = (0.05 + 0.15 * i as f64, 0.05 + 0.15 * j as f64);
+                    let (b, c) = shape.sample(r1, r2);
+                    let point = shape.point_at(b, c);
+                    let normal = shape.geometric_normal(b, c);
+                    approx::assert_abs_diff_eq!(1.0, normal.magnitude(), epsilon = 1e-12);
+
+                    let ray = linear::Ray {
+                        position: point.add(&normal.scale(0.5)),
+                        direction: normal.negative(),
+                        time: 0.0,
+                    };
+                    let (intersected, t, hit_b, hit_c) =
+                        shape.intersect(&ray, f64::MAX, Culling::Back);
+                    assert!(intersected);
+                    approx::assert_abs_diff_eq!(0.5, t, epsilon = 1e-9);
+
+                    let hit = shape.point_at(hit_b, hit_c);
+                    approx::assert_abs_diff_eq!(
+                        0.0,
+                        hit.subtract(&point).magnitude(),
+                        epsilon = 1e-9
+                    );
+
+                    let ray = linear::Ray {
+                        position: point.subtract(&normal.scale(0.5)),
+                        direction: normal,
+                        time: 0.0,
+                    };
+                    let (intersected, t, _, _) = shape.intersect(&ray, f64::MAX, Culling::Back);
+                    assert!(!intersected || t > 0.5 + 1e-9);
+
+                    // Culling the front leaves only the back to be hit
+                    let ray = linear::Ray {
+                        position: point.add(&normal.scale(0.5)),
+                        direction: normal.negative(),
+                        time: 0.0,
+                    };
+                    let (intersected, t, _, _) = shape.intersect(&ray, f64::MAX, Culling::Front);
+                    assert!(!intersected || t > 0.5 + 1e-9);
+
+                    let ray = linear::Ray {
+                        position: point.subtract(&normal.scale(0.05)),
+                        direction: normal,
+                        time: 0.0,
+                    };
+                    for &culling in [Culling::Front, Culling::TwoSided].iter() {
+                        let (intersected, t, hit_b, hit_c) =
+                            shape.intersect(&ray, f64::MAX, culling);
+                        assert!(intersected);
+                        approx::assert_abs_diff_eq!(0.05, t, epsilon = 1e-9);
+
+                        let hit = shape.point_at(hit_b, hit_c);
+                        approx::assert_abs_diff_eq!(
+                            0.0,
+                            hit.subtract(&point).magnitude(),
+                            epsilon = 1e-9
+                        );
+                    }
+
+                    let bounds = shape.bounds();
+                    for axis in 0..3 {
+                        assert!(point.component(axis) >= bounds.min.component(axis) - 1e-9);
+                        assert!(point.component(axis) <= bounds.max.component(axis) + 1e-9);
+                    }
+
+                    // Texture directions follow the outside of the surface
+                    if let Some((dpdu, dpdv)) = shape.uv_derivatives(b, c) {
+                        assert!(dpdu.cross(&dpdv).dot(&normal) > 0.0);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transformed_area() {
+        let transform = linear::Transform::new(linear::Matrix4::scaling(&linear::Vector::new(
+            2.0, 2.0, 2.0,
+        )))
+        .unwrap();
+
+        for shape in shapes() {
+            approx::assert_relative_eq!(
+                4.0 * shape.area(),
+                shape.transformed_area(&transform),
+                max_relative = 1e-12
+            );
         }
     }
 }